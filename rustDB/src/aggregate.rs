@@ -0,0 +1,477 @@
+#[doc="
+  AGGREGATE, summaries over the items matching a filter.
+
+  Next to the filter lines every line names a function and the fields it
+  runs over:
+
+        COUNT                       items, COUNT field counts items with a value
+        SUM age score               sum of each field, int and float fields only
+        AVG age                     mean, int and float fields only
+        MIN age / MAX age           smallest and largest value
+        DISTINCT name               number of different values
+        GROUP BY class year         one summary per combination of values
+
+  Without a function the items are counted. The answer is a JSON list with
+  one object per group, ordered by the group values, holding the group
+  fields and a key per function such as \"count\", \"sum(age)\" or
+  \"distinct(name)\". Without GROUP BY the list has one object, also when no
+  item matches. A value the items do not have is null.
+
+  The work is split into partial results that are merged, so chunks of a
+  large collection can be summed up on several threads. summarize does so
+  on the pool of scans (see pool.rs) from PARALLEL_AGGREGATE records on,
+  the records are moved into the chunks and not copied.
+"]
+use std::collections::{BTreeMap, BTreeSet};
+use std::cmp;
+
+use value::{Value, FieldType, Schema, field_type};
+use vec_dbcollection::Record;
+use pool::{WorkerPool, scans};
+use error::DbError;
+
+// from this many records on a summary runs on several threads
+const PARALLEL_AGGREGATE: usize = 10000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Distinct,
+}
+
+impl Function {
+    fn from_keyword(word: &str) -> Option<Function> {
+        match word {
+            "COUNT" => Some(Function::Count),
+            "SUM" => Some(Function::Sum),
+            "AVG" => Some(Function::Avg),
+            "MIN" => Some(Function::Min),
+            "MAX" => Some(Function::Max),
+            "DISTINCT" => Some(Function::Distinct),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Function::Count => "count",
+            Function::Sum => "sum",
+            Function::Avg => "avg",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Distinct => "distinct",
+        }
+    }
+}
+
+// one object of the answer, group fields and function results by name
+pub type Summary = BTreeMap<String, Option<Value>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    pub group_by: Vec<String>,
+    pub functions: Vec<(Function, Option<String>)>,     // the field, None for COUNT of items
+}
+
+impl Aggregate {
+    pub fn new() -> Self {
+        Aggregate {
+            group_by: Vec::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    pub fn is_keyword(word: &str) -> bool {
+        word == "GROUP" || Function::from_keyword(word).is_some()
+    }
+
+    // add the functions or the grouping of a line from the words after its keyword
+    pub fn set(&mut self, keyword: &str, words: &[String]) -> Result<(), String> {
+        if keyword == "GROUP" {
+            if words.len() < 2 || words[0] != "BY" {
+                return Err("GROUP BY expects at least one field".to_owned());
+            }
+            self.group_by = words[1..].to_vec();
+            return Ok(());
+        }
+        let function = match Function::from_keyword(keyword) {
+            Some(function) => function,
+            None => return Err(format!("unknown function {}", keyword)),
+        };
+        if words.is_empty() {
+            if function != Function::Count {
+                return Err(format!("{} expects at least one field", keyword));
+            }
+            self.functions.push((function, None));
+        }
+        for field in words.iter() {
+            self.functions.push((function, Some(field.clone())));
+        }
+        Ok(())
+    }
+
+    // fields must exist, SUM and AVG need numbers
+    pub fn check(&self, fields: &Schema) -> Result<(), String> {
+        for field in self.group_by.iter() {
+            if field_type(fields, field).is_none() {
+                return Err(format!("field {} does not exist", field));
+            }
+        }
+        for &(function, ref field) in self.functions.iter() {
+            let field = match *field {
+                Some(ref field) => field,
+                None => continue,
+            };
+            match (function, field_type(fields, field)) {
+                (_, None) => return Err(format!("field {} does not exist", field)),
+                (Function::Sum, Some(field_type)) | (Function::Avg, Some(field_type))
+                    if field_type != FieldType::Int && field_type != FieldType::Float =>
+                    return Err(format!("{} of {} needs an int or float field", function.name(), field)),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    // COUNT when no function is given
+    fn functions(&self) -> Vec<(Function, Option<String>)> {
+        if self.functions.is_empty() {
+            vec![(Function::Count, None)]
+        } else {
+            self.functions.clone()
+        }
+    }
+
+    pub fn groups(&self) -> Groups {
+        Groups {
+            group_by: self.group_by.clone(),
+            functions: self.functions(),
+            groups: BTreeMap::new(),
+        }
+    }
+
+    // summaries of the items, one pass on this thread
+    pub fn run<'a, I: Iterator<Item=&'a Record>>(&self, items: I) -> Result<Vec<Summary>, String> {
+        let mut groups = self.groups();
+        for item in items {
+            groups.add(item);
+        }
+        groups.finish()
+    }
+}
+
+// what a function has seen so far
+#[derive(Debug, Clone)]
+struct Partial {
+    count: u64,
+    int_sum: Option<i64>,       // None once the sum left the i64 range
+    float_sum: f64,
+    floats: bool,
+    min: Option<Value>,
+    max: Option<Value>,
+    distinct: BTreeSet<Value>,
+}
+
+impl Partial {
+    fn new() -> Self {
+        Partial {
+            count: 0,
+            int_sum: Some(0),
+            float_sum: 0.0,
+            floats: false,
+            min: None,
+            max: None,
+            distinct: BTreeSet::new(),
+        }
+    }
+
+    fn add(&mut self, function: Function, value: &Value) {
+        self.count += 1;
+        match function {
+            Function::Sum | Function::Avg => match *value {
+                Value::Int(n) => {
+                    self.int_sum = self.int_sum.and_then(|sum| sum.checked_add(n));
+                    self.float_sum += n as f64;
+                },
+                Value::Float(n) => {
+                    self.floats = true;
+                    self.float_sum += n;
+                },
+                _ => (),
+            },
+            Function::Min => if self.min.as_ref().map_or(true, |min| value < min) {
+                self.min = Some(value.clone());
+            },
+            Function::Max => if self.max.as_ref().map_or(true, |max| value > max) {
+                self.max = Some(value.clone());
+            },
+            Function::Distinct => {
+                self.distinct.insert(value.clone());
+            },
+            Function::Count => (),
+        }
+    }
+
+    fn merge(&mut self, other: Partial) {
+        self.count += other.count;
+        self.int_sum = match (self.int_sum, other.int_sum) {
+            (Some(a), Some(b)) => a.checked_add(b),
+            _ => None,
+        };
+        self.float_sum += other.float_sum;
+        self.floats = self.floats || other.floats;
+        if let Some(min) = other.min {
+            if self.min.as_ref().map_or(true, |current| min < *current) {
+                self.min = Some(min);
+            }
+        }
+        if let Some(max) = other.max {
+            if self.max.as_ref().map_or(true, |current| max > *current) {
+                self.max = Some(max);
+            }
+        }
+        self.distinct.extend(other.distinct);
+    }
+
+    fn result(self, function: Function, field: &Option<String>) -> Result<Option<Value>, String> {
+        Ok(match function {
+            Function::Count => Some(Value::Int(self.count as i64)),
+            Function::Sum if self.floats => Some(Value::Float(self.float_sum)),
+            Function::Sum => match self.int_sum {
+                Some(sum) => Some(Value::Int(sum)),
+                None => return Err(format!("sum of {} is too large", field.as_ref().map_or("", |f| f.as_str()))),
+            },
+            Function::Avg if self.count == 0 => None,
+            Function::Avg => Some(Value::Float(self.float_sum / self.count as f64)),
+            Function::Min => self.min,
+            Function::Max => self.max,
+            Function::Distinct => Some(Value::Int(self.distinct.len() as i64)),
+        })
+    }
+}
+
+// partial summaries by group values, merged across chunks of items
+#[derive(Debug, Clone)]
+pub struct Groups {
+    group_by: Vec<String>,
+    functions: Vec<(Function, Option<String>)>,
+    groups: BTreeMap<Vec<Option<Value>>, Vec<Partial>>,
+}
+
+impl Groups {
+    pub fn add(&mut self, item: &Record) {
+        let key: Vec<Option<Value>> = self.group_by.iter().map(|field| item.get(field).cloned()).collect();
+        let functions = &self.functions;
+        let partials = self.groups.entry(key).or_insert_with(|| vec![Partial::new(); functions.len()]);
+        for (partial, &(function, ref field)) in partials.iter_mut().zip(functions.iter()) {
+            match *field {
+                None => partial.count += 1,
+                Some(ref field) => if let Some(value) = item.get(field) {
+                    partial.add(function, value);
+                },
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: Groups) {
+        for (key, partials) in other.groups.into_iter() {
+            match self.groups.get_mut(&key) {
+                Some(current) => {
+                    for (current, partial) in current.iter_mut().zip(partials.into_iter()) {
+                        current.merge(partial);
+                    }
+                    continue;
+                },
+                None => (),
+            }
+            self.groups.insert(key, partials);
+        }
+    }
+
+    pub fn finish(mut self) -> Result<Vec<Summary>, String> {
+        // without grouping there is an answer even when nothing matched
+        if self.group_by.is_empty() && self.groups.is_empty() {
+            self.groups.insert(Vec::new(), vec![Partial::new(); self.functions.len()]);
+        }
+        let mut summaries = Vec::new();
+        for (key, partials) in self.groups.into_iter() {
+            let mut summary = Summary::new();
+            for (field, value) in self.group_by.iter().zip(key.into_iter()) {
+                summary.insert(field.clone(), value);
+            }
+            for (partial, &(function, ref field)) in partials.into_iter().zip(self.functions.iter()) {
+                let name = match *field {
+                    Some(ref field) => format!("{}({})", function.name(), field),
+                    None => function.name().to_owned(),
+                };
+                summary.insert(name, try!(partial.result(function, field)));
+            }
+            summaries.push(summary);
+        }
+        Ok(summaries)
+    }
+}
+
+// a large set of records is summarized on several threads
+pub fn summarize(records: Vec<Record>, aggregate: &Aggregate) -> Result<Vec<Summary>, DbError> {
+    match records.len() >= PARALLEL_AGGREGATE {
+        true => aggregate_records(records, aggregate),
+        false => aggregate.run(records.iter()).map_err(DbError::InvalidQuery),
+    }
+}
+
+// the records in one chunk per worker of the pool of scans
+fn aggregate_records(records: Vec<Record>, aggregate: &Aggregate) -> Result<Vec<Summary>, DbError> {
+    let groups = try!(aggregate_chunks(scans(), records, aggregate.groups(), |groups, record| groups.add(record)));
+    groups.finish().map_err(DbError::InvalidQuery)
+}
+
+// one chunk of items per worker, the partial results are merged in chunk order
+pub fn aggregate_chunks<T, F>(workers: &WorkerPool, items: Vec<T>, groups: Groups, add: F) -> Result<Groups, DbError>
+    where T: Send + 'static, F: Fn(&mut Groups, &T) + Send + Sync + 'static
+{
+    let chunk_size = (items.len() + workers.size() - 1) / workers.size();
+    let empty = groups.clone();
+    let partials = try!(workers.map(items, cmp::max(1, chunk_size), move |chunk| {
+        let mut partial = empty.clone();
+        for item in chunk.iter() {
+            add(&mut partial, item);
+        }
+        partial
+    }));
+    let mut merged = groups;
+    for partial in partials {
+        merged.merge(partial);
+    }
+    Ok(merged)
+}
+
+
+#[cfg(test)]
+mod aggregate_tests {
+    use super::{Aggregate, Function, PARALLEL_AGGREGATE, summarize, aggregate_records};
+    use value::{Value, FieldType, Schema};
+    use vec_dbcollection::Record;
+    use error::DbError;
+
+    #[test]
+    fn set_test() {
+        let mut aggregate = Aggregate::new();
+        assert!(aggregate.set("COUNT", &[]).is_ok());
+        assert!(aggregate.set("SUM", &words("age score")).is_ok());
+        assert!(aggregate.set("GROUP", &words("BY class")).is_ok());
+        assert_eq!(aggregate.functions, vec![(Function::Count, None),
+                                             (Function::Sum, Some("age".to_owned())),
+                                             (Function::Sum, Some("score".to_owned()))]);
+        assert_eq!(aggregate.group_by, words("class"));
+
+        assert!(aggregate.set("AVG", &[]).is_err());
+        assert!(aggregate.set("GROUP", &words("class")).is_err());
+        assert!(Aggregate::is_keyword("DISTINCT") && !Aggregate::is_keyword("count"));
+    }
+
+    #[test]
+    fn check_test() {
+        let mut fields = Schema::new();
+        fields.insert("name".to_owned(), FieldType::Str);
+        fields.insert("age".to_owned(), FieldType::Int);
+        let mut aggregate = Aggregate::new();
+        assert!(aggregate.set("MAX", &words("name age")).is_ok());
+        assert!(aggregate.check(&fields).is_ok());
+        assert!(aggregate.set("AVG", &words("name")).is_ok());
+        assert!(aggregate.check(&fields).is_err());
+
+        let mut aggregate = Aggregate::new();
+        assert!(aggregate.set("GROUP", &words("BY class")).is_ok());
+        assert!(aggregate.check(&fields).is_err());
+    }
+
+    #[test]
+    fn run_test() {
+        let items = vec![record("A", Some(10), "Ada"), record("B", Some(20), "Bob"),
+                         record("A", Some(30), "Cy"), record("A", None, "Ada")];
+        let mut aggregate = Aggregate::new();
+        for line in ["COUNT", "COUNT age", "SUM age", "AVG age", "MIN age", "MAX name", "DISTINCT name"].iter() {
+            let words = words(line);
+            assert!(aggregate.set(&words[0], &words[1..]).is_ok());
+        }
+        let all = aggregate.run(items.iter()).unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0]["count"], Some(Value::Int(4)));
+        assert_eq!(all[0]["count(age)"], Some(Value::Int(3)));
+        assert_eq!(all[0]["sum(age)"], Some(Value::Int(60)));
+        assert_eq!(all[0]["avg(age)"], Some(Value::Float(20.0)));
+        assert_eq!(all[0]["min(age)"], Some(Value::Int(10)));
+        assert_eq!(all[0]["max(name)"], Some(Value::from("Cy")));
+        assert_eq!(all[0]["distinct(name)"], Some(Value::Int(3)));
+
+        assert!(aggregate.set("GROUP", &words("BY class")).is_ok());
+        let grouped = aggregate.run(items.iter()).unwrap();
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[0]["class"], Some(Value::from("A")));
+        assert_eq!(grouped[0]["sum(age)"], Some(Value::Int(40)));
+        assert_eq!(grouped[0]["distinct(name)"], Some(Value::Int(2)));
+        assert_eq!(grouped[1]["count"], Some(Value::Int(1)));
+
+        // chunks merged give the same answer as one pass
+        let mut first = aggregate.groups();
+        let mut second = aggregate.groups();
+        first.add(&items[0]);
+        first.add(&items[1]);
+        second.add(&items[2]);
+        second.add(&items[3]);
+        first.merge(second);
+        assert_eq!(first.finish().unwrap(), grouped);
+    }
+
+    #[test]
+    fn empty_test() {
+        let mut aggregate = Aggregate::new();
+        assert!(aggregate.set("AVG", &words("age")).is_ok());
+        let none: Vec<Record> = Vec::new();
+        let summary = aggregate.run(none.iter()).unwrap();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0]["avg(age)"], None);
+
+        assert!(aggregate.set("GROUP", &words("BY class")).is_ok());
+        assert!(aggregate.run(none.iter()).unwrap().is_empty());
+
+        let big = vec![record("A", Some(i64::max_value()), "Ada"), record("A", Some(1), "Bob")];
+        let mut aggregate = Aggregate::new();
+        assert!(aggregate.set("SUM", &words("age")).is_ok());
+        assert!(aggregate.run(big.iter()).is_err());
+    }
+
+    #[test]
+    fn summarize_test() {
+        let mut aggregate = Aggregate::new();
+        assert!(aggregate.set("GROUP", &words("BY class")).is_ok());
+        assert!(aggregate.set("MAX", &words("name")).is_ok());
+        assert!(aggregate.set("SUM", &words("age")).is_ok());
+
+        // the same answer from records split over threads and from one pass
+        let records: Vec<Record> = (0..PARALLEL_AGGREGATE as i64 + 7).map(|n| record(&(n % 7).to_string(), Some(n), &format!("{:05}", n))).collect();
+        let expected = aggregate.run(records.iter()).map_err(DbError::InvalidQuery);
+        assert_eq!(aggregate_records(records[..50].to_vec(), &aggregate), aggregate.run(records[..50].iter()).map_err(DbError::InvalidQuery));
+        assert_eq!(summarize(records, &aggregate), expected);
+        assert_eq!(expected.unwrap().len(), 7);
+    }
+
+    fn words(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|word| word.to_owned()).collect()
+    }
+
+    fn record(class: &str, age: Option<i64>, name: &str) -> Record {
+        let mut record = Record::new();
+        record.insert("class".to_owned(), Value::from(class));
+        record.insert("name".to_owned(), Value::from(name));
+        if let Some(age) = age {
+            record.insert("age".to_owned(), Value::Int(age));
+        }
+        record
+    }
+}
@@ -0,0 +1,237 @@
+#[doc="
+  Changes to the fields of a collection, given as the lines of ALTER:
+
+        ADD note [type] [DEFAULT value]     a new field, existing items get the
+                                            default or stay without a value, and
+                                            so does an APPEND that leaves it out
+        DROP note                           the field and its value in every item
+        RENAME note TO remark               the field, its values, its index, its
+                                            default and its constraints keep their
+                                            place
+
+  The type is one of the types of PUTLIST, string when left out. The lines
+  are applied in order and together: when one of them does not fit the
+  collection, nothing is changed. A field of the primary key or a unique
+  field cannot be dropped, _id and _version are kept by the database. Like
+  the keywords of GET the keywords are capital words.
+"]
+use value::{FieldType, Schema, Constraints, ID_FIELD, VERSION_FIELD};
+use vec_dbcollection::Record;
+use tokenizer::{tokenize, TokenKind, ParseError};
+use error::DbError;
+
+#[derive(Debug, Clone, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum Alteration {
+    AddField(String, FieldType, Option<String>),    // the default as sent by the client
+    DropField(String),
+    RenameField(String, String),
+}
+
+impl Alteration {
+    // one line of ALTER, see above
+    pub fn parse(line: &str, line_number: usize) -> Result<Alteration, ParseError> {
+        let tokens = try!(tokenize(line, line_number));
+        let mut words = Vec::new();
+        for token in tokens.iter() {
+            match token.kind {
+                TokenKind::Word(ref word) => words.push(word.clone()),
+                _ => return Err(ParseError::new(token.line, token.column, "expected a field name or a value")),
+            }
+        }
+        let keyword = |position: usize, expected: &str| tokens.get(position).map_or(false, |token| !token.quoted && words[position] == expected);
+        let error = |position: usize, message: &str| match tokens.get(position) {
+            Some(token) => ParseError::new(token.line, token.column, message),
+            None => ParseError::new(line_number, line.len() + 1, message),
+        };
+
+        if keyword(0, "ADD") {
+            if words.len() < 2 {
+                return Err(error(1, "expected the name of the new field"));
+            }
+            let mut position = 2;
+            let mut field_type = FieldType::Str;
+            if let Some(declared) = words.get(2).and_then(|word| FieldType::from_name(word)) {
+                field_type = declared;
+                position = 3;
+            }
+            return match words.len() - position {
+                0 => Ok(Alteration::AddField(words[1].clone(), field_type, None)),
+                2 if keyword(position, "DEFAULT") => Ok(Alteration::AddField(words[1].clone(), field_type, Some(words[position + 1].clone()))),
+                _ => Err(error(position, "expected a field type or DEFAULT and a value")),
+            };
+        }
+        if keyword(0, "DROP") {
+            return match words.len() {
+                2 => Ok(Alteration::DropField(words[1].clone())),
+                _ => Err(error(if words.len() < 2 { 1 } else { 2 }, "expected the name of one field")),
+            };
+        }
+        if keyword(0, "RENAME") {
+            return match words.len() {
+                4 if keyword(2, "TO") => Ok(Alteration::RenameField(words[1].clone(), words[3].clone())),
+                _ => Err(error(if words.len() < 2 { 1 } else { 2 }, "expected a field name, TO and the new name")),
+            };
+        }
+        Err(error(0, "expected ADD, DROP or RENAME"))
+    }
+
+    // change the fields and constraints the way the items will be changed
+    pub fn check(&self, fields: &mut Schema, constraints: &mut Constraints) -> Result<(), DbError> {
+        match *self {
+            Alteration::AddField(ref field, field_type, ref default) => {
+                try!(not_reserved(field));
+                if fields.contains_key(field) {
+                    return Err(DbError::FieldExists(field.clone()));
+                }
+                if let Some(ref text) = *default {
+                    if field_type.coerce(text).is_none() {
+                        return Err(DbError::TypeMismatch(field.clone(), text.clone()));
+                    }
+                    constraints.defaults.insert(field.clone(), text.clone());
+                }
+                fields.insert(field.clone(), field_type);
+            },
+            Alteration::DropField(ref field) => {
+                try!(not_reserved(field));
+                if !fields.contains_key(field) {
+                    return Err(DbError::UnknownField(field.clone()));
+                }
+                if constraints.primary_key.as_ref() == Some(field) || constraints.unique.contains(field) {
+                    return Err(DbError::ConstrainedField(field.clone()));
+                }
+                fields.remove(field);
+                constraints.required.remove(field);
+                constraints.defaults.remove(field);
+                constraints.nullable.remove(field);
+            },
+            Alteration::RenameField(ref field, ref name) => {
+                try!(not_reserved(field));
+                try!(not_reserved(name));
+                if !fields.contains_key(field) {
+                    return Err(DbError::UnknownField(field.clone()));
+                }
+                if fields.contains_key(name) {
+                    return Err(DbError::FieldExists(name.clone()));
+                }
+                let field_type = fields.remove(field).unwrap();
+                fields.insert(name.clone(), field_type);
+                if constraints.primary_key.as_ref() == Some(field) {
+                    constraints.primary_key = Some(name.clone());
+                }
+                if constraints.unique.remove(field) {
+                    constraints.unique.insert(name.clone());
+                }
+                if constraints.required.remove(field) {
+                    constraints.required.insert(name.clone());
+                }
+                if let Some(default) = constraints.defaults.remove(field) {
+                    constraints.defaults.insert(name.clone(), default);
+                }
+                if constraints.nullable.remove(field) {
+                    constraints.nullable.insert(name.clone());
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // change one item, the alteration was checked against the collection before
+    pub fn apply(&self, item: &mut Record) {
+        match *self {
+            Alteration::AddField(ref field, field_type, Some(ref text)) => {
+                if let Some(value) = field_type.coerce(text) {
+                    item.insert(field.clone(), value);
+                }
+            },
+            Alteration::AddField(_, _, None) => (),
+            Alteration::DropField(ref field) => {
+                item.remove(field);
+            },
+            Alteration::RenameField(ref field, ref name) => {
+                if let Some(value) = item.remove(field) {
+                    item.insert(name.clone(), value);
+                }
+            },
+        }
+    }
+}
+
+// the fields and constraints after every alteration, or the first one that does not fit
+pub fn alter_schema(fields: &Schema, constraints: &Constraints, alterations: &[Alteration]) -> Result<(Schema, Constraints), DbError> {
+    let mut fields = fields.clone();
+    let mut constraints = constraints.clone();
+    for alteration in alterations {
+        try!(alteration.check(&mut fields, &mut constraints));
+    }
+    Ok((fields, constraints))
+}
+
+fn not_reserved(field: &str) -> Result<(), DbError> {
+    match field == ID_FIELD || field == VERSION_FIELD {
+        true => Err(DbError::ReservedField(field.to_owned())),
+        false => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod alter_tests {
+    use super::{Alteration, alter_schema};
+    use value::{Value, FieldType, Schema, Constraints};
+    use vec_dbcollection::Record;
+    use error::DbError;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(Alteration::parse("ADD score int DEFAULT 0", 2), Ok(Alteration::AddField("score".to_owned(), FieldType::Int, Some("0".to_owned()))));
+        assert_eq!(Alteration::parse("ADD note DEFAULT \"to do\"", 2), Ok(Alteration::AddField("note".to_owned(), FieldType::Str, Some("to do".to_owned()))));
+        assert_eq!(Alteration::parse("ADD note", 2), Ok(Alteration::AddField("note".to_owned(), FieldType::Str, None)));
+        assert_eq!(Alteration::parse("DROP note", 2), Ok(Alteration::DropField("note".to_owned())));
+        assert_eq!(Alteration::parse("RENAME note TO remark", 2), Ok(Alteration::RenameField("note".to_owned(), "remark".to_owned())));
+
+        let error = Alteration::parse("ADD score int 0", 3).unwrap_err();
+        assert_eq!((error.line, error.column), (3, 15));
+        assert_eq!(Alteration::parse("RENAME note remark", 2).unwrap_err().column, 13);
+        assert_eq!(Alteration::parse("DROP", 2).unwrap_err().column, 5);
+        assert!(Alteration::parse("\"DROP\" note", 2).is_err());
+        assert!(Alteration::parse("drop note", 2).is_err());
+    }
+
+    #[test]
+    fn schema_test() {
+        let mut fields = Schema::new();
+        fields.insert("name".to_owned(), FieldType::Str);
+        fields.insert("age".to_owned(), FieldType::Int);
+        let mut constraints = Constraints::new();
+        constraints.primary_key = Some("name".to_owned());
+        constraints.unique.insert("name".to_owned());
+
+        let alterations = vec![Alteration::AddField("score".to_owned(), FieldType::Int, Some("0".to_owned())),
+                               Alteration::RenameField("name".to_owned(), "login".to_owned()),
+                               Alteration::DropField("age".to_owned())];
+        let (altered, kept) = alter_schema(&fields, &constraints, &alterations).unwrap();
+        assert_eq!(altered.keys().cloned().collect::<Vec<String>>(), vec!["login", "score"]);
+        assert_eq!(kept.primary_key, Some("login".to_owned()));
+        assert!(kept.unique.contains("login") && !kept.unique.contains("name"));
+        assert_eq!(kept.defaults.get("score"), Some(&"0".to_owned()));
+
+        let mut item = Record::new();
+        item.insert("name".to_owned(), Value::Str("Ada".to_owned()));
+        item.insert("age".to_owned(), Value::Int(24));
+        for alteration in alterations.iter() {
+            alteration.apply(&mut item);
+        }
+        assert_eq!(item.get("login"), Some(&Value::Str("Ada".to_owned())));
+        assert_eq!(item.get("score"), Some(&Value::Int(0)));
+        assert_eq!(item.len(), 2);
+
+        // the first alteration that does not fit is reported
+        let check = |alteration: Alteration| alter_schema(&fields, &constraints, &[alteration]).err();
+        assert_eq!(check(Alteration::AddField("age".to_owned(), FieldType::Int, None)), Some(DbError::FieldExists("age".to_owned())));
+        assert_eq!(check(Alteration::AddField("score".to_owned(), FieldType::Int, Some("high".to_owned()))), Some(DbError::TypeMismatch("score".to_owned(), "high".to_owned())));
+        assert_eq!(check(Alteration::DropField("name".to_owned())), Some(DbError::ConstrainedField("name".to_owned())));
+        assert_eq!(check(Alteration::DropField("note".to_owned())), Some(DbError::UnknownField("note".to_owned())));
+        assert_eq!(check(Alteration::RenameField("age".to_owned(), "name".to_owned())), Some(DbError::FieldExists("name".to_owned())));
+        assert_eq!(check(Alteration::RenameField("age".to_owned(), "_id".to_owned())), Some(DbError::ReservedField("_id".to_owned())));
+    }
+}
@@ -0,0 +1,417 @@
+#[doc="
+  Storage backends of a collection.
+
+  PUTLIST picks the backend of a collection with a BACKEND line, it is
+  stored with the collection and kept when the database is loaded again:
+
+        sequential      the items in a list walked front to back (dbCollection.rs)
+        parallel        the items in a list scanned in chunks on a pool of
+                        worker threads (vecParallelCollection.rs)
+        indexed         the items by row with secondary indexes, primary key
+                        and unique fields and snapshot reads (vec_dbcollection.rs),
+                        the default
+
+  Every backend gives an item its _id and _version and answers APPEND, GET,
+  UPDATE, DELETE and AGGREGATE alike, the conformance tests at the end of
+  this file run the same statements on each of them. Indexes, constraints
+  and multi-version reads are only offered by the indexed backend.
+"]
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use rustc_serialize::{Encodable, Encoder, Decodable, Decoder};
+use rustc_serialize::json::{self, Json, EncoderError};
+
+use vec_dbcollection::{self, ItemNode, TableEntry, Record};
+use vecParallelCollection;
+use dbCollection;
+use value::{Value, Schema, Constraints, ID_FIELD, VERSION_FIELD};
+use filter::Filter;
+use query::Query;
+use aggregate::{Aggregate, Summary, summarize};
+use index::IndexKind;
+use error::DbError;
+use alter::Alteration;
+
+pub const NOT_INDEXED: &'static str = "Indexes need the indexed backend";
+
+pub type Backend = Box<dyn CollectionBackend>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendKind {
+    Sequential,
+    Parallel,
+    Indexed,
+}
+
+impl BackendKind {
+    pub fn from_name(name: &str) -> Option<BackendKind> {
+        match name.to_lowercase().as_ref() {
+            "sequential" => Some(BackendKind::Sequential),
+            "parallel" => Some(BackendKind::Parallel),
+            "indexed" => Some(BackendKind::Indexed),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BackendKind::Sequential => "sequential",
+            BackendKind::Parallel => "parallel",
+            BackendKind::Indexed => "indexed",
+        }
+    }
+}
+
+impl Encodable for BackendKind {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(self.name())
+    }
+}
+
+impl Decodable for BackendKind {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        let name = try!(d.read_str());
+        match BackendKind::from_name(&name) {
+            Some(kind) => Ok(kind),
+            None => Err(d.error(&format!("unknown backend {}", name))),
+        }
+    }
+}
+
+pub trait CollectionBackend: Debug + Send + Sync {
+    fn kind(&self) -> BackendKind;
+
+    fn get_fields(&self) -> &Schema;
+
+    // primary key, unique and required fields and defaults
+    fn get_constraints(&self) -> &Constraints;
+
+    // the number of items
+    fn get_number_of_data(&self) -> usize;
+
+    // every item, in insert order
+    fn get_items(&self) -> Vec<Record>;
+
+    // the items from skip on, at most take of them, in insert order, handed over without a copy
+    fn visit_items(&self, skip: usize, take: usize, visit: &mut dyn FnMut(&Record));
+
+    // the id of the new item
+    fn insert(&mut self, desired: &TableEntry) -> Result<u64, DbError>;
+
+    // the number of items changed
+    fn update(&mut self, target: &Filter<String>, desired: &TableEntry) -> Result<usize, DbError>;
+
+    // the matching items in insert order
+    fn find(&self, target: &Filter<String>) -> Result<Vec<Record>, DbError>;
+
+    // the number of items deleted
+    fn delete(&mut self, target: &Filter<String>) -> Result<usize, DbError>;
+
+    // a copy a transaction can change on its own
+    fn box_clone(&self) -> Backend;
+
+    // the stored form, read back by from_json
+    fn to_json(&self) -> Result<String, EncoderError>;
+
+    // change the fields of the collection and of every item, nothing is changed when one alteration does not fit
+    fn alter(&mut self, alterations: &[Alteration]) -> Result<(), DbError>;
+
+    // with an expected version nothing is changed unless every matching item is still at it
+    fn update_if(&mut self, target: &Filter<String>, desired: &TableEntry, expected: Option<u64>) -> Result<usize, DbError> {
+        if is_stale(&try!(self.find(target)), expected) {
+            return Err(DbError::StaleVersion);
+        }
+        self.update(target, desired)
+    }
+
+    // with an expected version nothing is deleted unless every matching item is still at it
+    fn delete_if(&mut self, target: &Filter<String>, expected: Option<u64>) -> Result<usize, DbError> {
+        if is_stale(&try!(self.find(target)), expected) {
+            return Err(DbError::StaleVersion);
+        }
+        self.delete(target)
+    }
+
+    // the matching items shaped by the options of GET
+    fn select(&self, target: &Filter<String>, query: &Query) -> Result<Vec<Record>, DbError> {
+        try!(query.check(self.get_fields()).map_err(DbError::InvalidQuery));
+        self.find(target).map(|items| query.apply(items.iter().collect()))
+    }
+
+    // summaries of the matching items
+    fn aggregate(&self, target: &Filter<String>, aggregate: &Aggregate) -> Result<Vec<Summary>, DbError> {
+        try!(aggregate.check(self.get_fields()).map_err(DbError::InvalidQuery));
+        summarize(try!(self.find(target)), aggregate)
+    }
+
+    fn create_index(&mut self, _field: &str, _kind: IndexKind) -> Result<&'static str, DbError> {
+        Err(DbError::Unsupported(NOT_INDEXED))
+    }
+
+    fn drop_index(&mut self, _field: &str) -> Result<&'static str, DbError> {
+        Err(DbError::Unsupported(NOT_INDEXED))
+    }
+
+    // drop what no reader needs anymore, the number of versions dropped
+    fn collect_garbage(&mut self) -> usize {
+        0
+    }
+
+    // the collection snapshot reads go through, None when only the latest version is kept
+    fn multi_version(&self) -> Option<&vec_dbcollection::Collection> {
+        None
+    }
+}
+
+impl Clone for Backend {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// one of the found items is not at the expected version
+fn is_stale(found: &[Record], expected: Option<u64>) -> bool {
+    match expected {
+        Some(expected) => found.iter().any(|item| item.get(VERSION_FIELD) != Some(&Value::Int(expected as i64))),
+        None => false,
+    }
+}
+
+// primary key and unique fields are kept by the indexes, so only the indexed backend takes them,
+// required fields and defaults are checked by every backend
+pub fn new_backend(kind: BackendKind, fields: &Schema, constraints: &Constraints) -> Result<Backend, DbError> {
+    let constrained = constraints.primary_key.is_some() || !constraints.unique.is_empty();
+    if let Some(field) = constraints.required.iter().chain(constraints.defaults.keys()).find(|field| !fields.contains_key(*field)) {
+        return Err(DbError::UnknownField(field.clone()));
+    }
+    match kind {
+        BackendKind::Indexed => Ok(Box::new(try!(vec_dbcollection::Collection::with_constraints(fields, constraints)))),
+        _ if constrained => Err(DbError::Unsupported("Primary key and unique fields need the indexed backend")),
+        BackendKind::Sequential => Ok(Box::new(dbCollection::Collection::with_constraints(fields, constraints))),
+        BackendKind::Parallel => Ok(Box::new(vecParallelCollection::Collection::with_constraints(fields, constraints))),
+    }
+}
+
+// the stored form of a backend without versions and indexes, the layout the indexed backend reads
+#[derive(RustcEncodable)]
+struct Stored {
+    backend: BackendKind,
+    fields: Schema,
+    constraints: Constraints,
+    entries: BTreeMap<u64, ItemNode>,
+    next_row: u64,
+}
+
+pub fn items_to_json(kind: BackendKind, fields: &Schema, constraints: &Constraints, items: Vec<Record>, next_id: u64) -> Result<String, EncoderError> {
+    let entries = items.iter().map(|item| {
+        let id = match item.get(ID_FIELD) {
+            Some(&Value::Int(id)) => id as u64,
+            _ => 0,
+        };
+        (id, ItemNode::new(item))
+    }).collect();
+    json::encode(&Stored {
+        backend: kind,
+        fields: fields.clone(),
+        constraints: constraints.clone(),
+        entries: entries,
+        next_row: next_id,
+    })
+}
+
+// read back a stored collection, one stored without a backend is an indexed one
+pub fn from_json(json: &Json) -> Result<Backend, String> {
+    let kind = match json.find("backend") {
+        Some(name) => match name.as_string().and_then(BackendKind::from_name) {
+            Some(kind) => kind,
+            None => return Err(format!("unknown backend {}", name)),
+        },
+        None => BackendKind::Indexed,
+    };
+    let collection = try!(vec_dbcollection::Collection::from_json(json));
+    Ok(match kind {
+        BackendKind::Indexed => Box::new(collection),
+        BackendKind::Sequential => Box::new(dbCollection::Collection::from_items(collection.get_fields(), collection.get_constraints(), collection.get_items(), collection.get_next_row())),
+        BackendKind::Parallel => Box::new(vecParallelCollection::Collection::from_items(collection.get_fields(), collection.get_constraints(), collection.get_items(), collection.get_next_row())),
+    })
+}
+
+
+#[cfg(test)]
+mod conformance_tests {
+    use super::{BackendKind, Backend, new_backend, from_json, NOT_INDEXED};
+    use rustc_serialize::json::Json;
+    use vec_dbcollection::{TableEntry, Record};
+    use value::{Value, FieldType, Schema, Constraints, VERSION_FIELD, NULL_TEXT};
+    use filter::Filter;
+    use query::Query;
+    use aggregate::Aggregate;
+    use index::IndexKind;
+    use error::DbError;
+    use alter::Alteration;
+
+    #[test]
+    fn sequential_test() {
+        conformance(BackendKind::Sequential);
+    }
+
+    #[test]
+    fn parallel_test() {
+        conformance(BackendKind::Parallel);
+    }
+
+    #[test]
+    fn indexed_test() {
+        conformance(BackendKind::Indexed);
+    }
+
+    #[test]
+    fn constraints_test() {
+        let mut constraints = Constraints::new();
+        constraints.unique.insert("name".to_owned());
+        assert!(new_backend(BackendKind::Sequential, &new_fields(), &constraints).is_err());
+        assert!(new_backend(BackendKind::Parallel, &new_fields(), &constraints).is_err());
+        assert!(new_backend(BackendKind::Indexed, &new_fields(), &constraints).is_ok());
+
+        let mut sequential = new_backend(BackendKind::Sequential, &new_fields(), &Constraints::new()).unwrap();
+        assert_eq!(sequential.create_index("age", IndexKind::BTree), Err(DbError::Unsupported(NOT_INDEXED)));
+        assert!(sequential.multi_version().is_none());
+    }
+
+    #[test]
+    fn required_test() {
+        let mut constraints = Constraints::new();
+        constraints.required.insert("name".to_owned());
+        constraints.required.insert("age".to_owned());
+        constraints.defaults.insert("age".to_owned(), "18".to_owned());
+        for kind in vec![BackendKind::Sequential, BackendKind::Parallel, BackendKind::Indexed] {
+            let mut collection = new_backend(kind, &new_fields(), &constraints).unwrap();
+            assert_eq!(collection.insert(&new_age("30")), Err(DbError::MissingField("name".to_owned())));
+            let mut ada = TableEntry::new();
+            ada.insert("name".to_owned(), "Ada".to_owned());
+            assert_eq!(collection.insert(&ada), Ok(0));
+            assert_eq!(collection.get_items()[0]["age"], Value::Int(18));
+
+            // kept by the stored form, an item without a required field is not read back
+            let mut loaded = reload(&collection);
+            assert_eq!(loaded.get_constraints(), collection.get_constraints());
+            assert_eq!(loaded.insert(&new_age("30")), Err(DbError::MissingField("name".to_owned())));
+            let stored = collection.to_json().unwrap().replace("\"name\":\"Ada\",", "").replace(",\"name\":\"Ada\"", "");
+            assert!(!stored.contains("Ada") && from_json(&Json::from_str(&stored).unwrap()).is_err());
+        }
+        constraints.defaults.insert("gender".to_owned(), "m".to_owned());
+        assert_eq!(new_backend(BackendKind::Sequential, &new_fields(), &constraints).err(), Some(DbError::UnknownField("gender".to_owned())));
+    }
+
+    #[test]
+    fn nullable_test() {
+        let mut constraints = Constraints::new();
+        constraints.nullable.insert("name".to_owned());
+        constraints.defaults.insert("name".to_owned(), "Anon".to_owned());
+        for kind in vec![BackendKind::Sequential, BackendKind::Parallel, BackendKind::Indexed] {
+            let mut collection = new_backend(kind, &new_fields(), &constraints).unwrap();
+            assert_eq!(collection.insert(&new_entry("Ada", "30")), Ok(0));
+            // NULL leaves a nullable field without a value, its default is not stored
+            assert_eq!(collection.insert(&new_entry(NULL_TEXT, "20")), Ok(1));
+            assert_eq!(collection.get_items()[1].get("name"), None);
+            assert_eq!(collection.insert(&new_age(NULL_TEXT)), Err(DbError::NotNullable("age".to_owned())));
+
+            // UPDATE clears a nullable field, and only a nullable one
+            let ada = Filter::parse("age 30", 1).unwrap();
+            assert_eq!(collection.update(&ada, &new_entry(NULL_TEXT, "31")), Ok(1));
+            let cleared = collection.find(&Filter::parse("age 31", 1).unwrap()).unwrap();
+            assert_eq!((cleared[0].get("name"), cleared[0].get(VERSION_FIELD)), (None, Some(&Value::Int(2))));
+            assert_eq!(collection.update(&Filter::new(), &new_age(NULL_TEXT)), Err(DbError::NotNullable("age".to_owned())));
+            assert_eq!(reload(&collection).get_constraints(), collection.get_constraints());
+        }
+    }
+
+    // the same statements and answers on every backend
+    fn conformance(kind: BackendKind) {
+        let mut collection = new_backend(kind, &new_fields(), &Constraints::new()).unwrap();
+        assert_eq!(collection.kind(), kind);
+        for (id, &(name, age)) in [("Ada", "24"), ("Bob", "25"), ("Cy", "25")].iter().enumerate() {
+            assert_eq!(collection.insert(&new_entry(name, age)), Ok(id as u64));
+        }
+        assert_eq!(collection.insert(&new_entry("Dan", "old")), Err(DbError::TypeMismatch("age".to_owned(), "old".to_owned())));
+        let mut unknown = new_entry("Dan", "26");
+        unknown.insert("gender".to_owned(), "m".to_owned());
+        assert_eq!(collection.insert(&unknown), Err(DbError::UnknownField("gender".to_owned())));
+        assert_eq!(collection.get_number_of_data(), 3);
+        assert_eq!(names(&collection.get_items()), vec!["Ada", "Bob", "Cy"]);
+
+        // found in insert order
+        let twenty_five = Filter::parse("age 25", 1).unwrap();
+        assert_eq!(names(&collection.find(&twenty_five).unwrap()), vec!["Bob", "Cy"]);
+        assert_eq!(collection.find(&Filter::parse("gender m", 1).unwrap()), Err(DbError::UnknownField("gender".to_owned())));
+
+        // every write counts in the version of the item
+        let bob = Filter::parse("name Bob", 1).unwrap();
+        assert_eq!(collection.update(&twenty_five, &new_age("26")), Ok(2));
+        assert_eq!(collection.find(&bob).unwrap()[0][VERSION_FIELD], Value::Int(2));
+        assert_eq!(collection.update_if(&bob, &new_age("27"), Some(1)), Err(DbError::StaleVersion));
+        assert_eq!(collection.update_if(&bob, &new_age("27"), Some(2)), Ok(1));
+        assert_eq!(collection.delete_if(&bob, Some(2)), Err(DbError::StaleVersion));
+        assert_eq!(collection.update(&Filter::parse("gender m", 1).unwrap(), &new_age("1")), Err(DbError::UnknownField("gender".to_owned())));
+
+        let mut query = Query::new();
+        assert!(query.set("SORT", &["age".to_owned(), "desc".to_owned()]).is_ok());
+        assert_eq!(names(&collection.select(&Filter::new(), &query).unwrap()), vec!["Bob", "Cy", "Ada"]);
+        let mut aggregate = Aggregate::new();
+        assert!(aggregate.set("COUNT", &[]).is_ok());
+        assert_eq!(collection.aggregate(&Filter::parse("age > 24", 1).unwrap(), &aggregate).unwrap()[0]["count"], Some(Value::Int(2)));
+
+        // a copy is changed on its own
+        let mut copy = collection.clone();
+        assert_eq!(copy.delete_if(&bob, Some(3)), Ok(1));
+        assert_eq!(copy.get_number_of_data(), 2);
+        assert_eq!(collection.get_number_of_data(), 3);
+
+        // stored with its backend, ids are not handed out twice after a reload
+        assert_eq!(collection.delete(&Filter::parse("name Cy", 1).unwrap()), Ok(1));
+        let mut loaded = reload(&collection);
+        assert_eq!(loaded.kind(), kind);
+        assert_eq!(loaded.get_items(), collection.get_items());
+        assert_eq!(loaded.insert(&new_entry("Dan", "30")), Ok(3));
+
+        // altered together or not at all, the new fields are stored
+        let rename = Alteration::RenameField("age".to_owned(), "years".to_owned());
+        let score = Alteration::AddField("score".to_owned(), FieldType::Int, Some("0".to_owned()));
+        assert_eq!(loaded.alter(&[rename.clone(), Alteration::DropField("gender".to_owned())]), Err(DbError::UnknownField("gender".to_owned())));
+        assert_eq!(loaded.get_fields(), &new_fields());
+        assert_eq!(loaded.alter(&[rename, score]), Ok(()));
+        assert_eq!(loaded.find(&Filter::parse("years 30; score 0", 1).unwrap()).unwrap().len(), 1);
+        assert_eq!(loaded.find(&twenty_five), Err(DbError::UnknownField("age".to_owned())));
+        assert_eq!(reload(&loaded).get_items(), loaded.get_items());
+    }
+
+    fn reload(collection: &Backend) -> Backend {
+        from_json(&Json::from_str(&collection.to_json().unwrap()).unwrap()).unwrap()
+    }
+
+    fn new_fields() -> Schema {
+        let mut fields = Schema::new();
+        fields.insert("name".to_owned(), FieldType::Str);
+        fields.insert("age".to_owned(), FieldType::Int);
+        fields
+    }
+
+    fn new_entry(name: &str, age: &str) -> TableEntry {
+        let mut entry = new_age(age);
+        entry.insert("name".to_owned(), name.to_owned());
+        entry
+    }
+
+    fn new_age(age: &str) -> TableEntry {
+        let mut entry = TableEntry::new();
+        entry.insert("age".to_owned(), age.to_owned());
+        entry
+    }
+
+    fn names(items: &Vec<Record>) -> Vec<String> {
+        items.iter().map(|item| match item.get("name") {
+            Some(&Value::Str(ref name)) => name.clone(),
+            _ => String::new(),
+        }).collect()
+    }
+}
@@ -1,114 +1,131 @@
-use std::sync::{Arc,Mutex};
-use std::collections::{LinkedList,HashMap, BtreeSet};
-use std::thread;
-use std::fmt::{Display};
-
-pub type TableEntry = HashMap<String, String>;
-pub type Set<K> = BtreeSet<K>;
-
-pub struct ItemNode {
-    valid: bool,
-    content: TableEntry,
+#[doc="
+  The sequential backend: the items of a collection in a linked list.
+
+  Every command walks the list from the front, in the order the items were
+  appended. There are no indexes, no constraints and only the latest
+  version of an item, which makes it the smallest of the backends and the
+  one the others are measured against (see backend.rs).
+"]
+use std::collections::LinkedList;
+use std::mem;
+use rustc_serialize::json::EncoderError;
+
+use vec_dbcollection::{ItemNode, TableEntry, Record, to_changes, to_new_record};
+use value::{Value, Schema, Constraints, ID_FIELD, VERSION_FIELD};
+use filter::Filter;
+use backend::{CollectionBackend, BackendKind, Backend, items_to_json};
+use error::DbError;
+use alter::{Alteration, alter_schema};
+
+pub type EntryList = LinkedList<ItemNode>;
+
+#[derive(Debug, Clone)]
+pub struct Collection{
+    fields: Schema,
+    constraints: Constraints,   // only required fields and defaults, see new_backend
+    entries: EntryList,
+    next_id: u64,
 }
 
-impl ItemNode {
-    pub fn new(entry: &TableEntry) -> Self {
-        ItemNode {
-            valid: true,
-            content: entry.to_owned(),
+impl Collection{
+    pub fn with_constraints(fields: &Schema, constraints: &Constraints) -> Self {
+        Collection {
+            fields: fields.to_owned(),
+            constraints: constraints.to_owned(),
+            entries: EntryList::new(),
+            next_id: 0,
         }
     }
 
-    pub fn is_valid(&self) -> bool {
-        self.valid
+    // items read back from a snapshot, in id order
+    pub fn from_items(fields: &Schema, constraints: &Constraints, items: Vec<Record>, next_id: u64) -> Self {
+        Collection {
+            fields: fields.to_owned(),
+            constraints: constraints.to_owned(),
+            entries: items.iter().map(ItemNode::new).collect(),
+            next_id: next_id,
+        }
     }
+}
 
-    pub fn set_valid(&mut self, set_value: bool){
-        self.valid = set_value;
+impl CollectionBackend for Collection{
+    fn kind(&self) -> BackendKind {
+        BackendKind::Sequential
     }
 
-    pub fn update_field(&mut self, field_name: String, field_value: String) -> Result<(), &str>{
-        if let Some(x) = self.content.get_mut(&field_name) {
-            *x = field_value;
-            Ok(())
-        }
-        else {
-            Err("Such field does not exist!")
-        }
+    fn get_fields(&self) -> &Schema {
+        &self.fields
     }
 
+    fn get_constraints(&self) -> &Constraints {
+        &self.constraints
+    }
 
-    pub fn matched(&self, template: &TableEntry) -> bool{
-        
-        for key in template.keys() {
-            if self.content.get(key) != template.get(key){
-                return false;
-            }
-        }
-        return true;
+    fn get_number_of_data(&self) -> usize {
+        self.entries.len()
     }
 
+    fn get_items(&self) -> Vec<Record> {
+        self.entries.iter().map(|item| item.get_content().clone()).collect()
+    }
 
-    fn modify(&mut self, template: &TableEntry) {
-        for key in template.keys() {
-            let val = self.content.entry(key.clone()).or_insert("".to_owned());
-            *val = template.get(key).unwrap().clone();
+    fn visit_items(&self, skip: usize, take: usize, visit: &mut dyn FnMut(&Record)) {
+        for item in self.entries.iter().skip(skip).take(take) {
+            visit(item.get_content());
         }
     }
-}
-
-
-pub type EntryList = LinkedList<Arc<Mutex<Box<ItemNode>>>>;
 
-
-pub struct Collection{
-    fields: Set<String>,
-    entries: EntryList,
-}
-
-impl Collection{
-    pub fn new(fields: &Set<String>) -> Self {
-        Collection {
-            fields: fields.to_owned(),
-            entries: EntryList::new()
-        }
+    // the id of the new item
+    fn insert(&mut self, desired: &TableEntry) -> Result<u64, DbError> {
+        let mut record = try!(to_new_record(&self.fields, &self.constraints, desired));
+        let id = self.next_id;
+        self.next_id += 1;
+        record.insert(ID_FIELD.to_owned(), Value::Int(id as i64));
+        record.insert(VERSION_FIELD.to_owned(), Value::Int(1));
+        self.entries.push_back(ItemNode::new(&record));
+        Ok(id)
     }
 
-    fn is_valid(&self,  target: &TableEntry) -> bool {
-        for key in target.keys() {
-            if !self.fields.contains(key){
-                return false;
-            }
+    fn update(&mut self, target: &Filter<String>, desired: &TableEntry) -> Result<usize, DbError> {
+        let target = try!(target.bind(&self.fields));
+        let desired = try!(to_changes(&self.fields, &self.constraints, desired));
+        let mut count = 0;
+        for item in self.entries.iter_mut().filter(|item| item.matched(&target)) {
+            item.revise(&desired);
+            count += 1;
         }
-        return true;
+        Ok(count)
     }
 
+    fn find(&self, target: &Filter<String>) -> Result<Vec<Record>, DbError> {
+        let target = try!(target.bind(&self.fields));
+        Ok(self.entries.iter().filter(|item| item.matched(&target)).map(|item| item.get_content().clone()).collect())
+    }
 
+    fn delete(&mut self, target: &Filter<String>) -> Result<usize, DbError> {
+        let target = try!(target.bind(&self.fields));
+        let before = self.entries.len();
+        // the list is walked once and relinked without the matching items
+        let entries = mem::replace(&mut self.entries, EntryList::new());
+        self.entries = entries.into_iter().filter(|item| !item.matched(&target)).collect();
+        Ok(before - self.entries.len())
+    }
 
-
-    pub fn update(&mut self, target: &TableEntry, updated: &TableEntry) -> Option<usize>{
-        if !self.is_valid(target) || self.is_valid(updated) {
-            None
-        } else {
-
-            let mut count = 0;
-            let mut it = self.entries.iter();
-            while let Some(ref item) = it.next() {
-                let peek = item.into_inner().unwrap();
-                if peek.matched(target){
-                    let shared_item = item.clone();
-                    let mut node = shared_item.lock().unwrap();
-                    if node.is_valid() {
-                        node.modify(updated);
-                        count += 1;
-                    }
-
-                }
-            }
-
-            Some(count)
+    fn alter(&mut self, alterations: &[Alteration]) -> Result<(), DbError> {
+        let (fields, constraints) = try!(alter_schema(&self.fields, &self.constraints, alterations));
+        self.fields = fields;
+        self.constraints = constraints;
+        for item in self.entries.iter_mut() {
+            item.alter(alterations);
         }
+        Ok(())
+    }
 
+    fn box_clone(&self) -> Backend {
+        Box::new(self.clone())
     }
 
-}
\ No newline at end of file
+    fn to_json(&self) -> Result<String, EncoderError> {
+        items_to_json(self.kind(), &self.fields, &self.constraints, self.get_items(), self.next_id)
+    }
+}
@@ -1,173 +1,414 @@
 #[doc="
   DB interface
+  Every collection sits behind its own reader/writer lock, the catalog lock
+  is only held while a collection is looked up, created or removed.
 "]
-use std::collections::{HashMap, BTreeSet};
-use vec_dbcollection::Collection;
-type Set<K> = BTreeSet<K>;
-type CollectionObj= HashMap<String,Collection>;
+use std::collections::{HashMap, BTreeMap};
+use std::sync::{Arc, RwLock};
+use std::cmp;
+use std::mem;
+use rustc_serialize::json::{self, Json, EncoderError};
+use backend::{self, Backend, BackendKind, new_backend};
+use value::{Value, Schema, Constraints};
+use vec_dbcollection::Record;
+use error::DbError;
+pub type SharedCollection = Arc<RwLock<Backend>>;
+// items on a page of SHOWDB without a LIMIT
+pub const PAGE_ITEMS: usize = 100;
+// items on a page of SHOWDB at most, a larger LIMIT is cut down to it
+pub const MAX_PAGE_ITEMS: usize = 1000;
+type CollectionObj= HashMap<String,SharedCollection>;
 
-#[derive(RustcDecodable, RustcEncodable)]
 pub struct RustDB {
-    collections: CollectionObj,
+    collections: RwLock<CollectionObj>,
 }
 
 impl RustDB {
     pub fn new() -> Self{
         RustDB{
-            collections: CollectionObj::new(),
+            collections: RwLock::new(CollectionObj::new()),
         }
     }
 
-    pub fn create_table(&mut self, cl_name: &str, fields: &Set<String>)->Result<&Collection,&'static str>{
-        if self.collections.contains_key(cl_name){
-            return Err("Collection name already exists.");
-        }
-        let cl = Collection::new(&fields);
-        self.collections.insert(cl_name.to_owned(),cl);
-        match self.collections.get(cl_name){
-            Some(col) => {
-                return Ok(col);
-            },
-            None => Err("Database insert error"),
+    pub fn create_table(&self, cl_name: &str, fields: &Schema, constraints: &Constraints, kind: BackendKind)->Result<SharedCollection,DbError>{
+        let mut collections = self.collections.write().unwrap();
+        if collections.contains_key(cl_name){
+            return Err(DbError::CollectionExists(cl_name.to_owned()));
         }
+        let cl = Arc::new(RwLock::new(try!(new_backend(kind, &fields, constraints))));
+        collections.insert(cl_name.to_owned(),cl.clone());
+        Ok(cl)
     }
 
-    pub fn find_cl(&mut self, cl_name: &str) -> Result<&mut Collection,&'static str>{
-        match self.collections.get_mut(cl_name) {
+    pub fn find_cl(&self, cl_name: &str) -> Result<SharedCollection,DbError>{
+        match self.collections.read().unwrap().get(cl_name) {
             Some(col) => {
-                return Ok(col);
+                return Ok(col.clone());
             },
-            None => Err("Collection name does not exist."),
+            None => Err(DbError::UnknownCollection(cl_name.to_owned())),
         }
     }
 
-    pub fn delete_cl(&mut self, cl_name: &str) -> Result<&'static str, &'static str>{
-        match self.collections.contains_key(cl_name) {
-            true => {
-                self.collections.remove(cl_name);
+    // every collection, for work that goes through all of them
+    pub fn all_cl(&self) -> Vec<SharedCollection>{
+        self.collections.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn delete_cl(&self, cl_name: &str) -> Result<&'static str, DbError>{
+        match self.collections.write().unwrap().remove(cl_name) {
+            Some(_) => {
                 return Ok("Collection has been deleted");
             }
-            false => {
-                return Err("Collection does not exist.");
+            None => {
+                return Err(DbError::UnknownCollection(cl_name.to_owned()));
             }
         }
     }
 
-    // pub fn get_collections(&mut self) -> Vec<&str>{
-    //     let mut cls: Vec<&str> = Vec::new();
-    //     for key in self.collections.keys(){
-    //         cls.push(key);
-    //     }
-    //     cls
-    // }
+    // names of the collections, in name order
+    pub fn get_collections(&self) -> Vec<String>{
+        let mut names: Vec<String> = self.collections.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
 
-    pub fn find_cl_immute(&self, cl_name: &str) -> Result<&Collection,&'static str>{
-        match self.collections.get(cl_name) {
-            Some(col) => {
-                return Ok(col);
-            },
-            None => Err("Collection name does not exist."),
+    // the catalog entry of a collection, the sizes are estimates summed up over the items without copying them
+    pub fn describe(&self, cl_name: &str) -> Result<Description, DbError>{
+        let cl = try!(self.find_cl(cl_name));
+        let cl = cl.read().unwrap();
+        let constraints = cl.get_constraints().clone();
+        let indexes = match cl.multi_version() {
+            Some(indexed) => indexed.get_indexes().iter().map(|(field, index)| (field.clone(), index.kind().name().to_owned())).collect(),
+            None => BTreeMap::new(),
+        };
+        let (mut memory_bytes, mut disk_bytes) = (0, 0);
+        cl.visit_items(0, usize::max_value(), &mut |item| {
+            memory_bytes += memory_size(item);
+            disk_bytes += stored_size(item);
+        });
+        Ok(Description {
+            name: cl_name.to_owned(),
+            backend: cl.kind(),
+            fields: type_names(cl.get_fields()),
+            primary_key: constraints.primary_key,
+            unique: constraints.unique.into_iter().collect(),
+            required: constraints.required.into_iter().collect(),
+            defaults: constraints.defaults,
+            nullable: constraints.nullable.into_iter().collect(),
+            indexes: indexes,
+            snapshot_reads: cl.multi_version().is_some(),
+            items: cl.get_number_of_data(),
+            memory_bytes: memory_bytes,
+            disk_bytes: disk_bytes,
+        })
+    }
+
+    // the items of every collection, one collection after the other in name order and each in id order,
+    // from the offset on, at most MAX_PAGE_ITEMS; collections are read one at a time, so a page is not one snapshot of the database
+    pub fn dump(&self, offset: usize, limit: usize) -> Page{
+        let limit = cmp::min(limit, MAX_PAGE_ITEMS);
+        let end = offset.saturating_add(limit);
+        let mut position = 0;           // items of the collections before the current one
+        let mut more = false;           // an empty collection starts the next page
+        let mut listed = Vec::new();
+        for name in self.get_collections() {
+            let cl = match self.find_cl(&name) {
+                Ok(cl) => cl,
+                Err(_) => continue,     // removed meanwhile
+            };
+            let cl = cl.read().unwrap();
+            let count = cl.get_number_of_data();
+            let (start, from, to) = (position, cmp::max(position, offset), cmp::min(position + count, end));
+            position += count;
+            // an empty collection is listed on the page its position falls on
+            if from < to || (count == 0 && start >= offset && start < end) {
+                let mut items = Vec::new();
+                cl.visit_items(from - start, to.saturating_sub(from), &mut |item| items.push(item.clone()));
+                listed.push(DumpedCollection {
+                    name: name,
+                    backend: cl.kind(),
+                    fields: type_names(cl.get_fields()),
+                    items: items,
+                });
+            }
+            more = more || (count == 0 && start == end);
+        }
+        Page {
+            offset: offset,
+            limit: limit,
+            total: position,
+            next: if position > end || more { Some(end) } else { None },
+            collections: listed,
         }
     }
+}
+
+// what DESCRIBE answers
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct Description {
+    pub name: String,
+    pub backend: BackendKind,
+    pub fields: BTreeMap<String, String>,       // field and the name of its type
+    pub primary_key: Option<String>,
+    pub unique: Vec<String>,
+    pub required: Vec<String>,
+    pub defaults: BTreeMap<String, String>,     // field and its default as declared
+    pub nullable: Vec<String>,
+    pub indexes: BTreeMap<String, String>,      // field and the kind of its index
+    pub snapshot_reads: bool,                   // reads do not wait for writers, the indexed backend only
+    pub items: usize,
+    pub memory_bytes: usize,                    // the latest version of every item
+    pub disk_bytes: usize,                      // the items in a snapshot
+}
+
+// one page of SHOWDB, next is the offset of the following page
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: usize,
+    pub total: usize,                           // items in the database
+    pub next: Option<usize>,
+    pub collections: Vec<DumpedCollection>,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct DumpedCollection {
+    pub name: String,
+    pub backend: BackendKind,
+    pub fields: BTreeMap<String, String>,
+    pub items: Vec<Record>,                     // the items of the collection on this page
+}
+
+fn type_names(fields: &Schema) -> BTreeMap<String, String> {
+    fields.iter().map(|(field, field_type)| (field.clone(), field_type.name().to_owned())).collect()
+}
 
-    pub fn show_db(&mut self){
-        for name in self.collections.keys(){
-            self.show_cl(name);
+// field names, values and the text of strings
+fn memory_size(item: &Record) -> usize {
+    item.iter().map(|(field, value)| {
+        let text = match *value {
+            Value::Str(ref text) => text.len(),
+            _ => 0,
+        };
+        field.len() + mem::size_of::<String>() + mem::size_of::<Value>() + text
+    }).sum()
+}
+
+// the item as JSON, quotes, colons, commas and braces included
+fn stored_size(item: &Record) -> usize {
+    item.iter().map(|(field, value)| {
+        let text = match *value {
+            Value::Str(ref text) => text.len() + 2,
+            Value::Timestamp(_) => value.to_string().len() + 2,
+            _ => value.to_string().len(),
+        };
+        field.len() + 4 + text
+    }).sum::<usize>() + 2
+}
+
+impl RustDB {
+    // stored as {"collections": {name: collection, ...}}, every backend writes its own collections
+    pub fn to_json(&self) -> Result<String, EncoderError> {
+        let collections = self.collections.read().unwrap();
+        let mut stored = Vec::new();
+        for (name, cl) in collections.iter() {
+            stored.push(format!("{}:{}", try!(json::encode(name)), try!(cl.read().unwrap().to_json())));
         }
+        Ok(format!("{{\"collections\":{{{}}}}}", stored.join(",")))
     }
 
-    fn show_cl(&self, cl_name: &str){
-        match self.find_cl_immute(cl_name) {
-            Ok(cl) => {
-                println!("**************** Collection: {} ****************", cl_name);
-                for field in cl.get_fields().iter(){
-                    print!("{:?}", field);
-                    print!("           ");
-                }
-                print!("\n");
-                let item_list = cl.get_entries();
-                for item in item_list{
-                    for field in cl.get_fields().iter() {
-                        print!("{:?}", item.get_content().get(field).unwrap());
-                        print!("           ");
-                    }
-                    print!("\n");
-                }
-                println!("\r\n");
-            }
-            Err(e) => {
-                println!("{:?}", e);
-                println!("\r\n");
-                // return false;
-            },
+    // values are read back through each collection's schema, so they are not decoded generically
+    pub fn from_json(json: &Json) -> Result<RustDB, String> {
+        let stored = match json.find("collections") {
+            Some(&Json::Object(ref object)) => object,
+            _ => return Err("missing collections".to_owned()),
+        };
+        let mut collections = CollectionObj::new();
+        for (name, cl) in stored.iter() {
+            let cl = match backend::from_json(cl) {
+                Ok(cl) => cl,
+                Err(e) => return Err(format!("collection {}: {}", name, e)),
+            };
+            collections.insert(name.clone(), Arc::new(RwLock::new(cl)));
         }
+        Ok(RustDB{
+            collections: RwLock::new(collections),
+        })
     }
 }
 
 
 mod database_test{
     #[allow(unused_imports)]
-    use super::{RustDB,Set};
+    use super::{RustDB, MAX_PAGE_ITEMS};
     #[allow(unused_imports)]
-    use vec_dbcollection::{Collection,TableEntry};
+    use std::sync::Arc;
+    #[allow(unused_imports)]
+    use std::thread;
+    #[allow(unused_imports)]
+    use vec_dbcollection::TableEntry;
+    #[allow(unused_imports)]
+    use backend::{BackendKind, CollectionBackend};
+    #[allow(unused_imports)]
+    use value::{FieldType,Schema,Constraints,Value};
+    #[allow(unused_imports)]
+    use rustc_serialize::json::{self,Json};
+    #[allow(unused_imports)]
+    use filter::Filter;
+    #[allow(unused_imports)]
+    use error::DbError;
+    #[allow(unused_imports)]
+    use index::IndexKind;
 
     #[test]
     fn create_table_test(){
-        let mut db = RustDB::new();
+        let db = RustDB::new();
         let fields = new_student_fields();
-        let collection_for_test = Collection::new(&fields);
-        let create_result = db.create_table("student",&fields);
+        let create_result = db.create_table("student",&fields,&Constraints::new(),BackendKind::Indexed);
         assert!(create_result.is_ok());
-        assert_eq!(&collection_for_test,create_result.unwrap());
+        let created = create_result.unwrap();
+        assert_eq!(*created.read().unwrap().get_fields(),fields);
+        assert_eq!(created.read().unwrap().kind(),BackendKind::Indexed);
     }
 
     #[test]
     fn find_cl_test(){
-        let mut db = RustDB::new();
+        let db = RustDB::new();
         let fields = new_student_fields();
-        assert!(db.create_table("student",&fields).is_ok());
+        assert!(db.create_table("student",&fields,&Constraints::new(),BackendKind::Indexed).is_ok());
         assert!(db.find_cl("student").is_ok());
-        assert!(!db.find_cl("teacher").is_ok());
+        assert_eq!(db.find_cl("teacher").err(), Some(DbError::UnknownCollection("teacher".to_owned())));
     }
     #[test]
     fn create_table_when_table_exists() {
-        let mut db = RustDB::new();
+        let db = RustDB::new();
         let student_fields = new_student_fields();
         let other_fields = new_other_fields();
-        assert!(db.create_table("student",&student_fields).is_ok());
-        assert!(!db.create_table("student",&other_fields).is_ok());
+        assert!(db.create_table("student",&student_fields,&Constraints::new(),BackendKind::Indexed).is_ok());
+        assert!(!db.create_table("student",&other_fields,&Constraints::new(),BackendKind::Indexed).is_ok());
     }
     #[test]
     fn delete_cl_test(){
-        let mut db = RustDB::new();
+        let db = RustDB::new();
         let student_fields = new_student_fields();
-        db.create_table("student",&student_fields);
+        db.create_table("student",&student_fields,&Constraints::new(),BackendKind::Indexed);
         assert!(db.delete_cl("student").is_ok());
         assert!(!db.delete_cl("student").is_ok());
     }
     #[test]
     fn create_table_after_deletion() {
-        let mut db = RustDB::new();
+        let db = RustDB::new();
         let student_fields = new_student_fields();
-        db.create_table("student",&student_fields);
+        db.create_table("student",&student_fields,&Constraints::new(),BackendKind::Indexed);
         db.delete_cl("student");
-        assert!(db.create_table("student",&student_fields).is_ok());
+        assert!(db.create_table("student",&student_fields,&Constraints::new(),BackendKind::Indexed).is_ok());
+    }
+
+    #[test]
+    fn concurrent_access_test() {
+        let db = Arc::new(RustDB::new());
+        db.create_table("student",&new_student_fields(),&Constraints::new(),BackendKind::Indexed).unwrap();
+        db.create_table("teacher",&new_other_fields(),&Constraints::new(),BackendKind::Indexed).unwrap();
+
+        let mut handles = vec![];
+        for id in 0..4 {
+            let db = db.clone();
+            handles.push(thread::spawn(move || {
+                let cl = db.find_cl("student").unwrap();
+                cl.write().unwrap().insert(&new_sort_entry(id, "Ada", 24)).unwrap();
+                let reader = cl.read().unwrap();
+                assert!(reader.find(&Filter::new()).unwrap().len() > 0);
+            }));
+        }
+        // the other collection stays readable while student is written
+        let teacher = db.find_cl("teacher").unwrap();
+        assert_eq!(teacher.read().unwrap().get_number_of_data(), 0);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(db.find_cl("student").unwrap().read().unwrap().get_number_of_data(), 4);
+    }
+
+    #[test]
+    fn json_round_trip_test() {
+        let db = RustDB::new();
+        db.create_table("student",&new_student_fields(),&Constraints::new(),BackendKind::Indexed).unwrap();
+        db.find_cl("student").unwrap().write().unwrap().insert(&new_sort_entry(0, "Ada", 24)).unwrap();
+        db.create_table("teacher",&new_other_fields(),&Constraints::new(),BackendKind::Sequential).unwrap();
+
+        let content = db.to_json().unwrap();
+        let loaded = RustDB::from_json(&Json::from_str(&content).unwrap()).unwrap();
+        let cl = loaded.find_cl("student").unwrap();
+        let found = cl.read().unwrap().find(&Filter::new()).unwrap();
+        assert_eq!(found[0].get("age"), Some(&Value::Int(24)));
+        assert_eq!(found[0].get("name"), Some(&Value::from("Ada")));
+        assert_eq!(loaded.find_cl("teacher").unwrap().read().unwrap().kind(), BackendKind::Sequential);
+
+        // written before fields had types
+        let legacy = "{\"collections\":{\"student\":{\"fields\":[\"id\",\"name\"],\"entries\":[{\"valid\":true,\"content\":{\"id\":\"0\",\"name\":\"Ada\"}}]}}}";
+        let loaded = RustDB::from_json(&Json::from_str(legacy).unwrap()).unwrap();
+        assert_eq!(loaded.find_cl("student").unwrap().read().unwrap().get_number_of_data(), 1);
+    }
+
+    #[test]
+    fn catalog_test() {
+        let db = RustDB::new();
+        let mut constraints = Constraints::new();
+        constraints.primary_key = Some("id".to_owned());
+        db.create_table("student",&new_student_fields(),&constraints,BackendKind::Indexed).unwrap();
+        db.create_table("teacher",&new_other_fields(),&Constraints::new(),BackendKind::Sequential).unwrap();
+        db.create_table("course",&new_other_fields(),&Constraints::new(),BackendKind::Parallel).unwrap();
+        let student = db.find_cl("student").unwrap();
+        for (id, name) in vec!["Ada", "Bob", "Cy"].into_iter().enumerate() {
+            student.write().unwrap().insert(&new_sort_entry(id, name, 24)).unwrap();
+        }
+        student.write().unwrap().create_index("age", IndexKind::Hash).unwrap();
+        assert_eq!(db.get_collections(), vec!["course", "student", "teacher"]);
+
+        let described = db.describe("student").unwrap();
+        assert_eq!((described.backend, described.items), (BackendKind::Indexed, 3));
+        assert_eq!(described.fields.get("age"), Some(&"int".to_owned()));
+        assert_eq!(described.primary_key, Some("id".to_owned()));
+        assert_eq!(described.indexes.get("age"), Some(&"hash".to_owned()));
+        assert!(described.memory_bytes > 0 && described.disk_bytes > 0);
+        assert!(described.snapshot_reads);
+        assert!(db.describe("teacher").unwrap().indexes.is_empty());
+        assert!(!db.describe("course").unwrap().snapshot_reads);
+        assert_eq!(db.describe("staff"), Err(DbError::UnknownCollection("staff".to_owned())));
+
+        // pages run through the collections in name order, the empty ones included
+        let first = db.dump(0, 2);
+        assert_eq!((first.total, first.next), (3, Some(2)));
+        assert_eq!(first.collections.iter().map(|cl| (cl.name.as_ref(), cl.items.len())).collect::<Vec<_>>(), vec![("course", 0), ("student", 2)]);
+        let second = db.dump(2, 2);
+        assert_eq!(second.next, None);
+        assert_eq!(second.collections.iter().map(|cl| (cl.name.as_ref(), cl.items.len())).collect::<Vec<_>>(), vec![("student", 1), ("teacher", 0)]);
+        assert_eq!(second.collections[0].items[0].get("name"), Some(&Value::from("Cy")));
+
+        // an empty collection right after a full page starts the next one
+        let last = db.dump(0, 3);
+        assert_eq!(last.next, Some(3));
+        assert_eq!(db.dump(3, 3).collections.len(), 1);
+
+        // an offset and a limit past the end of the numbers, the limit is cut down
+        let far = db.dump(usize::max_value(), usize::max_value());
+        assert_eq!((far.limit, far.next, far.collections.len()), (MAX_PAGE_ITEMS, None, 0));
     }
 
     #[allow(dead_code)]
-    fn new_student_fields()->Set<String>{
-        let mut fields: Set<String> = Set::new();
-        fields.insert("id".to_owned());
-        fields.insert("name".to_owned());
-        fields.insert("age".to_owned());
+    fn new_student_fields()->Schema{
+        let mut fields: Schema = Schema::new();
+        fields.insert("id".to_owned(), FieldType::Int);
+        fields.insert("name".to_owned(), FieldType::Str);
+        fields.insert("age".to_owned(), FieldType::Int);
         fields
     }
 
     #[allow(dead_code)]
-    fn new_other_fields()->Set<String>{
-        let mut fields: Set<String> = Set::new();
-        fields.insert("id".to_owned());
-        fields.insert("gender".to_owned());
+    fn new_other_fields()->Schema{
+        let mut fields: Schema = Schema::new();
+        fields.insert("id".to_owned(), FieldType::Int);
+        fields.insert("gender".to_owned(), FieldType::Str);
         fields
     }
 
@@ -0,0 +1,160 @@
+#[doc="
+  Errors of the database.
+
+  A collection, the catalog, a transaction or the parser fails with a
+  DbError, it is passed up unchanged to the command and answered with the
+  status and the code of its kind in the response envelope (see
+  response.rs). Clients tell the kinds apart by the code, embedders by
+  matching on the variants, the message is meant for people.
+"]
+use std::fmt;
+use std::io;
+
+use response::Status;
+use tokenizer::ParseError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DbError {
+    UnknownCollection(String),
+    CollectionExists(String),
+    UnknownField(String),
+    FieldExists(String),
+    ReservedField(String),                  // _id and _version
+    ConstrainedField(String),               // the primary key or a unique field, which ALTER cannot drop
+    TypeMismatch(String, String),           // field and the value that does not fit its type
+    InvalidFilter(String),
+    InvalidQuery(String),                   // options of GET and AGGREGATE that do not fit the collection
+    DuplicateKey(String),                   // unique field
+    MissingKey(String),                     // primary key
+    MissingField(String),                   // a required field
+    NotNullable(String),                    // NULL given for a field that is not nullable
+    StaleVersion,
+    UnknownIndex(String),
+    IndexExists(String),
+    ProtectedIndex(String),                 // the index of a unique field
+    Unsupported(&'static str),              // not offered by the backend of the collection
+    Parse(ParseError),
+    Protocol(Status, String),               // an HTTP request that cannot be served
+    UnknownCommand(String),
+    Transaction(&'static str),              // BEGIN, COMMIT or ROLLBACK out of order, or a statement a transaction cannot hold
+    StatementFailed(usize, Box<DbError>),   // number of the statement of a transaction
+    TransactionConflict,                    // COMMIT would answer differently than the statements did
+    Io(String),
+    WorkerFailed,                           // a chunk of a parallel scan has no result
+    ServerBusy,
+}
+
+impl DbError {
+    pub fn status(&self) -> Status {
+        match *self {
+            DbError::UnknownCollection(_) | DbError::UnknownIndex(_) => Status::NotFound,
+            DbError::TransactionConflict | DbError::CollectionExists(_) | DbError::FieldExists(_) | DbError::ConstrainedField(_) |
+            DbError::DuplicateKey(_) | DbError::IndexExists(_) | DbError::ProtectedIndex(_) => Status::Conflict,
+            DbError::StaleVersion => Status::PreconditionFailed,
+            DbError::Protocol(status, _) => status,
+            DbError::StatementFailed(_, ref failed) => failed.status(),
+            DbError::Io(_) | DbError::WorkerFailed => Status::InternalServerError,
+            DbError::ServerBusy => Status::ServiceUnavailable,
+            _ => Status::BadRequest,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match *self {
+            DbError::UnknownCollection(_) => "unknown_collection",
+            DbError::CollectionExists(_) => "collection_exists",
+            DbError::UnknownField(_) => "unknown_field",
+            DbError::FieldExists(_) => "field_exists",
+            DbError::ReservedField(_) => "reserved_field",
+            DbError::ConstrainedField(_) => "constrained_field",
+            DbError::TypeMismatch(_, _) => "type_mismatch",
+            DbError::InvalidFilter(_) => "invalid_filter",
+            DbError::InvalidQuery(_) => "invalid_query",
+            DbError::DuplicateKey(_) => "duplicate_key",
+            DbError::MissingKey(_) => "missing_key",
+            DbError::MissingField(_) => "missing_field",
+            DbError::NotNullable(_) => "not_nullable",
+            DbError::StaleVersion => "stale_version",
+            DbError::UnknownIndex(_) => "unknown_index",
+            DbError::IndexExists(_) => "index_exists",
+            DbError::ProtectedIndex(_) => "protected_index",
+            DbError::Unsupported(_) => "unsupported",
+            DbError::Parse(_) => "parse_error",
+            DbError::Protocol(status, _) => status.error_code(),
+            DbError::UnknownCommand(_) => "unknown_command",
+            DbError::Transaction(_) => "transaction_state",
+            DbError::StatementFailed(_, ref failed) => failed.code(),
+            DbError::TransactionConflict => "transaction_conflict",
+            DbError::Io(_) => "io_error",
+            DbError::WorkerFailed => "worker_failed",
+            DbError::ServerBusy => "server_busy",
+        }
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DbError::UnknownCollection(ref name) => write!(f, "Collection {} does not exist", name),
+            DbError::CollectionExists(ref name) => write!(f, "Collection {} already exists", name),
+            DbError::UnknownField(ref field) => write!(f, "Field {} does not exist", field),
+            DbError::FieldExists(ref field) => write!(f, "Field {} already exists", field),
+            DbError::ReservedField(ref field) => write!(f, "Field {} is kept by the database", field),
+            DbError::ConstrainedField(ref field) => write!(f, "Field {} is the primary key or unique and cannot be dropped", field),
+            DbError::TypeMismatch(ref field, ref value) => write!(f, "Value {} does not match the type of field {}", value, field),
+            DbError::InvalidFilter(ref message) | DbError::InvalidQuery(ref message) |
+            DbError::Protocol(_, ref message) | DbError::Io(ref message) => write!(f, "{}", message),
+            DbError::DuplicateKey(ref field) => write!(f, "Value of unique field {} already exists", field),
+            DbError::MissingKey(ref field) => write!(f, "Primary key {} is missing", field),
+            DbError::MissingField(ref field) => write!(f, "Required field {} is missing", field),
+            DbError::NotNullable(ref field) => write!(f, "Field {} is not nullable, it cannot be cleared", field),
+            DbError::StaleVersion => write!(f, "Item was changed since it was read"),
+            DbError::UnknownIndex(ref field) => write!(f, "Index on {} does not exist", field),
+            DbError::IndexExists(ref field) => write!(f, "Index on {} already exists", field),
+            DbError::ProtectedIndex(ref field) => write!(f, "Index of unique field {} cannot be dropped", field),
+            DbError::Unsupported(message) | DbError::Transaction(message) => write!(f, "{}", message),
+            DbError::Parse(ref e) => write!(f, "{} (line {}, column {})", e.message, e.line, e.column),
+            DbError::UnknownCommand(ref command) => write!(f, "Unknown command {}", command),
+            DbError::StatementFailed(number, ref failed) => write!(f, "Statement {} failed, nothing is applied: {}", number, failed),
+            DbError::TransactionConflict => write!(f, "Collections were changed by others since the statements ran, nothing is applied"),
+            DbError::WorkerFailed => write!(f, "A worker failed on part of the items, the answer is incomplete"),
+            DbError::ServerBusy => write!(f, "Server busy, try again later"),
+        }
+    }
+}
+
+impl From<ParseError> for DbError {
+    fn from(e: ParseError) -> Self {
+        DbError::Parse(e)
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(e: io::Error) -> Self {
+        DbError::Io(e.to_string())
+    }
+}
+
+
+#[cfg(test)]
+mod error_tests {
+    use super::DbError;
+    use response::Status;
+    use tokenizer::ParseError;
+
+    #[test]
+    fn kinds_test() {
+        assert_eq!(DbError::UnknownCollection("student".to_owned()).status(), Status::NotFound);
+        assert_eq!(DbError::DuplicateKey("name".to_owned()).code(), "duplicate_key");
+        assert_eq!(DbError::StaleVersion.status(), Status::PreconditionFailed);
+
+        // a failed statement of a transaction keeps the kind of its error
+        let failed = DbError::StatementFailed(2, Box::new(DbError::DuplicateKey("name".to_owned())));
+        assert_eq!((failed.status(), failed.code()), (Status::Conflict, "duplicate_key"));
+        assert_eq!(failed.to_string(), "Statement 2 failed, nothing is applied: Value of unique field name already exists");
+
+        let parsed: DbError = ParseError::new(2, 5, "missing value after >").into();
+        assert_eq!(parsed.to_string(), "missing value after > (line 2, column 5)");
+        assert_eq!(DbError::Protocol(Status::LengthRequired, "Content-Length is missing".to_owned()).code(), "length_required");
+    }
+}
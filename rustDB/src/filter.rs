@@ -0,0 +1,508 @@
+#[doc="
+  Filters of GET, UPDATE and DELETE.
+
+  A filter line holds conditions separated by ';', all of them must hold:
+
+        name Ada                    equal, same as  name = Ada
+        name != Ada                 not equal, also <>
+        age > 21                    also <, <=, >=
+        score between 10 and 20     both ends included
+        name prefix Ad              string fields only
+        name in Ada Bob \"Mary Ann\"  any of the listed values
+        email exists                the item has a value for the field
+
+  Conditions combine with AND, OR, NOT and parentheses, AND binds tighter
+  than OR and ';' is the same as AND:
+
+        (status active OR status trial) AND NOT age < 18
+
+  Operators and the capital AND, OR, NOT are separate words, quote a value
+  that looks like one: name \"<\", or holds a parenthesis: name \"Ada (1815)\".
+  Values are converted to the field type before comparing, so age > 9
+  compares numbers on an int field. A condition on a field the item has no
+  value for does not hold, NOT of it does.
+"]
+use std::collections::HashMap;
+
+use tokenizer::{tokenize_filter, Token, TokenKind, ParseError};
+use value::{Value, FieldType, Schema, field_type};
+use vec_dbcollection::Record;
+use error::DbError;
+
+#[derive(Debug, Clone, Copy, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Between,
+    Prefix,
+    In,
+    Exists,
+}
+
+impl Operator {
+    fn from_word(word: &str) -> Option<Operator> {
+        match word.to_lowercase().as_ref() {
+            "=" | "==" => Some(Operator::Equal),
+            "!=" | "<>" => Some(Operator::NotEqual),
+            "<" => Some(Operator::Less),
+            "<=" => Some(Operator::LessEqual),
+            ">" => Some(Operator::Greater),
+            ">=" => Some(Operator::GreaterEqual),
+            "between" => Some(Operator::Between),
+            "prefix" => Some(Operator::Prefix),
+            "in" => Some(Operator::In),
+            "exists" => Some(Operator::Exists),
+            _ => None,
+        }
+    }
+}
+
+// operands are the text sent by the client until the filter is bound to a schema
+#[derive(Debug, Clone, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Condition<V> {
+    pub field: String,
+    pub operator: Operator,
+    pub operands: Vec<V>,
+}
+
+impl Condition<String> {
+    fn bind(&self, fields: &Schema) -> Result<Condition<Value>, DbError> {
+        let field_type = match field_type(fields, &self.field) {
+            Some(field_type) => field_type,
+            None => return Err(DbError::UnknownField(self.field.clone())),
+        };
+        if self.operator == Operator::Prefix && field_type != FieldType::Str {
+            return Err(DbError::InvalidFilter(format!("prefix needs a str field, {} is a {}", self.field, field_type.name())));
+        }
+        let mut operands = Vec::new();
+        for text in self.operands.iter() {
+            match field_type.coerce(text) {
+                Some(value) => operands.push(value),
+                None => return Err(DbError::TypeMismatch(self.field.clone(), text.clone())),
+            }
+        }
+        Ok(Condition {
+            field: self.field.clone(),
+            operator: self.operator,
+            operands: operands,
+        })
+    }
+}
+
+impl Condition<Value> {
+    fn matches(&self, record: &Record) -> bool {
+        let value = match record.get(&self.field) {
+            Some(value) => value,
+            None => return false,
+        };
+        let operands = &self.operands;
+        match self.operator {
+            Operator::Equal => *value == operands[0],
+            Operator::NotEqual => *value != operands[0],
+            Operator::Less => *value < operands[0],
+            Operator::LessEqual => *value <= operands[0],
+            Operator::Greater => *value > operands[0],
+            Operator::GreaterEqual => *value >= operands[0],
+            Operator::Between => *value >= operands[0] && *value <= operands[1],
+            Operator::Prefix => match (value, &operands[0]) {
+                (&Value::Str(ref s), &Value::Str(ref prefix)) => s.starts_with(prefix.as_str()),
+                _ => false,
+            },
+            Operator::In => operands.contains(value),
+            Operator::Exists => true,
+        }
+    }
+}
+
+// parsed into a tree, evaluated against each item by Collection::find, update and delete
+#[derive(Debug, Clone, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum Filter<V> {
+    Condition(Condition<V>),
+    And(Vec<Filter<V>>),        // holds when all hold, the empty And matches every item
+    Or(Vec<Filter<V>>),
+    Not(Box<Filter<V>>),
+}
+
+impl Filter<String> {
+    // matches every item
+    pub fn new() -> Self {
+        Filter::And(Vec::new())
+    }
+
+    // one line of conditions, first_line is its line number in the request
+    pub fn parse(input: &str, first_line: usize) -> Result<Self, ParseError> {
+        let tokens = try!(tokenize_filter(input, first_line));
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+        };
+        parser.skip_semicolons();
+        if parser.peek().is_none() {
+            return Ok(Filter::new());
+        }
+        let filter = try!(parser.parse_or());
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(ParseError::new(token.line, token.column, &format!("unexpected {}", word_of(token)))),
+        }
+    }
+
+    // both filters must hold
+    pub fn and(self, other: Filter<String>) -> Self {
+        let mut filters = match self {
+            Filter::And(filters) => filters,
+            filter => vec![filter],
+        };
+        match other {
+            Filter::And(others) => filters.extend(others),
+            filter => filters.push(filter),
+        }
+        Filter::And(filters)
+    }
+
+    // convert the operands to the field types, an error when a field is unknown or a value does not fit
+    pub fn bind(&self, fields: &Schema) -> Result<Filter<Value>, DbError> {
+        match *self {
+            Filter::Condition(ref condition) => condition.bind(fields).map(Filter::Condition),
+            Filter::And(ref filters) => bind_all(filters, fields).map(Filter::And),
+            Filter::Or(ref filters) => bind_all(filters, fields).map(Filter::Or),
+            Filter::Not(ref filter) => filter.bind(fields).map(|f| Filter::Not(Box::new(f))),
+        }
+    }
+}
+
+fn bind_all(filters: &Vec<Filter<String>>, fields: &Schema) -> Result<Vec<Filter<Value>>, DbError> {
+    let mut bound = Vec::new();
+    for filter in filters.iter() {
+        bound.push(try!(filter.bind(fields)));
+    }
+    Ok(bound)
+}
+
+impl Filter<Value> {
+    pub fn matches(&self, record: &Record) -> bool {
+        match *self {
+            Filter::Condition(ref condition) => condition.matches(record),
+            Filter::And(ref filters) => filters.iter().all(|filter| filter.matches(record)),
+            Filter::Or(ref filters) => filters.iter().any(|filter| filter.matches(record)),
+            Filter::Not(ref filter) => !filter.matches(record),
+        }
+    }
+}
+
+// every key equal to its value, the filter of a plain key-value template
+impl<'a> From<&'a HashMap<String, String>> for Filter<String> {
+    fn from(entry: &'a HashMap<String, String>) -> Self {
+        let mut conditions: Vec<Condition<String>> = entry.iter().map(|(field, value)| Condition {
+            field: field.clone(),
+            operator: Operator::Equal,
+            operands: vec![value.clone()],
+        }).collect();
+        conditions.sort_by(|a, b| a.field.cmp(&b.field));
+        Filter::And(conditions.into_iter().map(Filter::Condition).collect())
+    }
+}
+
+/*
+    or        := and (OR and)*
+    and       := unary ((AND | ';') unary)*
+    unary     := NOT unary | '(' or ')' | condition
+    condition := field [operator] value...
+*/
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    // AND, OR and NOT are only keywords in capitals and unquoted
+    fn at_keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(token) => !token.quoted && token.kind == TokenKind::Word(keyword.to_owned()),
+            None => false,
+        }
+    }
+
+    fn at(&self, kind: TokenKind) -> bool {
+        self.peek().map(|token| token.kind == kind).unwrap_or(false)
+    }
+
+    fn skip_semicolons(&mut self) {
+        while self.at(TokenKind::Semicolon) {
+            self.position += 1;
+        }
+    }
+
+    // the value of a condition ends before these
+    fn at_value_end(&self) -> bool {
+        match self.peek() {
+            None => true,
+            Some(token) => !is_word(token) || self.at_keyword("AND") || self.at_keyword("OR"),
+        }
+    }
+
+    // where an error at the end of the input is reported
+    fn last_position(&self) -> (usize, usize) {
+        match self.tokens.last() {
+            Some(token) => (token.line, token.column),
+            None => (1, 1),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Filter<String>, ParseError> {
+        let mut filters = vec![try!(self.parse_and())];
+        while self.at_keyword("OR") {
+            self.position += 1;
+            filters.push(try!(self.parse_and()));
+        }
+        Ok(match filters.len() {
+            1 => filters.pop().unwrap(),
+            _ => Filter::Or(filters),
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Filter<String>, ParseError> {
+        let mut filters = vec![try!(self.parse_unary())];
+        loop {
+            if self.at_keyword("AND") {
+                self.position += 1;
+            } else if self.at(TokenKind::Semicolon) {
+                // a trailing ';' closes the line or group
+                self.skip_semicolons();
+                if self.peek().is_none() || self.at(TokenKind::Close) {
+                    break;
+                }
+            } else {
+                break;
+            }
+            filters.push(try!(self.parse_unary()));
+        }
+        Ok(match filters.len() {
+            1 => filters.pop().unwrap(),
+            _ => Filter::And(filters),
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<Filter<String>, ParseError> {
+        let token = match self.peek() {
+            Some(token) => token,
+            None => {
+                let (line, column) = self.last_position();
+                return Err(ParseError::new(line, column, "expected a condition"));
+            },
+        };
+        if self.at_keyword("NOT") {
+            self.position += 1;
+            return Ok(Filter::Not(Box::new(try!(self.parse_unary()))));
+        }
+        match token.kind {
+            TokenKind::Open => {
+                self.position += 1;
+                let filter = try!(self.parse_or());
+                if !self.at(TokenKind::Close) {
+                    return Err(ParseError::new(token.line, token.column, "unclosed parenthesis"));
+                }
+                self.position += 1;
+                Ok(filter)
+            },
+            TokenKind::Word(_) => self.parse_condition().map(Filter::Condition),
+            _ => Err(ParseError::new(token.line, token.column, "expected a condition")),
+        }
+    }
+
+    // field [operator] value...
+    fn parse_condition(&mut self) -> Result<Condition<String>, ParseError> {
+        let field_token = self.peek().unwrap();
+        let field = word_of(field_token);
+        self.position += 1;
+        if self.at_value_end() {
+            return Err(ParseError::new(field_token.line, field_token.column, &format!("missing value for key {}", field)));
+        }
+
+        let first = self.peek().unwrap();
+        let operator = match first.quoted {
+            true => None,
+            false => Operator::from_word(&word_of(first)),
+        };
+        let operator = match operator {
+            Some(operator) => {
+                self.position += 1;
+                operator
+            },
+            None => {
+                return Ok(Condition {
+                    field: field,
+                    operator: Operator::Equal,
+                    operands: vec![self.words().join(" ")],
+                });
+            },
+        };
+
+        let operands = match operator {
+            Operator::Exists => {
+                if !self.at_value_end() {
+                    let value = self.peek().unwrap();
+                    return Err(ParseError::new(value.line, value.column, "exists takes no value"));
+                }
+                Vec::new()
+            },
+            Operator::Between => {
+                // "and" here belongs to the range, whatever its case
+                let range: Vec<&Token> = self.tokens[self.position..].iter().take(3).collect();
+                let words: Vec<String> = range.iter().map(|t| word_of(t)).collect();
+                if range.len() != 3 || !range.iter().all(|t| is_word(t)) || words[1].to_lowercase() != "and" {
+                    return Err(ParseError::new(first.line, first.column, "expected between <low> and <high>"));
+                }
+                self.position += 3;
+                vec![words[0].clone(), words[2].clone()]
+            },
+            Operator::In => {
+                let words = self.words();
+                if words.is_empty() {
+                    return Err(ParseError::new(first.line, first.column, "missing values after in"));
+                }
+                words
+            },
+            _ => {
+                let words = self.words();
+                if words.is_empty() {
+                    return Err(ParseError::new(first.line, first.column, &format!("missing value after {}", word_of(first))));
+                }
+                vec![words.join(" ")]
+            },
+        };
+        Ok(Condition {
+            field: field,
+            operator: operator,
+            operands: operands,
+        })
+    }
+
+    // the words up to the end of the condition
+    fn words(&mut self) -> Vec<String> {
+        let mut words = Vec::new();
+        while !self.at_value_end() {
+            words.push(word_of(self.peek().unwrap()));
+            self.position += 1;
+        }
+        words
+    }
+}
+
+fn is_word(token: &Token) -> bool {
+    match token.kind {
+        TokenKind::Word(_) => true,
+        _ => false,
+    }
+}
+
+fn word_of(token: &Token) -> String {
+    match token.kind {
+        TokenKind::Word(ref word) => word.clone(),
+        TokenKind::Semicolon => ";".to_string(),
+        TokenKind::Open => "(".to_string(),
+        TokenKind::Close => ")".to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod filter_tests {
+    use super::{Filter, Condition, Operator};
+    use tokenizer::ParseError;
+    use value::{Value, FieldType, Schema};
+    use vec_dbcollection::Record;
+    use error::DbError;
+
+    #[test]
+    fn parse_test() {
+        let filter = Filter::parse("name Ada Lovelace; age >= 21; score between 1 and 5; tag in a \"b c\"; email exists", 2).unwrap();
+        assert_eq!(filter, Filter::And(vec![
+            condition("name", Operator::Equal, vec!["Ada Lovelace"]),
+            condition("age", Operator::GreaterEqual, vec!["21"]),
+            condition("score", Operator::Between, vec!["1", "5"]),
+            condition("tag", Operator::In, vec!["a", "b c"]),
+            condition("email", Operator::Exists, vec![])]));
+
+        // a quoted operator is a value
+        let filter = Filter::parse("name \"<\";", 2).unwrap();
+        assert_eq!(filter, condition("name", Operator::Equal, vec!["<"]));
+        assert_eq!(Filter::parse("", 2).unwrap(), Filter::new());
+    }
+
+    #[test]
+    fn boolean_parse_test() {
+        let filter = Filter::parse("(status active OR status trial) AND NOT age between 1 AND 17", 2).unwrap();
+        assert_eq!(filter, Filter::And(vec![
+            Filter::Or(vec![condition("status", Operator::Equal, vec!["active"]),
+                            condition("status", Operator::Equal, vec!["trial"])]),
+            Filter::Not(Box::new(condition("age", Operator::Between, vec!["1", "17"])))]));
+
+        // AND binds tighter than OR, lower case and quoted words are values
+        let filter = Filter::parse("a 1 OR b 2 AND c rock and roll; d \"OR\"", 2).unwrap();
+        assert_eq!(filter, Filter::Or(vec![
+            condition("a", Operator::Equal, vec!["1"]),
+            Filter::And(vec![condition("b", Operator::Equal, vec!["2"]),
+                             condition("c", Operator::Equal, vec!["rock and roll"]),
+                             condition("d", Operator::Equal, vec!["OR"])])]));
+    }
+
+    #[test]
+    fn malformed_filter_test() {
+        assert_eq!(Filter::parse("age >", 2), Err(ParseError::new(2, 5, "missing value after >")));
+        assert_eq!(Filter::parse("age between 1 5", 3), Err(ParseError::new(3, 5, "expected between <low> and <high>")));
+        assert_eq!(Filter::parse("age exists 1", 2), Err(ParseError::new(2, 12, "exists takes no value")));
+        assert_eq!(Filter::parse("age", 2), Err(ParseError::new(2, 1, "missing value for key age")));
+        assert_eq!(Filter::parse("(age 1 OR age 2", 2), Err(ParseError::new(2, 1, "unclosed parenthesis")));
+        assert_eq!(Filter::parse("age 1)", 2), Err(ParseError::new(2, 6, "unexpected )")));
+        assert_eq!(Filter::parse("name Ada (", 2), Err(ParseError::new(2, 10, "unexpected (")));
+        assert_eq!(Filter::parse("age 1 OR", 2), Err(ParseError::new(2, 7, "expected a condition")));
+        assert_eq!(Filter::parse("NOT ()", 2), Err(ParseError::new(2, 6, "expected a condition")));
+    }
+
+    #[test]
+    fn typed_match_test() {
+        let mut fields = Schema::new();
+        fields.insert("name".to_owned(), FieldType::Str);
+        fields.insert("age".to_owned(), FieldType::Int);
+        fields.insert("email".to_owned(), FieldType::Str);
+
+        let mut ada = Record::new();
+        ada.insert("name".to_owned(), Value::from("Ada"));
+        ada.insert("age".to_owned(), Value::Int(9));
+
+        let holds = |line: &str| Filter::parse(line, 2).unwrap().bind(&fields).unwrap().matches(&ada);
+        assert!(holds("age < 10"));           // compared as numbers, "9" > "10" as text
+        assert!(holds("age between 9 and 10"));
+        assert!(holds("name prefix Ad; name != Bob"));
+        assert!(holds("name in Bob Ada"));
+        assert!(!holds("email exists"));
+        assert!(!holds("email != x"));
+        assert!(!holds("age > 9"));
+
+        assert!(holds("NOT email exists"));
+        assert!(holds("name Bob OR age 9"));
+        assert!(!holds("name Bob OR (age 9 AND NOT name prefix A)"));
+        assert!(holds("NOT (name Bob OR age > 9)"));
+
+        assert_eq!(Filter::parse("age > ten", 2).unwrap().bind(&fields), Err(DbError::TypeMismatch("age".to_owned(), "ten".to_owned())));
+        assert!(Filter::parse("age prefix 1", 2).unwrap().bind(&fields).is_err());
+        assert_eq!(Filter::parse("name Ada OR height > 1", 2).unwrap().bind(&fields), Err(DbError::UnknownField("height".to_owned())));
+    }
+
+    fn condition(field: &str, operator: Operator, operands: Vec<&str>) -> Filter<String> {
+        Filter::Condition(Condition {
+            field: field.to_owned(),
+            operator: operator,
+            operands: operands.into_iter().map(|s| s.to_owned()).collect(),
+        })
+    }
+}
@@ -0,0 +1,459 @@
+#[doc="
+  HTTP/1.1 front end, mapped onto the same commands as the line protocol:
+
+        PUT     /collections/{name}                 body: [\"field\", ...]     PUTLIST
+                                                    or {\"field\": \"type\"}
+        POST    /collections/{name}                 body: {\"field\": value}   APPEND
+        GET     /collections/{name}?field=value                              GET
+        PATCH   /collections/{name}?field=value     body: {\"field\": value}   UPDATE
+        DELETE  /collections/{name}?field=value                              DELETE
+
+  PUT with ?backend=sequential, parallel or indexed picks the backend of the
+  collection, as the BACKEND line of PUTLIST does.
+
+  GET, PATCH and DELETE of /collections/{name}/{id} address the item with
+  that _id, the id POST answers with. An If-Match header with the _version
+  of the item makes PATCH and DELETE conditional, as IF VERSION does.
+
+  Bodies are JSON and must come with a Content-Length header, a body above
+  MAX_PARAMETER_BYTES is answered with 413 and not read. Query pairs
+  are equality conditions, filter=<conditions> takes the line protocol
+  syntax (see filter.rs), e.g. ?filter=age%20%3E%2021. The pairs fields,
+  sort, limit and offset are the options of GET (see query.rs), e.g.
+  ?fields=name,age&sort=age%20desc,name&limit=10&offset=20. Responses carry
+  the status of the command and the JSON envelope of response.rs.
+"]
+use std::io::{BufRead, Read};
+use std::collections::HashMap;
+use rustc_serialize::json::Json;
+
+use response::Status;
+use value::{Schema, Constraints, declare_field, ID_FIELD, NULL_TEXT};
+use filter::Filter;
+use query::Query;
+use tokenizer::ParseError;
+use backend::BackendKind;
+use error::DbError;
+use request::MAX_PARAMETER_BYTES;
+
+// query pairs that are options of GET rather than conditions
+const OPTIONS: [&'static str; 4] = ["fields", "sort", "limit", "offset"];
+
+pub struct HttpRequest {
+    pub command: &'static str,
+    pub collection: String,
+    pub query: HashMap<String, String>,
+    pub entry: HashMap<String, String>,       // JSON object body of POST and PATCH
+    pub fields: Schema,                       // JSON array or object body of PUT
+    pub constraints: Constraints,
+    pub backend: BackendKind,                 // backend query pair of PUT
+    pub if_version: Option<u64>,              // If-Match header of PATCH and DELETE
+    pub keep_alive: bool,
+}
+
+impl HttpRequest {
+    // equality of the query pairs and the conditions of the filter pair
+    pub fn query_filter(&self) -> Result<Filter<String>, ParseError> {
+        let mut pairs = self.query.clone();
+        let conditions = match pairs.remove("filter") {
+            Some(line) => try!(Filter::parse(&line, 1)),
+            None => Filter::new(),
+        };
+        for option in OPTIONS.iter() {
+            pairs.remove(*option);
+        }
+        Ok(Filter::from(&pairs).and(conditions))
+    }
+
+    // fields, sort, limit and offset of the query, lists are separated by commas or spaces
+    pub fn query_options(&self) -> Result<Query, ParseError> {
+        let mut query = Query::new();
+        for option in OPTIONS.iter() {
+            if let Some(value) = self.query.get(*option) {
+                let words: Vec<String> = value.split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|word| !word.is_empty()).map(|word| word.to_owned()).collect();
+                if let Err(message) = query.set(&option.to_uppercase(), &words) {
+                    return Err(ParseError::new(1, 1, &message));
+                }
+            }
+        }
+        Ok(query)
+    }
+}
+
+// "GET /collections/student HTTP/1.1"
+pub fn is_http_request_line(line: &str) -> bool {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    parts.len() == 3 && parts[2].starts_with("HTTP/")
+}
+
+// read headers and body following the request line, the raw text is appended to log
+pub fn read_http_request<R: BufRead>(request_line: &str, reader: &mut R, log: &mut String) -> Result<HttpRequest, DbError> {
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    let method = parts[0];
+    let version = parts[2];
+
+    let mut headers = HashMap::<String, String>::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return Err(DbError::Protocol(Status::BadRequest, "Incomplete HTTP header".to_owned())),
+            Ok(_) => (),
+        }
+        if line.trim().is_empty() {
+            break;
+        }
+        log.push_str(&line);
+        match line.find(':') {
+            Some(pos) => {
+                headers.insert(line[..pos].trim().to_lowercase(), line[pos + 1..].trim().to_owned());
+            },
+            None => return Err(DbError::Protocol(Status::BadRequest, "Malformed HTTP header".to_owned())),
+        }
+    }
+
+    let keep_alive = match headers.get("connection").map(|v| v.to_lowercase()) {
+        Some(ref v) if v == "close" => false,
+        Some(ref v) if v == "keep-alive" => true,
+        _ => version == "HTTP/1.1",
+    };
+
+    // If-Match: 3, quoted like an entity tag or not
+    let if_version = match headers.get("if-match") {
+        Some(tag) => match tag.trim_matches('"').parse::<u64>() {
+            Ok(version) => Some(version),
+            Err(_) => return Err(DbError::Protocol(Status::BadRequest, "If-Match expects the _version of the item".to_owned())),
+        },
+        None => None,
+    };
+
+    if headers.contains_key("transfer-encoding") {
+        return Err(DbError::Protocol(Status::LengthRequired, "Chunked body is not supported, send Content-Length".to_owned()));
+    }
+    let body = match headers.get("content-length") {
+        Some(length) => {
+            let length = match length.parse::<usize>() {
+                Ok(length) => length,
+                Err(_) => return Err(DbError::Protocol(Status::BadRequest, "Invalid Content-Length".to_owned())),
+            };
+            // the length is the client's word, nothing is allocated for it beyond the cap
+            if length > MAX_PARAMETER_BYTES {
+                return Err(DbError::Protocol(Status::PayloadTooLarge, format!("HTTP body is larger than {} bytes", MAX_PARAMETER_BYTES)));
+            }
+            let mut buf = Vec::new();
+            match reader.take(length as u64).read_to_end(&mut buf) {
+                Ok(read) if read == length => (),
+                _ => return Err(DbError::Protocol(Status::BadRequest, "Incomplete HTTP body".to_owned())),
+            }
+            match String::from_utf8(buf) {
+                Ok(body) => body,
+                Err(_) => return Err(DbError::Protocol(Status::BadRequest, "HTTP body is not UTF-8".to_owned())),
+            }
+        },
+        None => String::new(),
+    };
+    log.push_str(&body);
+
+    let (path, mut query) = match parts[1].find('?') {
+        Some(pos) => (&parts[1][..pos], parse_query(&parts[1][pos + 1..])),
+        None => (parts[1], HashMap::new()),
+    };
+    let collection = match collection_of(path) {
+        Some((name, id)) => {
+            if let Some(id) = id {
+                query.insert(ID_FIELD.to_owned(), id);
+            }
+            name
+        },
+        None => return Err(DbError::Protocol(Status::NotFound, format!("No resource at {}", path))),
+    };
+
+    let mut request = HttpRequest {
+        command: "",
+        collection: collection,
+        query: query,
+        entry: HashMap::new(),
+        fields: Schema::new(),
+        constraints: Constraints::new(),
+        backend: BackendKind::Indexed,
+        if_version: if_version,
+        keep_alive: keep_alive,
+    };
+    match method {
+        "PUT" => {
+            request.command = "PUTLIST";
+            let (fields, constraints) = try!(json_to_fields(&body));
+            request.fields = fields;
+            request.constraints = constraints;
+            if let Some(name) = request.query.remove("backend") {
+                request.backend = match BackendKind::from_name(&name) {
+                    Some(kind) => kind,
+                    None => return Err(DbError::Protocol(Status::BadRequest, "expected backend=sequential, parallel or indexed".to_owned())),
+                };
+            }
+        },
+        "POST" => {
+            request.command = "APPEND";
+            request.entry = try!(json_to_entry(&body));
+        },
+        "GET" => request.command = "GET",
+        "PATCH" => {
+            request.command = "UPDATE";
+            request.entry = try!(json_to_entry(&body));
+        },
+        "DELETE" => request.command = "DELETE",
+        _ => return Err(DbError::Protocol(Status::MethodNotAllowed, format!("Method {} is not supported", method))),
+    }
+    Ok(request)
+}
+
+// "/collections/{name}" -> name, "/collections/{name}/{id}" -> name and id
+fn collection_of(path: &str) -> Option<(String, Option<String>)> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match segments.len() {
+        2 if segments[0] == "collections" => Some((url_decode(segments[1]), None)),
+        3 if segments[0] == "collections" => Some((url_decode(segments[1]), Some(url_decode(segments[2])))),
+        _ => None,
+    }
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut pairs = HashMap::new();
+    for pair in query.split('&').filter(|s| !s.is_empty()) {
+        match pair.find('=') {
+            Some(pos) => pairs.insert(url_decode(&pair[..pos]), url_decode(&pair[pos + 1..])),
+            None => pairs.insert(url_decode(pair), String::new()),
+        };
+    }
+    pairs
+}
+
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push(high * 16 + low);
+                        i += 2;
+                    },
+                    _ => decoded.push(b'%'),
+                }
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn json_to_entry(body: &str) -> Result<HashMap<String, String>, DbError> {
+    match Json::from_str(body) {
+        Ok(Json::Object(object)) => {
+            let mut entry = HashMap::new();
+            for (key, value) in object.into_iter() {
+                let value = match value {
+                    Json::String(ref s) if s.contains('\u{0}') => return Err(DbError::Protocol(Status::BadRequest, format!("Field {} may not hold a NUL character", key))),
+                    Json::String(s) => s,
+                    Json::I64(n) => n.to_string(),
+                    Json::U64(n) => n.to_string(),
+                    Json::F64(n) => n.to_string(),
+                    Json::Boolean(b) => b.to_string(),
+                    Json::Null => NULL_TEXT.to_owned(),
+                    _ => return Err(DbError::Protocol(Status::BadRequest, format!("Field {} must be a string, number, boolean or null", key))),
+                };
+                entry.insert(key, value);
+            }
+            Ok(entry)
+        },
+        _ => Err(DbError::Protocol(Status::BadRequest, "Body must be a JSON object".to_owned())),
+    }
+}
+
+// ["name", ...] declares string fields, {"name": "type", ...} typed ones
+// a type may be followed by "primary key" or "unique": {"id": "int primary key"}
+fn json_to_fields(body: &str) -> Result<(Schema, Constraints), DbError> {
+    match Json::from_str(body) {
+        Ok(Json::Array(list)) => {
+            let mut fields = Schema::new();
+            let mut constraints = Constraints::new();
+            for field in list.into_iter() {
+                let name = match field {
+                    Json::String(s) => s,
+                    _ => return Err(DbError::Protocol(Status::BadRequest, "Field names must be strings".to_owned())),
+                };
+                if let Err((_, message)) = declare_field(&[name], &mut fields, &mut constraints) {
+                    return Err(DbError::Protocol(Status::BadRequest, message));
+                }
+            }
+            Ok((fields, constraints))
+        },
+        Ok(Json::Object(object)) => {
+            let mut fields = Schema::new();
+            let mut constraints = Constraints::new();
+            for (name, declaration) in object.into_iter() {
+                let mut words = vec![name.clone()];
+                match declaration.as_string() {
+                    Some(declaration) => words.extend(declaration.split_whitespace().map(|word| word.to_owned())),
+                    None => return Err(DbError::Protocol(Status::BadRequest, format!("Unknown type of field {}", name))),
+                };
+                if let Err((_, message)) = declare_field(&words, &mut fields, &mut constraints) {
+                    return Err(DbError::Protocol(Status::BadRequest, format!("Field {}: {}", name, message)));
+                }
+            }
+            Ok((fields, constraints))
+        },
+        _ => Err(DbError::Protocol(Status::BadRequest, "Body must be a JSON array of field names or an object of field types".to_owned())),
+    }
+}
+
+
+#[cfg(test)]
+mod http_tests {
+    use super::{is_http_request_line, read_http_request, url_decode};
+    use value::NULL_TEXT;
+    use response::Status;
+    use value::FieldType;
+    use filter::Filter;
+    use query::Order;
+    use backend::BackendKind;
+    use std::io::Cursor;
+
+    #[test]
+    fn request_line_test() {
+        assert!(is_http_request_line("GET /collections/student HTTP/1.1\r\n"));
+        assert!(!is_http_request_line("GET student\r\n"));
+    }
+
+    #[test]
+    fn post_request_test() {
+        let body = "{\"id\": 1, \"name\": \"Ada Lovelace\"}";
+        let raw = format!("Host: localhost\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let mut reader = Cursor::new(raw.into_bytes());
+        let mut log = String::new();
+        let request = read_http_request("POST /collections/student HTTP/1.1", &mut reader, &mut log).ok().unwrap();
+        assert_eq!(request.command, "APPEND");
+        assert_eq!(request.collection, "student");
+        assert_eq!(request.entry.get("id"), Some(&"1".to_owned()));
+        assert_eq!(request.entry.get("name"), Some(&"Ada Lovelace".to_owned()));
+        assert!(request.keep_alive);
+
+        // null clears a nullable field
+        let body = "{\"id\": 1, \"note\": null}";
+        let raw = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let request = read_http_request("PATCH /collections/student/0 HTTP/1.1", &mut Cursor::new(raw.into_bytes()), &mut log).ok().unwrap();
+        assert_eq!(request.entry.get("note"), Some(&NULL_TEXT.to_owned()));
+    }
+
+    #[test]
+    fn get_request_test() {
+        let mut reader = Cursor::new(b"Connection: close\r\n\r\n".to_vec());
+        let mut log = String::new();
+        let request = read_http_request("GET /collections/student?name=Ada%20Lovelace&age=24 HTTP/1.1", &mut reader, &mut log).ok().unwrap();
+        assert_eq!(request.command, "GET");
+        assert_eq!(request.query.get("name"), Some(&"Ada Lovelace".to_owned()));
+        assert_eq!(request.query.get("age"), Some(&"24".to_owned()));
+        assert!(!request.keep_alive);
+
+        let mut reader = Cursor::new(b"\r\n".to_vec());
+        let request = read_http_request("DELETE /collections/student/3 HTTP/1.1", &mut reader, &mut log).ok().unwrap();
+        assert_eq!(request.command, "DELETE");
+        assert_eq!(request.collection, "student");
+        assert_eq!(request.query.get("_id"), Some(&"3".to_owned()));
+    }
+
+    #[test]
+    fn if_match_test() {
+        let mut reader = Cursor::new(b"If-Match: \"4\"\r\n\r\n".to_vec());
+        let mut log = String::new();
+        let request = read_http_request("DELETE /collections/student/3 HTTP/1.1", &mut reader, &mut log).ok().unwrap();
+        assert_eq!(request.if_version, Some(4));
+
+        let mut reader = Cursor::new(b"If-Match: *\r\n\r\n".to_vec());
+        assert_eq!(read_http_request("DELETE /collections/student/3 HTTP/1.1", &mut reader, &mut log).err().map(|e| e.status()),
+                   Some(Status::BadRequest));
+    }
+
+    #[test]
+    fn query_filter_test() {
+        let mut reader = Cursor::new(b"\r\n".to_vec());
+        let mut log = String::new();
+        let request = read_http_request("GET /collections/student?name=Ada&filter=age+%3E%3D+21 HTTP/1.1", &mut reader, &mut log).ok().unwrap();
+        let expected = Filter::parse("name Ada; age >= 21", 1).unwrap();
+        assert_eq!(request.query_filter(), Ok(expected));
+
+        let mut reader = Cursor::new(b"\r\n".to_vec());
+        let request = read_http_request("GET /collections/student?name=Ada&fields=name,age&sort=age%20desc&limit=5 HTTP/1.1", &mut reader, &mut log).ok().unwrap();
+        assert_eq!(request.query_filter(), Ok(Filter::parse("name Ada", 1).unwrap().and(Filter::new())));
+        let query = request.query_options().unwrap();
+        assert_eq!(query.fields, Some(vec!["name".to_owned(), "age".to_owned()]));
+        assert_eq!(query.sort, vec![("age".to_owned(), Order::Descending)]);
+        assert_eq!((query.limit, query.offset), (Some(5), 0));
+    }
+
+    #[test]
+    fn bad_request_test() {
+        let mut log = String::new();
+        let mut reader = Cursor::new(b"\r\n".to_vec());
+        let err = read_http_request("GET /tables/student HTTP/1.1", &mut reader, &mut log).err().unwrap();
+        assert_eq!(err.status(), Status::NotFound);
+
+        let mut reader = Cursor::new(b"\r\n".to_vec());
+        let err = read_http_request("HEAD /collections/student HTTP/1.1", &mut reader, &mut log).err().unwrap();
+        assert_eq!(err.status(), Status::MethodNotAllowed);
+
+        let mut reader = Cursor::new(b"Content-Length: 5\r\n\r\n[1,2]".to_vec());
+        let err = read_http_request("POST /collections/student HTTP/1.1", &mut reader, &mut log).err().unwrap();
+        assert_eq!(err.status(), Status::BadRequest);
+
+        // a huge length is refused before anything is allocated or read
+        let mut reader = Cursor::new(b"Content-Length: 1000000000000\r\n\r\n{}".to_vec());
+        let err = read_http_request("POST /collections/student HTTP/1.1", &mut reader, &mut log).err().unwrap();
+        assert_eq!((err.status(), err.code()), (Status::PayloadTooLarge, "payload_too_large"));
+
+        let mut reader = Cursor::new(b"Content-Length: 9\r\n\r\n{}".to_vec());
+        let err = read_http_request("POST /collections/student HTTP/1.1", &mut reader, &mut log).err().unwrap();
+        assert_eq!(err.to_string(), "Incomplete HTTP body");
+    }
+
+    #[test]
+    fn put_request_test() {
+        let body = "{\"name\": \"string unique\", \"age\": \"int\", \"id\": \"int primary key\"}";
+        let raw = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let mut reader = Cursor::new(raw.into_bytes());
+        let mut log = String::new();
+        let request = read_http_request("PUT /collections/student HTTP/1.1", &mut reader, &mut log).ok().unwrap();
+        assert_eq!(request.command, "PUTLIST");
+        assert_eq!(request.fields.get("age"), Some(&FieldType::Int));
+        assert_eq!(request.fields.get("name"), Some(&FieldType::Str));
+        assert_eq!(request.constraints.primary_key, Some("id".to_owned()));
+        assert!(request.constraints.unique.contains("name"));
+        assert_eq!(request.backend, BackendKind::Indexed);
+
+        let raw = "Content-Length: 8\r\n\r\n[\"name\"]";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let request = read_http_request("PUT /collections/student?backend=sequential HTTP/1.1", &mut reader, &mut log).ok().unwrap();
+        assert_eq!(request.backend, BackendKind::Sequential);
+        assert!(request.query.is_empty());
+    }
+
+    #[test]
+    fn url_decode_test() {
+        assert_eq!(url_decode("Ada+Lovelace"), "Ada Lovelace");
+        assert_eq!(url_decode("a%2Fb%"), "a/b%");
+    }
+}
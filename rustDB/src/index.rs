@@ -0,0 +1,289 @@
+#[doc="
+  Secondary indexes of a collection.
+
+  A hash index answers equality and in-list conditions, a btree index also
+  ranges, prefixes and exists. An index maps a field value to the rows
+  holding it. Only the definitions (field and kind) are written into a
+  snapshot, the entries are rebuilt when the collection is loaded.
+"]
+use std::collections::{HashMap, BTreeMap, BTreeSet};
+use std::ops::Bound::{Included, Excluded, Unbounded};
+use rustc_serialize::{Encodable, Encoder, Decodable, Decoder};
+
+use value::{Value, ID_FIELD};
+use filter::{Filter, Condition, Operator};
+use vec_dbcollection::Record;
+
+pub type Rows = BTreeSet<u64>;
+pub type Indexes = BTreeMap<String, Index>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexKind {
+    Hash,
+    BTree,
+}
+
+impl IndexKind {
+    pub fn from_name(name: &str) -> Option<IndexKind> {
+        match name.to_lowercase().as_ref() {
+            "hash" => Some(IndexKind::Hash),
+            "btree" | "b-tree" => Some(IndexKind::BTree),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            IndexKind::Hash => "hash",
+            IndexKind::BTree => "btree",
+        }
+    }
+}
+
+impl Encodable for IndexKind {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(self.name())
+    }
+}
+
+impl Decodable for IndexKind {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        let name = try!(d.read_str());
+        match IndexKind::from_name(&name) {
+            Some(kind) => Ok(kind),
+            None => Err(d.error(&format!("unknown index kind {}", name))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Index {
+    Hash(HashMap<Value, Rows>),
+    BTree(BTreeMap<Value, Rows>),
+}
+
+impl Index {
+    pub fn new(kind: IndexKind) -> Self {
+        match kind {
+            IndexKind::Hash => Index::Hash(HashMap::new()),
+            IndexKind::BTree => Index::BTree(BTreeMap::new()),
+        }
+    }
+
+    pub fn kind(&self) -> IndexKind {
+        match *self {
+            Index::Hash(_) => IndexKind::Hash,
+            Index::BTree(_) => IndexKind::BTree,
+        }
+    }
+
+    pub fn insert(&mut self, value: &Value, row: u64) {
+        match *self {
+            Index::Hash(ref mut map) => map.entry(value.clone()).or_insert_with(Rows::new).insert(row),
+            Index::BTree(ref mut map) => map.entry(value.clone()).or_insert_with(Rows::new).insert(row),
+        };
+    }
+
+    pub fn remove(&mut self, value: &Value, row: u64) {
+        let emptied = match *self {
+            Index::Hash(ref mut map) => map.get_mut(value).map(|rows| { rows.remove(&row); rows.is_empty() }),
+            Index::BTree(ref mut map) => map.get_mut(value).map(|rows| { rows.remove(&row); rows.is_empty() }),
+        };
+        if emptied == Some(true) {
+            match *self {
+                Index::Hash(ref mut map) => map.remove(value),
+                Index::BTree(ref mut map) => map.remove(value),
+            };
+        }
+    }
+
+    pub fn rows_of(&self, value: &Value) -> Rows {
+        let rows = match *self {
+            Index::Hash(ref map) => map.get(value),
+            Index::BTree(ref map) => map.get(value),
+        };
+        rows.cloned().unwrap_or_else(Rows::new)
+    }
+
+    // rows whose value satisfies the condition, None when this index cannot answer it
+    pub fn lookup(&self, condition: &Condition<Value>) -> Option<Rows> {
+        let operands = &condition.operands;
+        match condition.operator {
+            Operator::Equal => return Some(self.rows_of(&operands[0])),
+            Operator::In => return Some(operands.iter().flat_map(|value| self.rows_of(value)).collect()),
+            _ => (),
+        }
+        let map = match *self {
+            Index::BTree(ref map) => map,
+            Index::Hash(_) => return None,
+        };
+        match condition.operator {
+            Operator::Less => Some(collect(map.range((Unbounded, Excluded(&operands[0]))))),
+            Operator::LessEqual => Some(collect(map.range((Unbounded, Included(&operands[0]))))),
+            Operator::Greater => Some(collect(map.range((Excluded(&operands[0]), Unbounded)))),
+            Operator::GreaterEqual => Some(collect(map.range((Included(&operands[0]), Unbounded)))),
+            Operator::Between => {
+                if operands[0] > operands[1] {
+                    return Some(Rows::new());
+                }
+                Some(collect(map.range((Included(&operands[0]), Included(&operands[1])))))
+            },
+            Operator::Prefix => {
+                let prefix = match operands[0] {
+                    Value::Str(ref prefix) => prefix,
+                    _ => return None,
+                };
+                Some(collect(map.range((Included(&operands[0]), Unbounded)).take_while(|&(value, _)| match *value {
+                    Value::Str(ref s) => s.starts_with(prefix.as_str()),
+                    _ => false,
+                })))
+            },
+            Operator::Exists => Some(collect(map.iter())),
+            _ => None,
+        }
+    }
+}
+
+// stored as its kind only, the entries are rebuilt on load
+impl Encodable for Index {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.kind().encode(s)
+    }
+}
+
+fn collect<'a, I: Iterator<Item=(&'a Value, &'a Rows)>>(iter: I) -> Rows {
+    let mut rows = Rows::new();
+    for (_, found) in iter {
+        rows.extend(found.iter().cloned());
+    }
+    rows
+}
+
+pub fn add_record(indexes: &mut Indexes, row: u64, record: &Record) {
+    for (field, index) in indexes.iter_mut() {
+        if let Some(value) = record.get(field) {
+            index.insert(value, row);
+        }
+    }
+}
+
+pub fn remove_record(indexes: &mut Indexes, row: u64, record: &Record) {
+    for (field, index) in indexes.iter_mut() {
+        if let Some(value) = record.get(field) {
+            index.remove(value, row);
+        }
+    }
+}
+
+// the id is the row number, an equality or in-list on it names the rows directly
+fn id_rows(condition: &Condition<Value>) -> Option<Rows> {
+    match condition.operator {
+        Operator::Equal | Operator::In => Some(condition.operands.iter().filter_map(|id| match *id {
+            Value::Int(id) if id >= 0 => Some(id as u64),
+            _ => None,
+        }).collect()),
+        _ => None,
+    }
+}
+
+// rows that may match the filter, None when no index narrows it down
+pub fn candidates(indexes: &Indexes, filter: &Filter<Value>) -> Option<Rows> {
+    match *filter {
+        Filter::Condition(ref condition) if condition.field == ID_FIELD => id_rows(condition),
+        Filter::Condition(ref condition) => indexes.get(&condition.field).and_then(|index| index.lookup(condition)),
+        Filter::And(ref filters) => {
+            let mut narrowed: Option<Rows> = None;
+            for filter in filters.iter() {
+                if let Some(rows) = candidates(indexes, filter) {
+                    narrowed = Some(match narrowed {
+                        None => rows,
+                        Some(previous) => previous.intersection(&rows).cloned().collect(),
+                    });
+                }
+            }
+            narrowed
+        },
+        Filter::Or(ref filters) => {
+            let mut rows = Rows::new();
+            for filter in filters.iter() {
+                match candidates(indexes, filter) {
+                    Some(found) => rows.extend(found),
+                    None => return None,
+                }
+            }
+            Some(rows)
+        },
+        Filter::Not(_) => None,
+    }
+}
+
+
+#[cfg(test)]
+mod index_tests {
+    use super::{Index, IndexKind, Indexes, Rows, add_record, remove_record, candidates};
+    use value::{Value, FieldType, Schema};
+    use filter::Filter;
+    use vec_dbcollection::Record;
+
+    #[test]
+    fn lookup_test() {
+        let mut indexes = Indexes::new();
+        indexes.insert("age".to_owned(), Index::new(IndexKind::BTree));
+        indexes.insert("name".to_owned(), Index::new(IndexKind::Hash));
+        for (row, &(name, age)) in [("Ada", 9), ("Bob", 30), ("Amy", 30)].iter().enumerate() {
+            add_record(&mut indexes, row as u64, &record(name, age));
+        }
+
+        let rows = |line: &str| candidates(&indexes, &bind(line));
+        assert_eq!(rows("age 30"), Some(set(&[1, 2])));
+        assert_eq!(rows("age > 9"), Some(set(&[1, 2])));
+        assert_eq!(rows("age between 1 and 10"), Some(set(&[0])));
+        assert_eq!(rows("age between 10 and 1"), Some(set(&[])));
+        assert_eq!(rows("name in Ada Amy AND age 30"), Some(set(&[2])));
+        assert_eq!(rows("name Bob OR age < 10"), Some(set(&[0, 1])));
+        // a hash index does not answer ranges, NOT is always scanned
+        assert_eq!(rows("name > A"), None);
+        assert_eq!(rows("NOT age 30"), None);
+        assert_eq!(rows("name Bob OR name > A"), None);
+        assert_eq!(rows("name prefix A AND age >= 30"), Some(set(&[1, 2])));
+        // the id is the row itself, no index needed
+        assert_eq!(rows("_id 1"), Some(set(&[1])));
+        assert_eq!(rows("_id in 0 2 -1 AND age 30"), Some(set(&[2])));
+        assert_eq!(rows("_id > 0"), None);
+
+        remove_record(&mut indexes, 2, &record("Amy", 30));
+        assert_eq!(candidates(&indexes, &bind("age 30")), Some(set(&[1])));
+    }
+
+    #[test]
+    fn prefix_test() {
+        let mut index = Index::new(IndexKind::BTree);
+        for (row, name) in ["Ada", "Adam", "Bob", "Ab"].iter().enumerate() {
+            index.insert(&Value::from(*name), row as u64);
+        }
+        let mut fields = Schema::new();
+        fields.insert("name".to_owned(), FieldType::Str);
+        let mut indexes = Indexes::new();
+        indexes.insert("name".to_owned(), index);
+        let filter = Filter::parse("name prefix Ad", 1).unwrap().bind(&fields).unwrap();
+        assert_eq!(candidates(&indexes, &filter), Some(set(&[0, 1])));
+    }
+
+    fn bind(line: &str) -> Filter<Value> {
+        let mut fields = Schema::new();
+        fields.insert("name".to_owned(), FieldType::Str);
+        fields.insert("age".to_owned(), FieldType::Int);
+        Filter::parse(line, 1).unwrap().bind(&fields).unwrap()
+    }
+
+    fn record(name: &str, age: i64) -> Record {
+        let mut record = Record::new();
+        record.insert("name".to_owned(), Value::from(name));
+        record.insert("age".to_owned(), Value::Int(age));
+        record
+    }
+
+    fn set(rows: &[u64]) -> Rows {
+        rows.iter().cloned().collect()
+    }
+}
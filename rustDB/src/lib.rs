@@ -1,16 +1,20 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::Path;
-use std::io::Result;
+use std::io::{Result, Error, ErrorKind};
 use std::io::prelude::*;
 use std::fs::OpenOptions;
 use std::sync::{Arc,Mutex};
 
+const SNAPSHOT_MAGIC: &'static str = "RUSTDB-SNAPSHOT";
+pub const SNAPSHOT_VERSION: u32 = 1;
+
 // read the content in the in-disk database
-pub fn read_db()->Result<String> {
+pub fn read_db(path: &Path)->Result<String> {
     let mut f = try!(OpenOptions::new()
             .read(true)
+            .write(true)
             .create(true)
-            .open("db.txt"));
+            .open(path));
     let mut s = String::new();
     match f.read_to_string(&mut s) {
         Ok(_) => Ok(s),
@@ -18,19 +22,91 @@ pub fn read_db()->Result<String> {
     }
 }
 
-pub fn store_in_disk(db_content: &str)->Result<()>{
-    let mut f = try!(OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("db.txt"));
-    let content = db_content.to_owned();
-    match f.write(content.as_bytes()){
-        Ok(_) => Ok(()),
-        Err(e) => {
-            return Err(e);
-        },
+// write into a temp file, fsync it and rename it over the old file,
+// so a crash leaves either the old or the new content on disk
+pub fn store_in_disk(path: &Path, db_content: &str)->Result<()>{
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut f = try!(OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .create(true)
+                .open(&temp_path));
+        try!(f.write_all(db_content.as_bytes()));
+        try!(f.sync_all());
+    }
+    try!(fs::rename(&temp_path, path));
+    // persist the rename itself
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(d) = File::open(dir) {
+            let _ = d.sync_all();
+        }
     }
+    Ok(())
+}
+
+// snapshot layout:
+//     RUSTDB-SNAPSHOT <version> <lsn> <length> <crc32>\n
+//     <content>
+pub fn store_snapshot(path: &Path, lsn: u64, content: &str)->Result<()>{
+    let header = format!("{} {} {} {} {:08x}\n", SNAPSHOT_MAGIC, SNAPSHOT_VERSION, lsn,
+                         content.len(), crc32(content.as_bytes()));
+    store_in_disk(path, &(header + content))
+}
+
+// read back a snapshot, returns None when no snapshot has been taken yet
+pub fn read_snapshot(path: &Path)->Result<Option<(u64, String)>>{
+    let raw = try!(read_db(path));
+    if raw.trim().is_empty() {
+        return Ok(None);
+    }
+    let corrupt = |reason: &str| Error::new(ErrorKind::InvalidData,
+        format!("Corrupt snapshot {}: {}", path.display(), reason));
+
+    if raw.starts_with("{") {
+        return Ok(Some((0, raw)));      // plain json dump written before snapshots had a header
+    }
+    let (header, content) = match raw.find('\n') {
+        Some(pos) => (&raw[..pos], &raw[pos + 1..]),
+        None => return Err(corrupt("missing header")),
+    };
+    let parts: Vec<&str> = header.split_whitespace().collect();
+    if parts.len() != 5 || parts[0] != SNAPSHOT_MAGIC {
+        return Err(corrupt("missing header"));
+    }
+    match parts[1].parse::<u32>() {
+        Ok(SNAPSHOT_VERSION) => (),
+        Ok(version) => return Err(corrupt(&format!("unsupported format version {}", version))),
+        Err(_) => return Err(corrupt("malformed header")),
+    }
+    let lsn = match parts[2].parse::<u64>() {
+        Ok(lsn) => lsn,
+        Err(_) => return Err(corrupt("malformed header")),
+    };
+    match parts[3].parse::<usize>() {
+        Ok(length) if length == content.len() => (),
+        Ok(length) => return Err(corrupt(&format!("expected {} bytes, found {}", length, content.len()))),
+        Err(_) => return Err(corrupt("malformed header")),
+    }
+    match u32::from_str_radix(parts[4], 16) {
+        Ok(checksum) if checksum == crc32(content.as_bytes()) => Ok(Some((lsn, content.to_owned()))),
+        Ok(_) => Err(corrupt("checksum mismatch")),
+        Err(_) => Err(corrupt("malformed header")),
+    }
+}
+
+// CRC-32 (IEEE 802.3), bitwise to avoid a lookup table
+pub fn crc32(bytes: &[u8])->u32{
+    let mut crc: u32 = 0xffffffff;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+    !crc
 }
 
 // read the file from the http request source
@@ -61,7 +137,7 @@ pub fn write_into_file(http_content: &str, log_file_with_lock: &Arc<Mutex<OpenOp
 #[cfg(test)]
 mod lib_function_test {
 
-    use super::{get_file_content, write_into_file};
+    use super::{get_file_content, write_into_file, store_snapshot, read_snapshot, crc32};
     use std::fs::{File, OpenOptions, remove_file};
     use std::io::prelude::*;
     use std::io::SeekFrom;
@@ -139,4 +215,35 @@ mod lib_function_test {
         } ;
     }
 
-}
\ No newline at end of file
+
+    #[test]
+    fn crc32_test(){
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn snapshot_round_trip_test(){
+        let path = Path::new("snapshot_round_trip.txt");
+        store_snapshot(path, 7, "{\"collections\":{}}").unwrap();
+        store_snapshot(path, 8, "{}").unwrap();     // shorter content must not leave stale bytes
+        assert_eq!(read_snapshot(path).unwrap(), Some((8, "{}".to_owned())));
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_snapshot_test(){
+        let path = Path::new("corrupt_snapshot.txt");
+        store_snapshot(path, 3, "{\"collections\":{}}").unwrap();
+        let mut content = get_file_content(path).unwrap();
+        content = content.replace("collections", "collectionz");
+        File::create(path).unwrap().write_all(content.as_bytes()).unwrap();
+        assert!(read_snapshot(path).is_err());
+
+        let truncated = get_file_content(path).unwrap();
+        File::create(path).unwrap().write_all(truncated[..truncated.len() - 2].as_bytes()).unwrap();
+        assert!(read_snapshot(path).is_err());
+        remove_file(path).unwrap();
+    }
+
+}
@@ -1,173 +1,345 @@
 extern crate rustc_serialize;
-use rustc_serialize::json;
+use rustc_serialize::json::Json;
 use std::net::{TcpListener,TcpStream};
-use std::thread;
+use std::io::BufReader;
 use std::sync::{Arc,Mutex};
 use std::fs::OpenOptions;
 use std::convert::AsRef;
+use std::path::Path;
+use std::process;
+use std::env;
+use std::time::{Duration, Instant};
 
 
 extern crate time;  // import for record time for log
 
+mod value;
 mod vec_dbcollection;
+mod vecParallelCollection;
+mod dbCollection;
+mod backend;
+mod error;
+use error::DbError;
+mod pool;
+use pool::WorkerPool;
+mod server;
+use server::{ServerConfig, Admission, TurnAway};
 mod db_module;
 use db_module::RustDB;
 mod response;
+use response::{Status, Envelope};
+mod http;
+mod tokenizer;
+mod filter;
+mod query;
+mod aggregate;
+mod index;
+mod alter;
 
 mod request;
 use request::Request;
 pub mod lib;
-
-use lib::{read_db, store_in_disk};
+mod wal;
+use wal::{WriteAheadLog, Operation};
+mod transaction;
+use transaction::{Transaction, apply_all};
+mod mvcc;
 
 fn main() {
-    initial_bind_server(8080);
+    match ServerConfig::from_args(env::args().skip(1)) {
+        Ok(config) => initial_bind_server(config),
+        Err(e) => {
+            println!("{}", e);
+            println!("Usage: rustDB [--port N] [--workers N] [--max-connections N] [--backlog reject|wait]");
+            process::exit(2);
+        },
+    }
 }
 
 
-fn handle_stream(stream:TcpStream,write_log_file: Arc<Mutex<OpenOptions>>, database_obj:&mut Arc<Mutex<RustDB>>){
-    let request_time = time::now().ctime().to_string();    // record time when request come
-    let mut request = Request::new(stream);                // parse the request, extract url and all requet info
+// serve commands on one connection until the client closes it
+fn handle_stream(stream:TcpStream,write_log_file: Arc<Mutex<OpenOptions>>, on_database:&Arc<RustDB>, write_ahead_log: Arc<WriteAheadLog>, idle_timeout: Duration){
+    // an idle client gives its worker back, the read fails and the connection is closed
+    if stream.set_read_timeout(Some(idle_timeout)).is_err() {
+        return;
+    }
+    let mut reader = match stream.try_clone() {
+        Ok(read_stream) => BufReader::new(read_stream),
+        Err(_) => {
+            println!("Reques Stream Error");
+            return;
+        },
+    };
+
+    let mut transaction: Option<Transaction> = None;       // open until COMMIT or ROLLBACK, dropped with the connection
+    loop {
+        let request_time = time::now().ctime().to_string();    // record time when request come
+        let mut request = match Request::read_from(&mut reader) {    // parse the request, extract url and all requet info
+            Some(request) => request,
+            None => break,          // connection closed by the client, or idle for too long
+        };
 
-    let mut on_database = database_obj.lock().unwrap();
-    let mut respone_info = String::new();
+        let envelope = match execute_request(&request, on_database, &write_ahead_log, &mut transaction) {
+            Ok(envelope) => envelope,
+            Err(err) => Envelope::failure(&err),
+        };
 
+        // dump a snapshot from time to time so the write-ahead log stays short
+        if write_ahead_log.should_snapshot() {
+            match write_ahead_log.snapshot(on_database) {
+                Ok(_) => println!("Snapshot store successful"),
+                _ => println!("Failed to store snapshot in disk"),
+            }
+        }
+
+        request.record_log(&request_time, &write_log_file);      // write request info into log
+
+        let mut response = request.form_response(envelope.status, Some(envelope.to_json()), &stream);            // create response structure from request information
+        response.write_response();           // send back response to the client
+        let response_time = time::now().ctime().to_string();   // record time when send out response
+        response.record_log(&response_time, &write_log_file);     // write request info into log
+
+        if !request.keep_alive() {
+            break;
+        }
+    }
+}
+
+fn execute_request(request: &Request, on_database: &RustDB, write_ahead_log: &WriteAheadLog, transaction: &mut Option<Transaction>) -> Result<Envelope, DbError>{
+    if let Some(err) = request.get_error() {
+        return Err(err);
+    }
+
+    // the catalog is not part of a transaction
+    if transaction.is_some() {
+        match request.get_command().as_ref() {
+            "PUTLIST" | "DELETELIST" | "ALTER" | "CREATEINDEX" | "DROPINDEX" => {
+                return Err(DbError::Transaction("Collections cannot be changed inside a transaction"));
+            },
+            _ => (),
+        }
+    }
+
+    // collection locks are only held inside each branch, never while answering the client;
+    // a write looks up its collection under the checkpoint lock, so no DELETELIST or PUTLIST of the same name slips in between;
+    // a change becomes visible only once it is logged, one that cannot be logged is undone or never applied
     match request.get_command().as_ref(){
         "PUTLIST" => {
-            match on_database.create_table(&request.get_collection(), &request.get_parameters()){
-                Ok(_) => respone_info = json::encode(&"Success").unwrap(),
-                Err(e) => respone_info = json::encode(&e.to_owned()).unwrap(),
+            let (fields, constraints, kind) = try!(request.get_parameters());
+            let _catalog = write_ahead_log.lock_for_catalog();
+            try!(on_database.create_table(&request.get_collection(), &fields, &constraints, kind));
+            if let Err(err) = log_operation(write_ahead_log, &Operation::put_list(request.get_collection(), fields, constraints, kind)) {
+                let _ = on_database.delete_cl(&request.get_collection());
+                return Err(err);
             }
+            Ok(Envelope::ok(Status::Created, "Collection created"))
         },
         "DELETELIST" => {
-            match on_database.delete_cl(&request.get_collection()){
-                Ok(s) => respone_info = json::encode(&s.to_owned()).unwrap(),
-                Err(err) => respone_info = json::encode(&err.to_owned()).unwrap(),
-            }
+            let _catalog = write_ahead_log.lock_for_catalog();
+            try!(on_database.find_cl(&request.get_collection()));
+            try!(log_operation(write_ahead_log, &Operation::DeleteList(request.get_collection())));
+            let message = try!(on_database.delete_cl(&request.get_collection()));
+            Ok(Envelope::ok(Status::Ok, message))
         },
         "GETLIST" => {
-            match on_database.find_cl(&request.get_collection()){
-                Ok(s) => {
-                    // create response here
-                    let json_result: String = json::encode(s).unwrap();
-                    println!("result of GETLIST is : {}", json_result);
-                    respone_info = json_result;
-                },
-                Err(err) => respone_info = json::encode(&err.to_owned()).unwrap(),
-            }
+            let cl = try!(on_database.find_cl(&request.get_collection()));
+            let json_result: String = cl.read().unwrap().to_json().unwrap();
+            println!("result of GETLIST is : {}", json_result);
+            Ok(Envelope::ok(Status::Ok, "Collection read").with_encoded(json_result))
         },
         "APPEND" => {
-            match on_database.find_cl(&request.get_collection()){
-                Ok(s) => {
-                    match s.insert(&request.get_attributes()){
-                        Ok(s) => respone_info = json::encode(&s.to_owned()).unwrap(),
-                        Err(err) => respone_info = json::encode(&err.to_owned()).unwrap(),
-                    }
-                },
-                Err(err) => respone_info = json::encode(&err.to_owned()).unwrap(),
+            let attributes = try!(request.get_attributes());
+            if let Some(ref mut transaction) = *transaction {
+                return queue(transaction, on_database, Operation::Append(request.get_collection(), attributes));
             }
+            let _checkpoint = write_ahead_log.lock_for_write();
+            let created = try!(apply_logged(on_database, write_ahead_log, Operation::Append(request.get_collection(), attributes)));
+            Ok(Envelope::ok(Status::Created, "Item appended").with_data(&created).with_affected(1))
         },
         "UPDATE" => {
-            match on_database.find_cl(&request.get_collection()){
-                Ok(s) => {
-                    let (object, desired) = request.get_object_desired();
-                    match s.update(&object, &desired){
-                        None => respone_info = json::encode(&"Error".to_owned()).unwrap(),
-                        Some(num) => {
-                            println!("{} number of items are updated", &num);
-                            respone_info = json::encode(&"Success".to_owned()).unwrap();
-                        },
-                    }
-                },
-                Err(err) => respone_info = json::encode(&err.to_owned()).unwrap(),
+            let (object, desired) = try!(request.get_object_desired());
+            let expected = try!(request.get_precondition());
+            if let Some(ref mut transaction) = *transaction {
+                return queue(transaction, on_database, Operation::update(request.get_collection(), object, desired, expected));
             }
+            let _checkpoint = write_ahead_log.lock_for_write();
+            let num = try!(apply_logged(on_database, write_ahead_log, Operation::update(request.get_collection(), object, desired, expected)));
+            Ok(Envelope::ok(Status::Ok, "Items updated").with_affected(num.as_u64().unwrap_or(0)))
         },
         "GET" => {
-            match on_database.find_cl(&request.get_collection()){
-                Ok(s) => {
-                    match s.find(&request.get_attributes()){
-                        Some(items) => {
-                            let json_data: String = json::encode(&items).unwrap();
-                            println!("the items find are: {}", &json_data);
-                            respone_info = json_data;
-                        },
-                        None => {
-                            println!("Illeagel query");
-                            respone_info = json::encode(&"Error".to_owned()).unwrap();
-                        },
-                    }
-                },
-                Err(err) => respone_info = err.to_owned(),
-            }
+            let (filter, query) = try!(request.get_query());
+            // a transaction reads its own changes
+            let items = match transaction.as_ref().and_then(|t| t.collection(&request.get_collection())) {
+                Some(copy) => try!(copy.select(&filter, &query)),
+                None => try!(mvcc::select(&try!(on_database.find_cl(&request.get_collection())), &filter, &query)),
+            };
+            Ok(Envelope::ok(Status::Ok, "Items found").with_data(&items))
+        },
+        "AGGREGATE" => {
+            let (filter, aggregate) = try!(request.get_aggregate());
+            let summaries = match transaction.as_ref().and_then(|t| t.collection(&request.get_collection())) {
+                Some(copy) => try!(copy.aggregate(&filter, &aggregate)),
+                None => try!(mvcc::aggregate(&try!(on_database.find_cl(&request.get_collection())), &filter, &aggregate)),
+            };
+            Ok(Envelope::ok(Status::Ok, "Items summarized").with_data(&summaries))
         },
         "DELETE" => {
-            match on_database.find_cl(&request.get_collection()){
-                Ok(s) => {
-                    match s.delete(&request.get_attributes()){
-                        Some(number) => {
-                            println!("there are {} number of data deleted", &number);
-                            respone_info = json::encode(&"Success".to_owned()).unwrap();
-                        },
-                        None => {
-                            println!("Illeagel collection attribute");
-                            respone_info = json::encode(&"Error".to_owned()).unwrap();
-                        }
-                    }
+            let filter = try!(request.get_filter());
+            let expected = try!(request.get_precondition());
+            if let Some(ref mut transaction) = *transaction {
+                return queue(transaction, on_database, Operation::delete(request.get_collection(), filter, expected));
+            }
+            let _checkpoint = write_ahead_log.lock_for_write();
+            let number = try!(apply_logged(on_database, write_ahead_log, Operation::delete(request.get_collection(), filter, expected)));
+            Ok(Envelope::ok(Status::Ok, "Items deleted").with_affected(number.as_u64().unwrap_or(0)))
+        },
+        "CREATEINDEX" | "DROPINDEX" => {
+            let (field, kind) = try!(request.get_index());
+            let _checkpoint = write_ahead_log.lock_for_write();
+            let cl = try!(on_database.find_cl(&request.get_collection()));
+            let mut s = cl.write().unwrap();
+            let mut copy = s.clone();
+            let (status, message, operation) = match request.get_command() == "CREATEINDEX" {
+                true => (Status::Created, try!(copy.create_index(&field, kind)), Operation::CreateIndex(request.get_collection(), field, kind)),
+                false => (Status::Ok, try!(copy.drop_index(&field)), Operation::DropIndex(request.get_collection(), field)),
+            };
+            try!(log_operation(write_ahead_log, &operation));
+            *s = copy;
+            Ok(Envelope::ok(status, message))
+        },
+        "ALTER" => {
+            let alterations = try!(request.get_alterations());
+            let _checkpoint = write_ahead_log.lock_for_write();
+            let cl = try!(on_database.find_cl(&request.get_collection()));
+            let mut s = cl.write().unwrap();
+            let mut copy = s.clone();
+            try!(copy.alter(&alterations));
+            try!(log_operation(write_ahead_log, &Operation::Alter(request.get_collection(), alterations)));
+            *s = copy;
+            Ok(Envelope::ok(Status::Ok, "Collection altered"))
+        },
+        "BEGIN" => {
+            if transaction.is_some() {
+                return Err(DbError::Transaction("A transaction is already open"));
+            }
+            *transaction = Some(Transaction::new());
+            Ok(Envelope::ok(Status::Ok, "Transaction started"))
+        },
+        "COMMIT" => {
+            match transaction.take() {
+                Some(open) => {
+                    let results = try!(open.commit(on_database, write_ahead_log));
+                    Ok(Envelope::ok(Status::Ok, "Transaction committed").with_data(&results))
                 },
-                Err(err) => respone_info = err.to_owned(),
+                None => Err(DbError::Transaction("No transaction to commit")),
             }
         },
+        "ROLLBACK" => {
+            match transaction.take() {
+                Some(_) => Ok(Envelope::ok(Status::Ok, "Transaction rolled back")),
+                None => Err(DbError::Transaction("No transaction to roll back")),
+            }
+        },
+        "LISTCOLLECTIONS" => {
+            Ok(Envelope::ok(Status::Ok, "Collections listed").with_data(&on_database.get_collections()))
+        },
+        "DESCRIBE" => {
+            let description = try!(on_database.describe(&request.get_collection()));
+            Ok(Envelope::ok(Status::Ok, "Collection described").with_data(&description))
+        },
         "SHOWDB" => {
-            on_database.show_db();
+            let (offset, limit) = try!(request.get_page());
+            Ok(Envelope::ok(Status::Ok, "Database page").with_data(&on_database.dump(offset, limit)))
         },
-        _ => {
+        command => {
             println!("Receive an illegel query method");
-            respone_info = json::encode(&"Unsupport query type".to_owned()).unwrap();
+            Err(DbError::UnknownCommand(command.to_owned()))
         }
     }
+}
 
-    // in-disk storage for database content
-    let json_for_storage: String = json::encode(&*on_database).unwrap();
-    match store_in_disk(&json_for_storage) {
-        Ok(_) => println!("Query result store successful"),
-        _ => println!("Failed to store in disk"),
-    }
+// run a statement inside the open transaction, the answer is tentative until COMMIT
+fn queue(transaction: &mut Transaction, on_database: &RustDB, operation: Operation) -> Result<Envelope, DbError>{
+    // APPEND is answered with the id, UPDATE and DELETE with the number of items
+    Ok(match try!(transaction.execute(on_database, operation)) {
+        Json::U64(number) => Envelope::ok(Status::Accepted, "Statement queued").with_affected(number),
+        result => Envelope::ok(Status::Accepted, "Statement queued").with_data(&result).with_affected(1),
+    })
+}
 
-    request.record_log(&request_time, &write_log_file);      // write request info into log
+// in-disk storage: called while the collection or catalog lock is still held,
+// so the log keeps the order in which changes were applied; a change that is not logged is
+// not applied and not acknowledged
+fn log_operation(write_ahead_log: &WriteAheadLog, operation: &Operation) -> Result<(), DbError>{
+    match write_ahead_log.append(operation) {
+        Ok(_) => {
+            println!("Query result logged");
+            Ok(())
+        },
+        Err(e) => Err(DbError::Io(format!("Failed to write ahead log, the change is not applied: {}", e))),
+    }
+}
 
-    let mut response = request.form_response(Some(respone_info));            // create response structure from request information
-    response.write_response();           // send back response to the client
-    let response_time = time::now().ctime().to_string();   // record time when send out response
-    response.record_log(&response_time, &write_log_file);     // write request info into log
+// APPEND, UPDATE or DELETE on a copy of its collection, the copy replaces the collection once the change is logged
+fn apply_logged(on_database: &RustDB, write_ahead_log: &WriteAheadLog, operation: Operation) -> Result<Json, DbError>{
+    let operations = [operation];
+    match apply_all(on_database, &operations, |_| log_operation(write_ahead_log, &operations[0])) {
+        Ok(mut results) => Ok(results.remove(0)),
+        Err(DbError::StatementFailed(_, failed)) => Err(*failed),
+        Err(err) => Err(err),
+    }
 }
 
 
-fn initial_bind_server(port:usize){
+fn initial_bind_server(config: ServerConfig){
     // bing server to the localhost
-    let bind_addr:&str = &("127.0.0.1:".to_owned()+&port.to_string());
+    let bind_addr:&str = &("127.0.0.1:".to_owned()+&config.port.to_string());
     let listener = TcpListener::bind(bind_addr).unwrap();
     println!("Server Started");
 
     // new database object initial here 
-    // read data from in-disk
-    let mut database = Arc::new(Mutex::new(RustDB::new()));
+    // read latest snapshot from in-disk and replay the write-ahead log after it
     let file_for_log = Arc::new(Mutex::new(OpenOptions::new()));
+    let (database, write_ahead_log) = match WriteAheadLog::recover(Path::new("db.txt"), Path::new("wal.txt")){
+        Ok((rust_db, wal)) => (Arc::new(rust_db), Arc::new(wal)),
+        Err(e) => {
+            println!("Failed to recover database: {}", e);
+            println!("Server Stopped, restore or remove the damaged file to start again");
+            process::exit(1);
+        },
+    };
+    // drops the item versions no reader needs anymore
+    mvcc::spawn_collector(database.clone());
 
-    if let Ok(storage_string) = read_db(){
-        if storage_string.is_empty() == false{
-            let rust_db : RustDB = json::decode(&storage_string).unwrap();
-            database = Arc::new(Mutex::new(rust_db));
-        }
-    }
-
+    // a fixed number of threads serve the admitted connections, see server.rs for the limits
+    let workers = WorkerPool::new(config.workers);
+    let admission = Admission::new(&config);
+    let turned_away = TurnAway::spawn();
     for stream in listener.incoming() {
         let log_file_for_write = file_for_log.clone();
-        let mut database_obj = database.clone();
+        let database_obj = database.clone();
+        let wal_for_write = write_ahead_log.clone();
+        let busy = turned_away.clone();
+        let (idle_timeout, queue_timeout) = (config.idle_timeout, config.queue_timeout);
         match stream{
             Ok(stream)=>{               
-                thread::spawn(move || {  // spawn a thread for each request 
-                    handle_stream(stream,log_file_for_write,&mut database_obj);
-                });
+                match admission.admit() {
+                    Some(slot) => {
+                        let admitted = Instant::now();
+                        workers.execute(move || {
+                            let _slot = slot;       // given back when the connection ends
+                            if admitted.elapsed() > queue_timeout {
+                                println!("Server busy, queued connection turned away");
+                                return busy.send(stream);
+                            }
+                            handle_stream(stream,log_file_for_write,&database_obj,wal_for_write,idle_timeout);
+                        })
+                    },
+                    None => {
+                        println!("Server busy, connection turned away");
+                        turned_away.send(stream);
+                    },
+                }
             },
             Err(_)=>{
                 println!("Reques Stream Error");
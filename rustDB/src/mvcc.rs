@@ -0,0 +1,322 @@
+#[doc="
+  Multi-version reads.
+
+  Every write of a collection gets the next version number of the
+  collection. An update does not change an item in place, it adds a new
+  version of the item and marks the old one expired with the version of the
+  update, a delete only marks the item expired. A snapshot is the version of
+  the last write, a reader of it sees every item version created at or
+  before it and not yet expired at it.
+
+  GET and AGGREGATE hold the read lock of the collection while they take the
+  snapshot and then for CHUNK_ROWS rows at a time, writers get the lock in
+  between and the reader still sees the collection as it was when it
+  started. ALTER changes every version in place, so a reader that finds
+  the fields changed between two chunks starts over with the new fields.
+  Every COLLECT_INTERVAL seconds the collector drops the versions
+  that no registered snapshot can see anymore.
+
+  Only the indexed backend keeps versions. The sequential and parallel
+  backends keep the latest version only (see backend.rs), they are read
+  under the lock in one go and a reader waits for the writers.
+"]
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use db_module::{RustDB, SharedCollection};
+use vec_dbcollection::Record;
+use value::Value;
+use filter::Filter;
+use query::Query;
+use aggregate::{Aggregate, Summary, summarize};
+use index::Rows;
+use backend::CollectionBackend;
+use error::DbError;
+
+pub const CHUNK_ROWS: usize = 1000;
+pub const COLLECT_INTERVAL: u64 = 5;
+
+// versions being read, with the number of readers of each
+#[derive(Debug, Clone)]
+pub struct Readers {
+    versions: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+impl Readers {
+    pub fn new() -> Self {
+        Readers {
+            versions: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    // the oldest version still read, None when nobody reads
+    pub fn oldest(&self) -> Option<u64> {
+        self.versions.lock().unwrap().keys().next().cloned()
+    }
+}
+
+// a registered version, released when dropped
+pub struct Snapshot {
+    version: u64,
+    epoch: u64,         // of the fields the filter was bound to
+    readers: Readers,
+}
+
+impl Snapshot {
+    pub fn new(readers: &Readers, version: u64, epoch: u64) -> Self {
+        *readers.versions.lock().unwrap().entry(version).or_insert(0) += 1;
+        Snapshot {
+            version: version,
+            epoch: epoch,
+            readers: readers.clone(),
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut versions = self.readers.versions.lock().unwrap();
+        let released = match versions.get_mut(&self.version) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false,
+        };
+        if released {
+            versions.remove(&self.version);
+        }
+    }
+}
+
+// GET on a snapshot
+pub fn select(shared: &SharedCollection, target: &Filter<String>, query: &Query) -> Result<Vec<Record>, DbError> {
+    loop {
+        let (snapshot, bound, rows) = {
+            let shared_collection = shared.read().unwrap();
+            let collection = match shared_collection.multi_version() {
+                Some(collection) => collection,
+                None => return shared_collection.select(target, query),
+            };
+            let bound = try!(target.bind(collection.get_fields()));
+            try!(query.check(collection.get_fields()).map_err(DbError::InvalidQuery));
+            let rows = collection.candidate_rows(&bound);
+            (collection.snapshot(), bound, rows)
+        };
+        if let Some(items) = read(shared, &snapshot, &bound, rows) {
+            return Ok(query.apply(items.iter().collect()));
+        }
+    }
+}
+
+// AGGREGATE on a snapshot
+pub fn aggregate(shared: &SharedCollection, target: &Filter<String>, aggregate: &Aggregate) -> Result<Vec<Summary>, DbError> {
+    loop {
+        let (snapshot, bound, rows) = {
+            let shared_collection = shared.read().unwrap();
+            let collection = match shared_collection.multi_version() {
+                Some(collection) => collection,
+                None => return shared_collection.aggregate(target, aggregate),
+            };
+            let bound = try!(target.bind(collection.get_fields()));
+            try!(aggregate.check(collection.get_fields()).map_err(DbError::InvalidQuery));
+            let rows = collection.candidate_rows(&bound);
+            (collection.snapshot(), bound, rows)
+        };
+        if let Some(items) = read(shared, &snapshot, &bound, rows) {
+            return summarize(items, aggregate);
+        }
+    }
+}
+
+// the matching items of the snapshot, the lock is taken again for every chunk of rows;
+// None when the fields were altered since the filter was bound
+fn read(shared: &SharedCollection, snapshot: &Snapshot, target: &Filter<Value>, candidates: Option<Rows>) -> Option<Vec<Record>> {
+    let mut found = Vec::new();
+    match candidates {
+        Some(rows) => {
+            let rows: Vec<u64> = rows.into_iter().collect();
+            for chunk in rows.chunks(CHUNK_ROWS) {
+                let collection = shared.read().unwrap();
+                if let Some(collection) = collection.multi_version() {
+                    if collection.get_epoch() != snapshot.epoch {
+                        return None;
+                    }
+                    found.extend(collection.read_at(target, snapshot.version(), chunk));
+                }
+            }
+        },
+        None => {
+            let mut from = 0;
+            loop {
+                let shared_collection = shared.read().unwrap();
+                let collection = match shared_collection.multi_version() {
+                    Some(collection) => collection,
+                    None => break,
+                };
+                if collection.get_epoch() != snapshot.epoch {
+                    return None;
+                }
+                let rows = collection.rows_from(from, CHUNK_ROWS);
+                found.extend(collection.read_at(target, snapshot.version(), &rows));
+                match rows.last() {
+                    Some(last) if rows.len() == CHUNK_ROWS => from = last + 1,
+                    _ => break,
+                }
+            }
+        },
+    }
+    Some(found)
+}
+
+// drop the versions nobody can see in every collection, one collection locked at a time
+pub fn collect_garbage(database: &RustDB) -> usize {
+    database.all_cl().iter().map(|cl| cl.write().unwrap().collect_garbage()).sum()
+}
+
+pub fn spawn_collector(database: Arc<RustDB>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(COLLECT_INTERVAL));
+            collect_garbage(&database);
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod mvcc_tests {
+    use super::{read, select, collect_garbage, CHUNK_ROWS};
+    use std::sync::{Arc, RwLock};
+    use db_module::{RustDB, SharedCollection};
+    use vec_dbcollection::{Collection, TableEntry, Record};
+    use backend::BackendKind;
+    use value::{Value, FieldType, Schema, Constraints};
+    use filter::Filter;
+    use query::Query;
+    use index::IndexKind;
+    use error::DbError;
+    use alter::Alteration;
+
+    #[test]
+    fn snapshot_test() {
+        let shared = new_collection();
+        for (name, age) in vec![("Ada", "24"), ("Bob", "25"), ("Cy", "26")] {
+            shared.write().unwrap().insert(&new_entry(name, age)).unwrap();
+        }
+        let snapshot = shared.read().unwrap().multi_version().unwrap().snapshot();
+        let all = Filter::<String>::new().bind(&new_fields()).unwrap();
+
+        // written after the snapshot was taken
+        shared.write().unwrap().update(&Filter::parse("name Ada", 1).unwrap(), &new_age("30")).unwrap();
+        shared.write().unwrap().delete(&Filter::parse("name Bob", 1).unwrap()).unwrap();
+        shared.write().unwrap().insert(&new_entry("Dan", "27")).unwrap();
+
+        let seen = read(&shared, &snapshot, &all, None).unwrap();
+        assert_eq!(ages(&seen), vec![24, 25, 26]);
+        let now = select(&shared, &Filter::new(), &Query::new()).unwrap();
+        assert_eq!(ages(&now), vec![30, 26, 27]);
+
+        // an index also finds the old version
+        shared.write().unwrap().create_index("age", IndexKind::BTree).unwrap();
+        let young = Filter::parse("age < 25", 1).unwrap().bind(&new_fields()).unwrap();
+        let rows = shared.read().unwrap().multi_version().unwrap().candidate_rows(&young);
+        assert_eq!(ages(&read(&shared, &snapshot, &young, rows).unwrap()), vec![24]);
+    }
+
+    #[test]
+    fn collect_garbage_test() {
+        let db = RustDB::new();
+        let shared = db.create_table("student", &new_fields(), &Constraints::new(), BackendKind::Indexed).unwrap();
+        shared.write().unwrap().insert(&new_entry("Ada", "24")).unwrap();
+        shared.write().unwrap().insert(&new_entry("Bob", "25")).unwrap();
+        let snapshot = shared.read().unwrap().multi_version().unwrap().snapshot();
+        shared.write().unwrap().update(&Filter::parse("name Ada", 1).unwrap(), &new_age("30")).unwrap();
+        shared.write().unwrap().delete(&Filter::parse("name Bob", 1).unwrap()).unwrap();
+
+        // kept while the snapshot is read
+        assert_eq!(collect_garbage(&db), 0);
+        let all = Filter::<String>::new().bind(&new_fields()).unwrap();
+        assert_eq!(ages(&read(&shared, &snapshot, &all, None).unwrap()), vec![24, 25]);
+
+        drop(snapshot);
+        assert_eq!(collect_garbage(&db), 2);
+        assert_eq!(collect_garbage(&db), 0);
+        let collection = shared.read().unwrap();
+        assert_eq!(collection.get_number_of_data(), 1);
+        assert_eq!(collection.multi_version().unwrap().rows_from(0, 10), vec![0]);
+    }
+
+    #[test]
+    fn chunked_read_test() {
+        let shared = new_collection();
+        for age in 0..CHUNK_ROWS + 10 {
+            shared.write().unwrap().insert(&new_entry("Ada", &age.to_string())).unwrap();
+        }
+        let found = select(&shared, &Filter::parse("age >= 5", 1).unwrap(), &Query::new()).unwrap();
+        assert_eq!(found.len(), CHUNK_ROWS + 5);
+    }
+
+    #[test]
+    fn altered_while_read_test() {
+        let shared = new_collection();
+        shared.write().unwrap().insert(&new_entry("Ada", "24")).unwrap();
+        let snapshot = shared.read().unwrap().multi_version().unwrap().snapshot();
+        let all = Filter::<String>::new().bind(&new_fields()).unwrap();
+
+        // the filter was bound to the old fields, the read starts over with the new ones
+        let rename = Alteration::RenameField("age".to_owned(), "years".to_owned());
+        shared.write().unwrap().alter(&[rename]).unwrap();
+        assert!(read(&shared, &snapshot, &all, None).is_none());
+        assert_eq!(select(&shared, &Filter::parse("years 24", 1).unwrap(), &Query::new()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn single_version_test() {
+        // read under the lock by a backend without versions
+        let db = RustDB::new();
+        let shared = db.create_table("student", &new_fields(), &Constraints::new(), BackendKind::Sequential).unwrap();
+        shared.write().unwrap().insert(&new_entry("Ada", "24")).unwrap();
+        shared.write().unwrap().insert(&new_entry("Bob", "25")).unwrap();
+        let found = select(&shared, &Filter::parse("age > 24", 1).unwrap(), &Query::new()).unwrap();
+        assert_eq!(ages(&found), vec![25]);
+        assert_eq!(select(&shared, &Filter::parse("gender f", 1).unwrap(), &Query::new()), Err(DbError::UnknownField("gender".to_owned())));
+        assert_eq!(collect_garbage(&db), 0);
+    }
+
+    fn new_fields() -> Schema {
+        let mut fields = Schema::new();
+        fields.insert("name".to_owned(), FieldType::Str);
+        fields.insert("age".to_owned(), FieldType::Int);
+        fields
+    }
+
+    fn new_collection() -> SharedCollection {
+        Arc::new(RwLock::new(Box::new(Collection::with_schema(&new_fields()))))
+    }
+
+    fn new_entry(name: &str, age: &str) -> TableEntry {
+        let mut entry = new_age(age);
+        entry.insert("name".to_owned(), name.to_owned());
+        entry
+    }
+
+    fn new_age(age: &str) -> TableEntry {
+        let mut entry = TableEntry::new();
+        entry.insert("age".to_owned(), age.to_owned());
+        entry
+    }
+
+    fn ages(items: &Vec<Record>) -> Vec<i64> {
+        items.iter().map(|item| match item.get("age") {
+            Some(&Value::Int(age)) => age,
+            _ => -1,
+        }).collect()
+    }
+}
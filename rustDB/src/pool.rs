@@ -0,0 +1,162 @@
+#[doc="
+  A fixed number of worker threads taking jobs from one queue.
+
+  A job waits in the queue until a worker is free, so the number of threads
+  stays the same however many jobs are queued. map splits a list into
+  chunks, runs a function on every chunk on the workers and returns the
+  results in chunk order, or WorkerFailed when a chunk has no result. A job
+  that panics does not take its worker down. Dropping the pool lets the
+  workers finish the queued jobs and stop.
+
+  The scans of every collection and the summaries of large answers share
+  the one pool of scans(), so the number of threads of the process does
+  not grow with the number of collections. A job of that pool must not map
+  on it again, it would wait for workers that wait for it.
+"]
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+use std::panic::{self, AssertUnwindSafe};
+use std::cmp;
+
+use error::DbError;
+
+// threads of the pool of scans
+const SCAN_WORKERS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Debug)]
+pub struct WorkerPool {
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    // at least one worker
+    pub fn new(size: usize) -> Self {
+        let (jobs, queue) = channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..cmp::max(1, size)).map(|_| {
+            let queue = queue.clone();
+            thread::spawn(move || work(queue))
+        }).collect();
+        WorkerPool {
+            jobs: Some(jobs),
+            workers: workers,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn execute<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        if let Some(ref jobs) = self.jobs {
+            jobs.send(Box::new(job)).unwrap();
+        }
+    }
+
+    // f on every chunk of at most chunk_size items, the results in chunk order
+    pub fn map<T, R, F>(&self, mut items: Vec<T>, chunk_size: usize, f: F) -> Result<Vec<R>, DbError>
+        where T: Send + 'static, R: Send + 'static, F: Fn(Vec<T>) -> R + Send + Sync + 'static
+    {
+        let chunk_size = cmp::max(1, chunk_size);
+        let chunks = (items.len() + chunk_size - 1) / chunk_size;
+        let f = Arc::new(f);
+        let (results, finished) = channel();
+        // cut from the back, so no item is moved twice
+        for number in (0..chunks).rev() {
+            let chunk = items.split_off(number * chunk_size);
+            let f = f.clone();
+            let results = results.clone();
+            self.execute(move || {
+                let _ = results.send((number, f(chunk)));
+            });
+        }
+        drop(results);
+        let mut ordered: Vec<Option<R>> = (0..chunks).map(|_| None).collect();
+        for (number, result) in finished.iter() {
+            ordered[number] = Some(result);
+        }
+        // a chunk whose job panicked has no result
+        ordered.into_iter().map(|result| result.ok_or(DbError::WorkerFailed)).collect()
+    }
+}
+
+// the pool the scans of all collections run on, started on first use
+pub fn scans() -> &'static WorkerPool {
+    static SCANS: OnceLock<WorkerPool> = OnceLock::new();
+    SCANS.get_or_init(|| WorkerPool::new(SCAN_WORKERS))
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// run jobs until the pool is dropped
+fn work(queue: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = match queue.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+
+#[cfg(test)]
+mod pool_tests {
+    use super::{WorkerPool, scans};
+    use error::DbError;
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn map_test() {
+        let pool = WorkerPool::new(3);
+        let items: Vec<usize> = (0..1000).collect();
+        let sums = pool.map(items, 100, |chunk| chunk.iter().sum::<usize>()).unwrap();
+        assert_eq!(sums.len(), 10);
+        assert_eq!(sums[0], (0..100).sum());
+        assert_eq!(sums[9], (900..1000).sum());
+
+        let doubled: Vec<usize> = pool.map((0..10).collect(), 3, |chunk: Vec<usize>| chunk.iter().map(|n| n * 2).collect::<Vec<usize>>()).unwrap()
+            .into_iter().flat_map(|chunk| chunk.into_iter()).collect();
+        assert_eq!(doubled, (0..10).map(|n| n * 2).collect::<Vec<usize>>());
+        assert!(pool.map(Vec::<usize>::new(), 10, |chunk| chunk.len()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bounded_test() {
+        let pool = WorkerPool::new(2);
+        let threads = Arc::new(Mutex::new(BTreeSet::new()));
+        let seen = threads.clone();
+        pool.map((0..500).collect::<Vec<usize>>(), 1, move |_| {
+            seen.lock().unwrap().insert(format!("{:?}", thread::current().id()));
+        }).unwrap();
+        assert!(threads.lock().unwrap().len() <= pool.size());
+
+        // a panicking job leaves the worker running
+        pool.execute(|| panic!("job failed"));
+        assert_eq!(pool.map(vec![1, 2, 3], 1, |chunk| chunk[0]), Ok(vec![1, 2, 3]));
+
+        // a chunk that fails is an error, not a panic of the caller
+        assert_eq!(pool.map(vec![1, 2, 3], 1, |chunk| if chunk[0] == 2 { panic!("chunk failed") } else { chunk[0] }), Err(DbError::WorkerFailed));
+        assert_eq!(pool.map(vec![4], 1, |chunk| chunk[0]), Ok(vec![4]));
+    }
+
+    #[test]
+    fn scans_test() {
+        // one pool for the whole process
+        assert!(scans() as *const WorkerPool == scans() as *const WorkerPool);
+        assert_eq!(scans().map(vec![1, 2], 1, |chunk| chunk[0] * 10), Ok(vec![10, 20]));
+    }
+}
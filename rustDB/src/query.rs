@@ -0,0 +1,211 @@
+#[doc="
+  Options of GET, given as lines of their own next to the filter lines:
+
+        FIELDS name age             only these fields of every item
+        SORT age desc name          by age from high to low, then by name
+        LIMIT 10                    at most 10 items
+        OFFSET 20                   skip the first 20 items
+
+  A field sorts ascending unless asc or desc follows it, an item without a
+  value for the field comes first. Items that compare equal keep their
+  storage order. The offset and limit are applied after sorting, the
+  projection last. Like AND, OR and NOT the keywords are capital words.
+"]
+use std::cmp::Ordering;
+
+use value::{Schema, field_type};
+use vec_dbcollection::Record;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub fields: Option<Vec<String>>,            // every field when None
+    pub sort: Vec<(String, Order)>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl Query {
+    // every item in storage order
+    pub fn new() -> Self {
+        Query {
+            fields: None,
+            sort: Vec::new(),
+            limit: None,
+            offset: 0,
+        }
+    }
+
+    pub fn is_keyword(word: &str) -> bool {
+        match word {
+            "FIELDS" | "SORT" | "LIMIT" | "OFFSET" => true,
+            _ => false,
+        }
+    }
+
+    // set the option of a keyword from the words after it
+    pub fn set(&mut self, keyword: &str, words: &[String]) -> Result<(), String> {
+        match keyword {
+            "FIELDS" => {
+                if words.is_empty() {
+                    return Err("FIELDS expects at least one field".to_owned());
+                }
+                self.fields = Some(words.to_vec());
+            },
+            "SORT" => {
+                if words.is_empty() {
+                    return Err("SORT expects at least one field".to_owned());
+                }
+                let mut sort = Vec::new();
+                let mut position = 0;
+                while position < words.len() {
+                    let order = match words.get(position + 1).map(|word| word.to_lowercase()) {
+                        Some(ref word) if word == "asc" => Some(Order::Ascending),
+                        Some(ref word) if word == "desc" => Some(Order::Descending),
+                        _ => None,
+                    };
+                    sort.push((words[position].clone(), order.unwrap_or(Order::Ascending)));
+                    position += if order.is_some() { 2 } else { 1 };
+                }
+                self.sort = sort;
+            },
+            "LIMIT" => self.limit = Some(try!(count_of(keyword, words))),
+            "OFFSET" => self.offset = try!(count_of(keyword, words)),
+            _ => return Err(format!("unknown option {}", keyword)),
+        }
+        Ok(())
+    }
+
+    // a field that is not in the schema is an error, as in a filter
+    pub fn check(&self, fields: &Schema) -> Result<(), String> {
+        let projected = self.fields.iter().flat_map(|names| names.iter());
+        for name in projected.chain(self.sort.iter().map(|&(ref name, _)| name)) {
+            if field_type(fields, name).is_none() {
+                return Err(format!("field {} does not exist", name));
+            }
+        }
+        Ok(())
+    }
+
+    // sort, cut and project the matching items, only the returned ones are copied
+    pub fn apply(&self, mut items: Vec<&Record>) -> Vec<Record> {
+        if !self.sort.is_empty() {
+            items.sort_by(|a, b| self.compare(a, b));
+        }
+        let page = items.into_iter().skip(self.offset).take(self.limit.unwrap_or(usize::max_value()));
+        match self.fields {
+            None => page.cloned().collect(),
+            Some(ref fields) => page.map(|item| {
+                fields.iter().filter_map(|field| item.get(field).map(|value| (field.clone(), value.clone()))).collect()
+            }).collect(),
+        }
+    }
+
+    fn compare(&self, a: &Record, b: &Record) -> Ordering {
+        for &(ref field, order) in self.sort.iter() {
+            let ordering = match order {
+                Order::Ascending => a.get(field).cmp(&b.get(field)),
+                Order::Descending => b.get(field).cmp(&a.get(field)),
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+fn count_of(keyword: &str, words: &[String]) -> Result<usize, String> {
+    if words.len() != 1 {
+        return Err(format!("{} expects one number", keyword));
+    }
+    match words[0].parse::<usize>() {
+        Ok(count) => Ok(count),
+        Err(_) => Err(format!("{} expects a number, not {}", keyword, words[0])),
+    }
+}
+
+
+#[cfg(test)]
+mod query_tests {
+    use super::{Query, Order};
+    use value::{Value, FieldType, Schema};
+    use vec_dbcollection::Record;
+
+    #[test]
+    fn set_test() {
+        let mut query = Query::new();
+        assert!(query.set("FIELDS", &words("name age")).is_ok());
+        assert!(query.set("SORT", &words("age DESC name id asc")).is_ok());
+        assert!(query.set("LIMIT", &words("10")).is_ok());
+        assert!(query.set("OFFSET", &words("5")).is_ok());
+        assert_eq!(query.fields, Some(words("name age")));
+        assert_eq!(query.sort, vec![("age".to_owned(), Order::Descending),
+                                    ("name".to_owned(), Order::Ascending),
+                                    ("id".to_owned(), Order::Ascending)]);
+        assert_eq!((query.limit, query.offset), (Some(10), 5));
+
+        assert!(query.set("LIMIT", &words("ten")).is_err());
+        assert!(query.set("OFFSET", &words("1 2")).is_err());
+        assert!(query.set("SORT", &words("")).is_err());
+        assert!(Query::is_keyword("LIMIT") && !Query::is_keyword("limit"));
+    }
+
+    #[test]
+    fn apply_test() {
+        let items = vec![record("Ada", 30), record("Bob", 25), record("Cy", 30), record("Dan", 20)];
+        let refs = || items.iter().collect::<Vec<&Record>>();
+
+        let mut query = Query::new();
+        assert_eq!(query.apply(refs()), items);
+
+        query.sort = vec![("age".to_owned(), Order::Descending), ("name".to_owned(), Order::Ascending)];
+        assert_eq!(names(&query.apply(refs())), vec!["Ada", "Cy", "Bob", "Dan"]);
+
+        query.offset = 1;
+        query.limit = Some(2);
+        assert_eq!(names(&query.apply(refs())), vec!["Cy", "Bob"]);
+        query.offset = 10;
+        assert!(query.apply(refs()).is_empty());
+
+        query = Query::new();
+        query.fields = Some(vec!["name".to_owned()]);
+        let projected = query.apply(refs());
+        assert_eq!(projected[0].len(), 1);
+        assert_eq!(projected[0].get("name"), Some(&Value::from("Ada")));
+    }
+
+    #[test]
+    fn check_test() {
+        let mut fields = Schema::new();
+        fields.insert("name".to_owned(), FieldType::Str);
+        let mut query = Query::new();
+        query.fields = Some(vec!["name".to_owned(), "_id".to_owned()]);
+        assert!(query.check(&fields).is_ok());
+        query.sort = vec![("age".to_owned(), Order::Ascending)];
+        assert!(query.check(&fields).is_err());
+    }
+
+    fn words(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|word| word.to_owned()).collect()
+    }
+
+    fn names(items: &Vec<Record>) -> Vec<String> {
+        items.iter().map(|item| match item.get("name") {
+            Some(&Value::Str(ref name)) => name.clone(),
+            _ => String::new(),
+        }).collect()
+    }
+
+    fn record(name: &str, age: i64) -> Record {
+        let mut record = Record::new();
+        record.insert("name".to_owned(), Value::from(name));
+        record.insert("age".to_owned(), Value::Int(age));
+        record
+    }
+}
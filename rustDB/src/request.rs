@@ -5,10 +5,29 @@
         PUTLIST
         @Arguments:
             PUTLIST CollectionName
-            Attributes1
-            Attributes2
+            Attributes1 Type primary key
+            Attributes2 unique
+            Attributes3 Type required default Value
+            Attributes4 nullable
             ...
         @Purpose: Create a new collection in the database with the given attributes
+        Type is one of string, int, float, bool or timestamp, string when left
+        out. Values of APPEND, UPDATE, GET and DELETE are converted to the field
+        type, a value that does not convert is rejected.
+        No two items may share a value of a unique field, the primary key is
+        unique and every APPEND must give it.
+        Every item has a value for a required field, an APPEND that leaves a
+        field out stores its default, and one still without a required field
+        is rejected. Any other field may be left out. A nullable field may
+        also be cleared: the unquoted value NULL of APPEND and UPDATE, or
+        null in an HTTP body, leaves it without a value (\"NULL\" is the text),
+        on any other field NULL is rejected. Required fields and the primary
+        key cannot be nullable. Fields that are not declared are
+        rejected by APPEND and UPDATE, and a snapshot with an item that breaks
+        these rules is not loaded.
+        A line BACKEND sequential, BACKEND parallel or BACKEND indexed picks
+        how the items are stored, indexed when left out (see backend.rs).
+        Primary key, unique fields and CREATEINDEX need the indexed backend.
 
         DELETELIST
         @Arguments: 
@@ -26,20 +45,86 @@
             Key Value
             ...
         @Purpose: Add an element to an existing list in the data store
+        Answered with the id of the new element, {\"_id\": 3}. Ids are handed
+        out in insert order and never reused. GET, UPDATE and DELETE address
+        an element by it, e.g.  _id 3, and GET returns it with the element.
 
         UPDATE
         @Arguments: 
             UPDATE CollectionName
             Key Value;Key Value;...;    // parse condition
             Key Value;...;  //update value
+            IF VERSION Number
         @Purpose: Update existing item in the databse
+        Every item carries a _version, 1 when it is appended and one more
+        with each update, GET returns it with the item. With an IF VERSION
+        line UPDATE and DELETE change nothing unless every matching item is
+        still at that version, a stale write is answered with status 412.
+
+        CREATEINDEX
+        @Arguments:
+            CREATEINDEX CollectionName
+            Attribute [hash|btree]
+        @Purpose: Index a field, a hash index serves equality and in-list
+        conditions, a btree index (the default) also ranges and prefixes.
+        Filters use an index by themselves when one applies.
+
+        DROPINDEX
+        @Arguments:
+            DROPINDEX CollectionName
+            Attribute
+        @Purpose: Remove the index of a field
+
+        ALTER
+        @Arguments:
+            ALTER CollectionName
+            ADD Attribute [Type] [DEFAULT Value]
+            DROP Attribute
+            RENAME Attribute TO NewName
+            ...
+        @Purpose: Add, drop and rename fields of a collection and of all of
+        its items, the lines are applied together or not at all (see alter.rs).
+
+        Values may be quoted, e.g.  name \"Ada Lovelace\"  or  note 'a;b',
+        and use the escapes \\n \\t \\\" \\; (see tokenizer.rs). A quoted value
+        may span several lines, but not a blank one: the blank line ends the
+        command even inside quotes, an empty line in a value is written \\n.
+        A value left open there, or longer than MAX_PARAMETER_BYTES, is
+        answered with the unterminated quoted string. A malformed line is
+        answered with its line, column and reason.
 
         GET
         @Arguments: 
             GET CollectionName
             Key Value
             ...
+            SORT Key [asc|desc] ...
+            LIMIT Count
         Purpose: Retrieve stored value that has the queried key-value
+        Lines starting with FIELDS, SORT, LIMIT or OFFSET select fields, order
+        and page of the result (see query.rs).
+
+        The condition line of UPDATE and the lines of GET and DELETE also take
+        operators, e.g.  age > 21; name != Ada  or  score between 10 and 20,
+        combined with AND, OR, NOT and parentheses (see filter.rs).
+
+        Snapshot reads are offered by the indexed backend only: there GET and
+        AGGREGATE see the collection as it was when they started and do not
+        hold up writers (see mvcc.rs). On the sequential and parallel backends
+        they read the latest items under the lock of the collection, so they
+        wait for a write in progress and writes wait for them.
+
+        AGGREGATE
+        @Arguments:
+            AGGREGATE CollectionName
+            Key Value
+            ...
+            GROUP BY Key ...
+            COUNT | SUM Key | AVG Key | MIN Key | MAX Key | DISTINCT Key
+            ...
+        Purpose: Count, sum, average, smallest and largest value and number of
+        different values of the items that have the queried key-value, per
+        group of items (see aggregate.rs).
 
         DELETE
         @Arguments: 
@@ -47,74 +132,192 @@
             Key Value
             ...
         Purpose: Deltte stored value that has the queried key-value
+
+        LISTCOLLECTIONS
+        @Arguments:
+            LISTCOLLECTIONS
+        Purpose: The names of the collections, in name order
+
+        DESCRIBE
+        @Arguments:
+            DESCRIBE CollectionName
+        Purpose: Backend, fields and their types, primary key, unique fields,
+        indexes, whether reads are snapshot reads, number of items and the
+        approximate size of the collection in memory and in a snapshot
+
+        SHOWDB
+        @Arguments:
+            SHOWDB
+            LIMIT Count
+            OFFSET Count
+        Purpose: One page of the items of every collection, collection after
+        collection in name order, 100 items when LIMIT is left out and at
+        most 1000 whatever the LIMIT, the answer shows the LIMIT used. Each
+        collection on the page comes with its fields, next is the OFFSET of
+        the following page and null on the last one.
+
+        BEGIN / COMMIT / ROLLBACK
+        @Arguments:
+            BEGIN
+        Purpose: Group the APPEND, UPDATE and DELETE commands of a connection,
+        COMMIT applies all of them or none, ROLLBACK drops them (see
+        transaction.rs).
+
+        Every command ends with an empty \\r\\n line. A connection stays open
+        after the response, so several commands can be sent one after another.
+        Each response is framed as:
+            <length of body in bytes>\\r\\n
+            <body>\\r\\n
+        The body is the same JSON envelope for every command (see response.rs):
+            {\"status\": \"ok\", \"code\": null, \"message\": \"Items updated\", \"data\": null, \"affected\": 2}
+        status is ok or error, code names the error, data holds the items of
+        GET and similar results, affected the number of items written.
+
+        A first line like \"GET /collections/student HTTP/1.1\" switches the
+        request to HTTP, see http.rs for the routes.
     **/
 "]
 
 use std::net::TcpStream;
-use std::io::BufReader;
 use std::io::prelude::*;
 use std::fs::OpenOptions;
 use std::sync::{Arc,Mutex};
-use std::collections::{HashMap, BTreeSet};
-type Set<K> = BTreeSet<K>;
+use std::collections::HashMap;
+use value::{Schema, Constraints, declare_field};
+use filter::Filter;
+use query::Query;
+use aggregate::Aggregate;
+use index::IndexKind;
+use alter::Alteration;
+use backend::BackendKind;
+use db_module::PAGE_ITEMS;
 
-use response::Response;
+use response::{Response, Status};
 use lib::write_into_file;
+use error::DbError;
+use http::{HttpRequest, is_http_request_line, read_http_request};
+use tokenizer::{ParseError, Token, TokenKind, tokenize, parse_pairs, needs_more};
+
+// a quoted value spread over lines is given up beyond this size
+pub const MAX_PARAMETER_BYTES: usize = 1 << 20;
 
 // defind request structure
 pub struct Request{
-    stream: TcpStream,
     command: String,
     request_info: String,
     request_collection: String,
     request_parameter: Vec<String>,
+    parameter_line: Vec<usize>,             // line in the request where each parameter starts
+    is_http: bool,                          // the command came in as an HTTP request
+    http: Option<HttpRequest>,
+    error: Option<DbError>,                 // request could not be parsed into a command
 }
 
 
 impl Request{
-    pub fn new(mut stream:TcpStream)->Self{
-        let mut http_reader = BufReader::new(stream);
+    // read the next command from a connection, None when the client has closed it
+    pub fn read_from<R: BufRead>(http_reader: &mut R)->Option<Self>{
         let mut log_request_info = String::new();
-        
+
         let mut header = String::new();
-        let mut http_info = Vec::<&str>::new();
-        // parse query type and objective function from first line
-        match http_reader.read_line(&mut header).unwrap()>0{
-            true=> {
-                http_info= header.split_whitespace().collect();
-            },
-            false =>{
-                println!("Request Error");
-            },
+        // parse query type and objective function from first line, skip blank lines between commands
+        loop {
+            header.clear();
+            match http_reader.read_line(&mut header){
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {
+                    if !header.trim().is_empty(){
+                        break;
+                    }
+                },
+            }
         }
+        let http_info: Vec<&str> = header.split_whitespace().collect();
 
         log_request_info.push_str(&header);   // record info for log
 
+        if is_http_request_line(&header) {
+            let mut request = Request{
+                command: String::new(),
+                request_info: String::new(),
+                request_collection: String::new(),
+                request_parameter: Vec::new(),
+                parameter_line: Vec::new(),
+                is_http: true,
+                http: None,
+                error: None,
+            };
+            match read_http_request(&header, http_reader, &mut log_request_info) {
+                Ok(http_request) => {
+                    request.command = http_request.command.to_owned();
+                    request.request_collection = http_request.collection.clone();
+                    request.http = Some(http_request);
+                },
+                Err(err) => request.error = Some(err),
+            }
+            request.request_info = log_request_info;
+            return Some(request);
+        }
+
         let mut parameter = Vec::new();
-        let mut read_stream_info = String::new();       
-        while http_reader.read_line(&mut read_stream_info).unwrap()>0{
-            if read_stream_info == "\r\n".to_owned(){   // since TcpStream is a long connection, have to jump out when 
-                break;                                  // read to the last line \r\n , or it will stall the network connection
+        let mut parameter_line = Vec::new();
+        let mut line_number = 1;
+        let mut pending = String::new();        // parameter with a quoted value spread over several lines
+        let mut error = None;
+        let mut read_stream_info = String::new();
+        loop {
+            read_stream_info.clear();
+            match http_reader.read_line(&mut read_stream_info){
+                Ok(0) => break,                 // client closed without the last empty line
+                Err(_) => return None,          // idle for too long, half a command is not run
+                Ok(_) => (),
+            }
+            line_number += 1;
+            if read_stream_info == "\r\n".to_owned() || read_stream_info == "\n".to_owned(){
+                break;                                  // an empty line ends the command, the connection stays open
+            }
+            if error.is_some() {
+                continue;                               // the rest of an oversized command is read and dropped
             }
             let record = read_stream_info.to_owned();
             log_request_info.push_str(&record);
-            // remove \r, \n with trim()
-            parameter.push(read_stream_info.clone().trim().to_owned());
-            read_stream_info.clear();
+            if pending.is_empty() {
+                parameter_line.push(line_number);
+            }
+            // remove \r, \n with trim_end_matches(), a newline inside quotes stays part of the value
+            pending.push_str(read_stream_info.trim_end_matches(|c| c == '\r' || c == '\n'));
+            if needs_more(&pending) {
+                if pending.len() > MAX_PARAMETER_BYTES {
+                    error = Some(unterminated(&pending, parameter_line[parameter_line.len() - 1]));
+                    pending.clear();
+                    continue;
+                }
+                pending.push('\n');
+                continue;
+            }
+            parameter.push(pending.trim().to_owned());
+            pending.clear();
+        }
+        if !pending.is_empty() {
+            error = Some(unterminated(&pending, parameter_line[parameter_line.len() - 1]));
         }
 
         let command = http_info[0].to_owned();
-        let col_name = http_info[1].to_owned();
-
-        stream = http_reader.into_inner();
+        let col_name = match http_info.get(1) {
+            Some(name) => name.to_string(),
+            None => String::new(),      // SHOWDB and LISTCOLLECTIONS have no collection
+        };
 
-        Request{
-            stream: stream,
+        Some(Request{
             command: command,
             request_info: log_request_info,
             request_collection: col_name,
             request_parameter: parameter,
-        }
+            parameter_line: parameter_line,
+            is_http: false,
+            http: None,
+            error: error,
+        })
     }
 
     /**exposed public function**/
@@ -127,33 +330,180 @@ impl Request{
         }
     }
 
-    pub fn get_parameters(&self) -> Set<String>{
-        let parameter_set: Set<String> = self.request_parameter.iter().cloned().collect();
-        parameter_set
+    pub fn get_error(&self) -> Option<DbError>{
+        self.error.clone()
+    }
+
+    // HTTP/1.0 and "Connection: close" requests end the connection after the response,
+    // so does an HTTP request that could not be read completely
+    pub fn keep_alive(&self) -> bool{
+        match self.http {
+            Some(ref http) => http.keep_alive,
+            None => !self.is_http,
+        }
+    }
+
+    // one field name per parameter line
+    // one "name [type] [primary key] [unique]" per line, and at most one BACKEND line
+    pub fn get_parameters(&self) -> Result<(Schema, Constraints, BackendKind), ParseError>{
+        if let Some(ref http) = self.http {
+            return Ok((http.fields.clone(), http.constraints.clone(), http.backend));
+        }
+        let mut parameter_set = Schema::new();
+        let mut constraints = Constraints::new();
+        let mut backend = None;
+        for (index, line) in self.request_parameter.iter().enumerate(){
+            let tokens = try!(tokenize(line, self.parameter_line[index]));
+            if tokens.is_empty() {
+                continue;
+            }
+            if let Token { kind: TokenKind::Word(ref word), quoted: false, line, column } = tokens[0] {
+                if word == "BACKEND" {
+                    let kind = match (tokens.len(), tokens.get(1).map(|t| &t.kind)) {
+                        (2, Some(&TokenKind::Word(ref name))) => BackendKind::from_name(name),
+                        _ => None,
+                    };
+                    match (kind, backend) {
+                        (Some(kind), None) => backend = Some(kind),
+                        (Some(_), Some(_)) => return Err(ParseError::new(line, column, "BACKEND is given twice")),
+                        (None, _) => return Err(ParseError::new(line, column, "expected BACKEND sequential, parallel or indexed")),
+                    }
+                    continue;
+                }
+            }
+            let mut words = Vec::new();
+            for token in tokens.iter() {
+                match token.kind {
+                    TokenKind::Word(ref word) => words.push(word.clone()),
+                    _ => return Err(ParseError::new(token.line, token.column, "expected a field name")),
+                }
+            }
+            if let Err((position, message)) = declare_field(&words, &mut parameter_set, &mut constraints) {
+                let token = &tokens[position];
+                return Err(ParseError::new(token.line, token.column, &message));
+            }
+        }
+        Ok((parameter_set, constraints, backend.unwrap_or(BackendKind::Indexed)))
     }
 
     // get object and desire for update
-    pub fn get_object_desired(&self) -> (HashMap<String, String>, HashMap<String, String>){
-        let obj_attributes:Vec<&str> = self.request_parameter[0].trim().split(";").collect();
-        let desired_attributes:Vec<&str> = self.request_parameter[1].trim().split(";").collect();
-        let mut obj_pair = HashMap::<String, String>::new();
-        for pair in obj_attributes.iter().clone(){
-            if pair.is_empty(){
-                break;
+    pub fn get_object_desired(&self) -> Result<(Filter<String>, HashMap<String, String>), ParseError>{
+        if let Some(ref http) = self.http {
+            return Ok((try!(http.query_filter()), http.entry.clone()));
+        }
+        let if_line = try!(self.precondition()).map(|(index, _)| index);
+        let lines: Vec<usize> = (0..self.request_parameter.len()).filter(|index| Some(*index) != if_line).collect();
+        if lines.len() != 2 {
+            return Err(ParseError::new(1, 1, "UPDATE expects a condition line and an update line"));
+        }
+        let object = try!(Filter::parse(&self.request_parameter[lines[0]], self.parameter_line[lines[0]]));
+        let desire_pair = try!(self.pairs_of(lines[1]));
+        Ok((object, desire_pair))
+    }
+
+    // the version of the IF VERSION line of UPDATE and DELETE, or of the If-Match header
+    pub fn get_precondition(&self) -> Result<Option<u64>, ParseError>{
+        if let Some(ref http) = self.http {
+            return Ok(http.if_version);
+        }
+        Ok(try!(self.precondition()).map(|(_, version)| version))
+    }
+
+    // field and kind of CREATEINDEX and DROPINDEX
+    pub fn get_index(&self) -> Result<(String, IndexKind), ParseError>{
+        if self.request_parameter.len() != 1 {
+            return Err(ParseError::new(1, 1, "expected one line with a field name"));
+        }
+        let tokens = try!(tokenize(&self.request_parameter[0], self.parameter_line[0]));
+        let mut words = Vec::new();
+        for token in tokens.iter() {
+            match token.kind {
+                TokenKind::Word(ref word) => words.push(word.clone()),
+                _ => return Err(ParseError::new(token.line, token.column, "expected a field name")),
+            }
+        }
+        match words.len() {
+            1 => Ok((words[0].clone(), IndexKind::BTree)),
+            2 => match IndexKind::from_name(&words[1]) {
+                Some(kind) => Ok((words[0].clone(), kind)),
+                None => Err(ParseError::new(tokens[1].line, tokens[1].column, &format!("unknown index kind {}", words[1]))),
+            },
+            _ => {
+                let (line, column) = tokens.get(2).map(|t| (t.line, t.column)).unwrap_or((self.parameter_line[0], 1));
+                Err(ParseError::new(line, column, "expected a field name and an index kind"))
+            },
+        }
+    }
+
+    // one ADD, DROP or RENAME per line, in the order they are applied
+    pub fn get_alterations(&self) -> Result<Vec<Alteration>, ParseError>{
+        let mut alterations = Vec::new();
+        for index in 0..self.request_parameter.len(){
+            if !self.request_parameter[index].trim().is_empty() {
+                alterations.push(try!(Alteration::parse(&self.request_parameter[index], self.parameter_line[index])));
             }
-            let key_value:Vec<&str> = pair.split_whitespace().collect();
-            obj_pair.insert(key_value[0].to_owned(), key_value[1].to_owned());          
         }
+        if alterations.is_empty() {
+            return Err(ParseError::new(1, 1, "ALTER expects ADD, DROP or RENAME lines"));
+        }
+        Ok(alterations)
+    }
 
-        let mut desire_pair = HashMap::<String, String>::new();
-        for pair in desired_attributes.iter().clone(){
-            if pair.is_empty(){
-                break;
+    // conditions and options of GET, a line starting with FIELDS, SORT, LIMIT or OFFSET is an option
+    pub fn get_query(&self) -> Result<(Filter<String>, Query), ParseError>{
+        if let Some(ref http) = self.http {
+            return Ok((try!(http.query_filter()), try!(http.query_options())));
+        }
+        let mut query = Query::new();
+        let filter = try!(self.filter_and_options(Query::is_keyword, |keyword, words| query.set(keyword, words)));
+        Ok((filter, query))
+    }
+
+    // conditions and functions of AGGREGATE, a line starting with a function or GROUP BY is not a condition
+    pub fn get_aggregate(&self) -> Result<(Filter<String>, Aggregate), ParseError>{
+        let mut aggregate = Aggregate::new();
+        let filter = try!(self.filter_and_options(Aggregate::is_keyword, |keyword, words| aggregate.set(keyword, words)));
+        Ok((filter, aggregate))
+    }
+
+    // LIMIT and OFFSET lines of SHOWDB, the first PAGE_ITEMS items when left out
+    pub fn get_page(&self) -> Result<(usize, usize), ParseError>{
+        let mut query = Query::new();
+        for index in 0..self.request_parameter.len(){
+            let tokens = try!(tokenize(&self.request_parameter[index], self.parameter_line[index]));
+            let mut words = Vec::new();
+            for token in tokens.iter() {
+                match token.kind {
+                    TokenKind::Word(ref word) if words.len() > 0 || (!token.quoted && (word == "LIMIT" || word == "OFFSET")) => words.push(word.clone()),
+                    _ => return Err(ParseError::new(token.line, token.column, "expected LIMIT or OFFSET and a number")),
+                }
+            }
+            if words.is_empty() {
+                continue;
+            }
+            if let Err(message) = query.set(&words[0], &words[1..]) {
+                return Err(ParseError::new(tokens[0].line, tokens[0].column, &message));
+            }
+            if query.limit == Some(0) {
+                return Err(ParseError::new(tokens[0].line, tokens[0].column, "LIMIT of SHOWDB must be at least 1"));
+            }
+        }
+        Ok((query.offset, query.limit.unwrap_or(PAGE_ITEMS)))
+    }
+
+    // conditions of DELETE, the conditions of every line must hold
+    pub fn get_filter(&self) -> Result<Filter<String>, ParseError>{
+        if let Some(ref http) = self.http {
+            return http.query_filter();
+        }
+        let if_line = try!(self.precondition()).map(|(index, _)| index);
+        let mut filter = Filter::new();
+        for index in 0..self.request_parameter.len(){
+            if Some(index) != if_line {
+                filter = filter.and(try!(Filter::parse(&self.request_parameter[index], self.parameter_line[index])));
             }
-            let key_value:Vec<&str> = pair.split_whitespace().collect();
-            desire_pair.insert(key_value[0].to_owned(), key_value[1].to_owned());           
         }
-        (obj_pair, desire_pair)
+        Ok(filter)
     }
 
     pub fn get_collection(&self) -> String{
@@ -164,17 +514,228 @@ impl Request{
         self.command.clone()
     }
 
-    pub fn get_attributes(&self) -> HashMap<String, String>{
+    pub fn get_attributes(&self) -> Result<HashMap<String, String>, ParseError>{
+        if let Some(ref http) = self.http {
+            return Ok(match http.command {
+                "APPEND" => http.entry.clone(),
+                _ => http.query.clone(),
+            });
+        }
         let mut key_value_pair = HashMap::<String, String>::new();
-        for pair in self.request_parameter.iter().clone(){
-            let key_value:Vec<&str> = pair.split_whitespace().collect();
-            key_value_pair.insert(key_value[0].to_owned(), key_value[1].to_owned());
+        for index in 0..self.request_parameter.len(){
+            key_value_pair.extend(try!(self.pairs_of(index)));
         }
-        key_value_pair
+        Ok(key_value_pair)
+    }
+
+    /**private function**/
+    // conditions of the lines, a line whose first word is an unquoted keyword is handed to set instead
+    fn filter_and_options<F>(&self, is_keyword: fn(&str) -> bool, mut set: F) -> Result<Filter<String>, ParseError>
+        where F: FnMut(&str, &[String]) -> Result<(), String>
+    {
+        let mut filter = Filter::new();
+        for index in 0..self.request_parameter.len(){
+            let tokens = try!(tokenize(&self.request_parameter[index], self.parameter_line[index]));
+            let keyword = match tokens.first() {
+                Some(&Token { kind: TokenKind::Word(ref word), quoted: false, .. }) if is_keyword(word) => word.clone(),
+                _ => {
+                    filter = filter.and(try!(Filter::parse(&self.request_parameter[index], self.parameter_line[index])));
+                    continue;
+                },
+            };
+            let mut words = Vec::new();
+            for token in tokens[1..].iter() {
+                match token.kind {
+                    TokenKind::Word(ref word) => words.push(word.clone()),
+                    _ => return Err(ParseError::new(token.line, token.column, "expected a field name or a number")),
+                }
+            }
+            if let Err(message) = set(&keyword, &words) {
+                return Err(ParseError::new(tokens[0].line, tokens[0].column, &message));
+            }
+        }
+        Ok(filter)
+    }
+
+    // the line starting with an unquoted IF and the version it expects
+    fn precondition(&self) -> Result<Option<(usize, u64)>, ParseError>{
+        for index in 0..self.request_parameter.len(){
+            let tokens = try!(tokenize(&self.request_parameter[index], self.parameter_line[index]));
+            match tokens.first() {
+                Some(&Token { kind: TokenKind::Word(ref word), quoted: false, .. }) if word == "IF" => (),
+                _ => continue,
+            }
+            let version = match (tokens.len(), tokens.get(1).map(|t| &t.kind), tokens.get(2).map(|t| &t.kind)) {
+                (3, Some(&TokenKind::Word(ref keyword)), Some(&TokenKind::Word(ref number))) if keyword == "VERSION" => number.parse::<u64>().ok(),
+                _ => None,
+            };
+            return match version {
+                Some(version) => Ok(Some((index, version))),
+                None => Err(ParseError::new(tokens[0].line, tokens[0].column, "expected IF VERSION and a number")),
+            };
+        }
+        Ok(None)
+    }
+
+    // key-value pairs of one parameter, pairs are separated by ';'
+    fn pairs_of(&self, index: usize) -> Result<HashMap<String, String>, ParseError>{
+        let pairs = try!(parse_pairs(&self.request_parameter[index], self.parameter_line[index]));
+        Ok(pairs.into_iter().collect())
     }
 
     // create a response from here 
-    pub fn form_response(&self, content:Option<String>)->Response{
-        Response::new(content, &self.stream)
+    pub fn form_response<'a>(&self, status:Status, content:Option<String>, stream:&'a TcpStream)->Response<'a>{
+        let http = match self.is_http {
+            true => Some(self.keep_alive()),
+            false => None,
+        };
+        Response::new(status, content, stream, http)
+    }
+}
+
+// a parameter whose quote is still open, answered with the position of the quote
+fn unterminated(pending: &str, first_line: usize) -> DbError {
+    match tokenize(pending, first_line) {
+        Err(e) => DbError::Parse(e),
+        Ok(_) => DbError::Parse(ParseError::new(first_line, 1, "unterminated quoted string")),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod request_tests {
+    use super::{Request, MAX_PARAMETER_BYTES};
+    use std::io::Cursor;
+    use value::FieldType;
+    use filter::Filter;
+    use backend::BackendKind;
+    use query::Order;
+    use db_module::PAGE_ITEMS;
+    use error::DbError;
+    use tokenizer::ParseError;
+
+    #[test]
+    fn quoted_parameter_test() {
+        let raw = "APPEND student\r\nname \"Ada Lovelace\"\r\nbio \"first line\r\nlast line\"\r\n\r\nGET student\r\nname Ada\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+
+        let append = Request::read_from(&mut reader).unwrap();
+        let attributes = append.get_attributes().unwrap();
+        assert_eq!(attributes.get("name"), Some(&"Ada Lovelace".to_owned()));
+        assert_eq!(attributes.get("bio"), Some(&"first line\nlast line".to_owned()));
+
+        let get = Request::read_from(&mut reader).unwrap();
+        assert_eq!(get.get_command(), "GET");
+        assert!(Request::read_from(&mut reader).is_none());
+
+        // an open quote ends with the command, the next command is still read
+        let raw = "APPEND student\r\nname O'Brien\r\n\r\nGET student\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        assert_eq!(Request::read_from(&mut reader).unwrap().get_error(),
+                   Some(DbError::Parse(ParseError::new(2, 7, "unterminated quoted string"))));
+        assert_eq!(Request::read_from(&mut reader).unwrap().get_command(), "GET");
+
+        let raw = format!("APPEND student\r\nbio \"{}\r\n{}\r\n\r\nGET student\r\n\r\n", "a".repeat(MAX_PARAMETER_BYTES), "b");
+        let mut reader = Cursor::new(raw.into_bytes());
+        assert!(Request::read_from(&mut reader).unwrap().get_error().is_some());
+        assert_eq!(Request::read_from(&mut reader).unwrap().get_command(), "GET");
+    }
+
+    #[test]
+    fn malformed_parameter_test() {
+        let raw = "APPEND student\r\nid 1\r\nname\r\n\r\nUPDATE student\r\nid 1\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+
+        let err = Request::read_from(&mut reader).unwrap().get_attributes().unwrap_err();
+        assert_eq!((err.line, err.column), (3, 1));
+        assert!(Request::read_from(&mut reader).unwrap().get_object_desired().is_err());
+    }
+
+    #[test]
+    fn typed_fields_test() {
+        let raw = "PUTLIST student\r\nname\r\nage int\r\n\r\nPUTLIST teacher\r\nage number\r\nsince yesterday\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+
+        let (fields, _, backend) = Request::read_from(&mut reader).unwrap().get_parameters().unwrap();
+        assert_eq!(fields.get("name"), Some(&FieldType::Str));
+        assert_eq!(fields.get("age"), Some(&FieldType::Int));
+        assert_eq!(backend, BackendKind::Indexed);
+
+        let err = Request::read_from(&mut reader).unwrap().get_parameters().unwrap_err();
+        assert_eq!((err.line, err.column), (3, 7));
+        assert_eq!(err.message, "unknown field type yesterday");
+    }
+
+    #[test]
+    fn query_options_test() {
+        let raw = "GET student\r\nage > 20\r\nSORT age desc\r\nLIMIT 2\r\n\"LIMIT\" 3\r\n\r\nGET student\r\nOFFSET -1\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+
+        let (filter, query) = Request::read_from(&mut reader).unwrap().get_query().unwrap();
+        // a quoted keyword is a field name
+        assert_eq!(filter, Filter::parse("age > 20; LIMIT 3", 1).unwrap());
+        assert_eq!(query.sort, vec![("age".to_owned(), Order::Descending)]);
+        assert_eq!(query.limit, Some(2));
+
+        let err = Request::read_from(&mut reader).unwrap().get_query().unwrap_err();
+        assert_eq!((err.line, err.column), (2, 1));
+        assert_eq!(err.message, "OFFSET expects a number, not -1");
+    }
+
+    #[test]
+    fn precondition_test() {
+        let raw = "UPDATE student\r\n_id 3\r\nIF VERSION 2\r\nage 25\r\n\r\nDELETE student\r\n_id 3\r\n\r\nDELETE student\r\nIF VERSION two\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+
+        let update = Request::read_from(&mut reader).unwrap();
+        let (object, desired) = update.get_object_desired().unwrap();
+        assert_eq!(object, Filter::parse("_id 3", 2).unwrap());
+        assert_eq!(desired.get("age"), Some(&"25".to_owned()));
+        assert_eq!(update.get_precondition(), Ok(Some(2)));
+
+        assert_eq!(Request::read_from(&mut reader).unwrap().get_precondition(), Ok(None));
+        let err = Request::read_from(&mut reader).unwrap().get_precondition().unwrap_err();
+        assert_eq!(err.message, "expected IF VERSION and a number");
+    }
+
+    #[test]
+    fn backend_test() {
+        let raw = "PUTLIST student\r\nname\r\nBACKEND parallel\r\n\r\nPUTLIST teacher\r\nname\r\nBACKEND linked\r\n\r\nPUTLIST staff\r\n\"BACKEND\" int\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+
+        let (fields, _, backend) = Request::read_from(&mut reader).unwrap().get_parameters().unwrap();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(backend, BackendKind::Parallel);
+
+        let err = Request::read_from(&mut reader).unwrap().get_parameters().unwrap_err();
+        assert_eq!((err.line, err.column), (3, 1));
+        assert_eq!(err.message, "expected BACKEND sequential, parallel or indexed");
+
+        // a quoted keyword is a field name
+        let (fields, _, _) = Request::read_from(&mut reader).unwrap().get_parameters().unwrap();
+        assert_eq!(fields.get("BACKEND"), Some(&FieldType::Int));
+    }
+
+    #[test]
+    fn page_test() {
+        let raw = "SHOWDB
+
+SHOWDB
+OFFSET 20
+LIMIT 10
+
+SHOWDB
+LIMIT 0
+
+SHOWDB
+name Ada
+
+";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+
+        assert_eq!(Request::read_from(&mut reader).unwrap().get_page(), Ok((0, PAGE_ITEMS)));
+        assert_eq!(Request::read_from(&mut reader).unwrap().get_page(), Ok((20, 10)));
+        assert!(Request::read_from(&mut reader).unwrap().get_page().is_err());
+        let err = Request::read_from(&mut reader).unwrap().get_page().unwrap_err();
+        assert_eq!((err.line, err.message.as_ref()), (2, "expected LIMIT or OFFSET and a number"));
+    }
+}
@@ -1,36 +1,199 @@
 use std::net::TcpStream;
+use std::io;
 use std::io::prelude::*;
 use std::sync::{Arc,Mutex};
 use std::fs::OpenOptions;
+use std::collections::BTreeMap;
+use rustc_serialize::Encodable;
+use rustc_serialize::json::{self, Json};
 use lib::write_into_file;
+use error::DbError;
+
+// outcome of a command, sent as the status line of HTTP responses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Ok,
+    Created,
+    Accepted,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    LengthRequired,
+    PreconditionFailed,
+    PayloadTooLarge,
+    InternalServerError,
+    ServiceUnavailable,
+}
+
+impl Status {
+    pub fn code(&self) -> u16 {
+        match *self {
+            Status::Ok => 200,
+            Status::Created => 201,
+            Status::Accepted => 202,
+            Status::BadRequest => 400,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::Conflict => 409,
+            Status::LengthRequired => 411,
+            Status::PreconditionFailed => 412,
+            Status::PayloadTooLarge => 413,
+            Status::InternalServerError => 500,
+            Status::ServiceUnavailable => 503,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            Status::Ok => "OK",
+            Status::Created => "Created",
+            Status::Accepted => "Accepted",
+            Status::BadRequest => "Bad Request",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::Conflict => "Conflict",
+            Status::LengthRequired => "Length Required",
+            Status::PreconditionFailed => "Precondition Failed",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.code() < 400
+    }
+
+    // the error code of an answer with this status, for errors without a more specific one
+    pub fn error_code(&self) -> &'static str {
+        match *self {
+            Status::Ok | Status::Created | Status::Accepted => "none",
+            Status::BadRequest => "bad_request",
+            Status::NotFound => "not_found",
+            Status::MethodNotAllowed => "method_not_allowed",
+            Status::Conflict => "conflict",
+            Status::LengthRequired => "length_required",
+            Status::PreconditionFailed => "precondition_failed",
+            Status::PayloadTooLarge => "payload_too_large",
+            Status::InternalServerError => "internal_error",
+            Status::ServiceUnavailable => "server_busy",
+        }
+    }
+}
+
+// the body of every answer, in both protocols:
+//      {"status": "ok" or "error", "code": error code or null, "message": text,
+//       "data": result or null, "affected": number of items written or null}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub status: Status,
+    pub code: Option<&'static str>,
+    pub message: String,
+    pub data: Option<String>,       // encoded already
+    pub affected: Option<u64>,
+}
+
+impl Envelope {
+    pub fn ok(status: Status, message: &str) -> Self {
+        Envelope {
+            status: status,
+            code: None,
+            message: message.to_owned(),
+            data: None,
+            affected: None,
+        }
+    }
+
+    // a failed command, with the status and the code of the kind of error
+    pub fn failure(e: &DbError) -> Self {
+        let envelope = Envelope {
+            status: e.status(),
+            code: Some(e.code()),
+            message: e.to_string(),
+            data: None,
+            affected: None,
+        };
+        match *e {
+            // a request that could not be read, the data tells where
+            DbError::Parse(ref parsed) => {
+                let mut position = BTreeMap::new();
+                position.insert("line".to_owned(), Json::U64(parsed.line as u64));
+                position.insert("column".to_owned(), Json::U64(parsed.column as u64));
+                envelope.with_data(&Json::Object(position))
+            },
+            _ => envelope,
+        }
+    }
+
+    pub fn with_data<T: Encodable>(self, data: &T) -> Self {
+        self.with_encoded(json::encode(data).unwrap())
+    }
+
+    // data that is JSON text already, e.g. a stored collection
+    pub fn with_encoded(mut self, data: String) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn with_affected(mut self, affected: u64) -> Self {
+        self.affected = Some(affected);
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        let status = if self.status.is_success() { "ok" } else { "error" };
+        format!("{{\"status\":{},\"code\":{},\"message\":{},\"data\":{},\"affected\":{}}}",
+                json::encode(&status).unwrap(), json::encode(&self.code).unwrap(), json::encode(&self.message).unwrap(),
+                self.data.as_ref().map_or("null", |data| data), json::encode(&self.affected).unwrap())
+    }
+}
 
 // define response structure to send back to client
 pub struct Response<'a>{
+    status: Status,
     content: Option<String>,
     stream: &'a TcpStream,      // since repsonse and request share same TcpStream, lifetime should be set here
+    http: Option<bool>,         // answer with an HTTP/1.1 message, the flag tells whether to keep the connection
 }
 
 impl <'a>Response<'a>{
-    pub fn new(content:Option<String>, stream:&'a TcpStream)->Self{    
+    pub fn new(status:Status, content:Option<String>, stream:&'a TcpStream, http:Option<bool>)->Self{    
         Response{
+            status: status,
             content: content,
             stream: stream,
+            http: http,
         }
     }
 
     /**exposed public function**/
     // send response info through TcpStream
+    // prefixed with the body length so a client can read pipelined responses apart
     pub fn write_response(&mut self){   
-        let response_content = match self.content{
-            Some(ref content) => format!("\r\n{}\r\n",content),
+        self.send().unwrap();
+    }
+
+    // write_response for a stream that may already be gone
+    pub fn send(&mut self) -> io::Result<()>{
+        let body = match self.content{
+            Some(ref content) => content.clone(),
             None => "".to_owned(),
         };
-        self.write_to_stream(&response_content);
+        let response_content = match self.http {
+            Some(keep_alive) => {
+                format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}",
+                        self.status.code(), self.status.reason(), body.len(),
+                        if keep_alive { "keep-alive" } else { "close" }, body)
+            },
+            None => format!("{}\r\n{}\r\n",body.len(),body),
+        };
+        self.write_to_stream(&response_content)
     }
 
     // write reponse status and time into log
     pub fn record_log(&mut self, time: &str, write_log_file: &Arc<Mutex<OpenOptions>>){
-        let mut format_log = "Response Time: ".to_owned()+time+"\r\n";
+        let mut format_log = "Response Time: ".to_owned()+time+"\r\n"+"Status: "+&self.status.code().to_string()+"\r\n";
         if let Some(ref cont) = self.content{
             format_log = format_log.to_owned() + cont + "\r\n\r\n";
         }
@@ -42,8 +205,40 @@ impl <'a>Response<'a>{
 
     /**private function**/
     // write reponse to TcpStream
-    fn write_to_stream(&mut self, content:&str){
+    fn write_to_stream(&mut self, content:&str) -> io::Result<()>{
         let response_write_content = content.to_owned();
-        self.stream.write(response_write_content.as_bytes()).unwrap();
+        self.stream.write_all(response_write_content.as_bytes())
+    }
+}
+
+
+#[cfg(test)]
+mod response_tests {
+    use super::{Envelope, Status};
+    use rustc_serialize::json::Json;
+    use tokenizer::ParseError;
+    use error::DbError;
+
+    #[test]
+    fn envelope_test() {
+        let created = Envelope::ok(Status::Created, "Item appended").with_data(&vec![1, 2]).with_affected(1);
+        let stored = Json::from_str(&created.to_json()).unwrap();
+        assert_eq!(stored.find("status"), Some(&Json::String("ok".to_owned())));
+        assert_eq!(stored.find("code"), Some(&Json::Null));
+        assert_eq!(stored.find("data"), Some(&Json::Array(vec![Json::U64(1), Json::U64(2)])));
+        assert_eq!(stored.find("affected"), Some(&Json::U64(1)));
+
+        let missing = Envelope::failure(&DbError::UnknownCollection("student".to_owned()));
+        assert_eq!(missing.status, Status::NotFound);
+        let missing = Json::from_str(&missing.to_json()).unwrap();
+        assert_eq!(missing.find("status"), Some(&Json::String("error".to_owned())));
+        assert_eq!(missing.find("code"), Some(&Json::String("unknown_collection".to_owned())));
+        assert_eq!(missing.find("message"), Some(&Json::String("Collection student does not exist".to_owned())));
+        assert_eq!(missing.find("data"), Some(&Json::Null));
+        assert_eq!(missing.find("affected"), Some(&Json::Null));
+
+        let unreadable = Json::from_str(&Envelope::failure(&ParseError::new(2, 5, "expected a value").into()).to_json()).unwrap();
+        assert_eq!(unreadable.find("code"), Some(&Json::String("parse_error".to_owned())));
+        assert_eq!(unreadable.find_path(&["data", "column"]), Some(&Json::U64(5)));
     }
 }
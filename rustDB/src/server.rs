@@ -0,0 +1,278 @@
+#[doc="
+  Limits of the TCP server.
+
+  Connections are served by a fixed pool of worker threads (see pool.rs), a
+  worker stays with its connection until the client closes it or sends
+  nothing for idle-timeout seconds. At most max_connections connections are
+  admitted at a time, the ones beyond the number of workers wait in the
+  queue of the pool for a worker to become free. One that waited longer
+  than queue-timeout seconds is answered like a rejected one when a worker
+  takes it. A connection that arrives while max_connections are admitted
+  is handled by the backlog policy:
+
+        reject      answered with 503 Service Unavailable and \"Server busy\"
+                    in the protocol of its first request, then closed (default)
+        wait        not accepted until an admitted connection ends, so it
+                    waits in the listen backlog of the operating system
+
+  The limits are given on the command line:
+
+        rustDB [--port N] [--workers N] [--max-connections N] [--backlog reject|wait]
+               [--idle-timeout SECONDS] [--queue-timeout SECONDS]
+
+  Rejected connections are answered on a thread of their own, so the accept
+  loop never waits for a client; beyond TURN_AWAY_QUEUE of them waiting for
+  their answer a connection is closed without one.
+"]
+use std::io::prelude::*;
+use std::net::{TcpStream, Shutdown};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+use response::{Response, Status, Envelope};
+use http::is_http_request_line;
+use error::DbError;
+
+// how long a turned away client is given to send its first request
+const BUSY_TIMEOUT_MS: u64 = 200;
+// turned away connections waiting for their answer
+const TURN_AWAY_QUEUE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BacklogPolicy {
+    Reject,
+    Wait,
+}
+
+impl BacklogPolicy {
+    pub fn from_name(name: &str) -> Option<BacklogPolicy> {
+        match name.to_lowercase().as_ref() {
+            "reject" => Some(BacklogPolicy::Reject),
+            "wait" => Some(BacklogPolicy::Wait),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub port: u16,
+    pub workers: usize,             // threads serving connections
+    pub max_connections: usize,     // served and waiting for a worker
+    pub backlog: BacklogPolicy,
+    pub idle_timeout: Duration,     // a served connection that sends nothing for this long is closed
+    pub queue_timeout: Duration,    // a connection that waited this long for a worker is turned away
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        ServerConfig {
+            port: 8080,
+            workers: 8,
+            max_connections: 64,
+            backlog: BacklogPolicy::Reject,
+            idle_timeout: Duration::from_secs(30),
+            queue_timeout: Duration::from_secs(5),
+        }
+    }
+
+    // the options after the program name, the defaults for the ones left out
+    pub fn from_args<I: Iterator<Item=String>>(mut args: I) -> Result<ServerConfig, String> {
+        let mut config = ServerConfig::new();
+        while let Some(option) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(format!("{} expects a value", option)),
+            };
+            match option.as_ref() {
+                "--port" => config.port = try!(value.parse().map_err(|_| format!("--port expects a port number, not {}", value))),
+                "--workers" => config.workers = try!(number_of(&option, &value)),
+                "--max-connections" => config.max_connections = try!(number_of(&option, &value)),
+                "--idle-timeout" => config.idle_timeout = Duration::from_secs(try!(number_of(&option, &value)) as u64),
+                "--queue-timeout" => config.queue_timeout = Duration::from_secs(try!(number_of(&option, &value)) as u64),
+                "--backlog" => config.backlog = match BacklogPolicy::from_name(&value) {
+                    Some(policy) => policy,
+                    None => return Err(format!("--backlog expects reject or wait, not {}", value)),
+                },
+                _ => return Err(format!("unknown option {}", option)),
+            }
+        }
+        if config.max_connections < config.workers {
+            return Err("--max-connections must be at least the number of workers".to_owned());
+        }
+        Ok(config)
+    }
+}
+
+fn number_of(option: &str, value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(number) if number > 0 => Ok(number),
+        _ => Err(format!("{} expects a positive number, not {}", option, value)),
+    }
+}
+
+// counts the admitted connections
+#[derive(Debug, Clone)]
+pub struct Admission {
+    admitted: Arc<(Mutex<usize>, Condvar)>,
+    limit: usize,
+    policy: BacklogPolicy,
+}
+
+// the place of an admitted connection, given back when dropped
+pub struct Slot {
+    admitted: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Admission {
+    pub fn new(config: &ServerConfig) -> Self {
+        Admission {
+            admitted: Arc::new((Mutex::new(0), Condvar::new())),
+            limit: config.max_connections,
+            policy: config.backlog,
+        }
+    }
+
+    // a slot for a new connection, None when the server is full and the policy rejects it
+    pub fn admit(&self) -> Option<Slot> {
+        let (ref count, ref freed) = *self.admitted;
+        let mut admitted = count.lock().unwrap();
+        while *admitted >= self.limit {
+            match self.policy {
+                BacklogPolicy::Reject => return None,
+                BacklogPolicy::Wait => admitted = freed.wait(admitted).unwrap(),
+            }
+        }
+        *admitted += 1;
+        Some(Slot {
+            admitted: self.admitted.clone(),
+        })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let (ref count, ref freed) = *self.admitted;
+        *count.lock().unwrap() -= 1;
+        freed.notify_one();
+    }
+}
+
+// hands turned away connections to the thread that answers them
+#[derive(Clone)]
+pub struct TurnAway {
+    sender: SyncSender<TcpStream>,
+}
+
+impl TurnAway {
+    pub fn spawn() -> Self {
+        let (sender, receiver) = sync_channel::<TcpStream>(TURN_AWAY_QUEUE);
+        thread::spawn(move || {
+            for stream in receiver.iter() {
+                turn_away(stream);
+            }
+        });
+        TurnAway {
+            sender: sender,
+        }
+    }
+
+    // never blocks, a connection beyond the queue is closed unanswered
+    pub fn send(&self, stream: TcpStream) {
+        let _ = self.sender.try_send(stream);
+    }
+}
+
+// answer a connection the server cannot take with ServerBusy, in the protocol of its first request, and close it
+pub fn turn_away(mut stream: TcpStream) {
+    let timeout = Duration::from_millis(BUSY_TIMEOUT_MS);
+    let _ = stream.set_read_timeout(Some(timeout));
+    let mut first = [0; 512];
+    let read = stream.peek(&mut first).unwrap_or(0);
+    let first_line = String::from_utf8_lossy(&first[..read]).lines().next().unwrap_or("").to_owned();
+    let http = match is_http_request_line(&first_line) {
+        true => Some(false),
+        false => None,
+    };
+    let busy = Envelope::failure(&DbError::ServerBusy).to_json();
+    if Response::new(Status::ServiceUnavailable, Some(busy), &stream, http).send().is_err() {
+        return;
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+    // what the client sent is read, closing with unread data would reset the connection before the answer arrives
+    let deadline = Instant::now() + timeout;
+    let mut rest = [0; 512];
+    while Instant::now() < deadline {
+        match stream.read(&mut rest) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod server_tests {
+    use super::{ServerConfig, BacklogPolicy, Admission, turn_away};
+    use std::io::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn config_test() {
+        let args = |line: &str| line.split_whitespace().map(|arg| arg.to_owned()).collect::<Vec<String>>().into_iter();
+        assert_eq!(ServerConfig::from_args(args("")), Ok(ServerConfig::new()));
+        let config = ServerConfig::from_args(args("--workers 2 --max-connections 3 --backlog wait --port 9000")).unwrap();
+        assert_eq!((config.workers, config.max_connections, config.backlog, config.port), (2, 3, BacklogPolicy::Wait, 9000));
+        let config = ServerConfig::from_args(args("--idle-timeout 10 --queue-timeout 2")).unwrap();
+        assert_eq!((config.idle_timeout, config.queue_timeout), (Duration::from_secs(10), Duration::from_secs(2)));
+        assert!(ServerConfig::from_args(args("--idle-timeout 0")).is_err());
+
+        assert!(ServerConfig::from_args(args("--workers 0")).is_err());
+        assert!(ServerConfig::from_args(args("--workers 4 --max-connections 2")).is_err());
+        assert!(ServerConfig::from_args(args("--backlog drop")).is_err());
+        assert!(ServerConfig::from_args(args("--threads 4")).is_err());
+        assert!(ServerConfig::from_args(args("--workers")).is_err());
+    }
+
+    #[test]
+    fn admission_test() {
+        let mut config = ServerConfig::new();
+        config.max_connections = 2;
+        let admission = Admission::new(&config);
+        let first = admission.admit().unwrap();
+        let _second = admission.admit().unwrap();
+        assert!(admission.admit().is_none());
+        drop(first);
+        let _third = admission.admit().unwrap();
+        assert!(admission.admit().is_none());
+
+        // waits until a connection ends
+        config.backlog = BacklogPolicy::Wait;
+        let admission = Admission::new(&config);
+        let slots = vec![admission.admit().unwrap(), admission.admit().unwrap()];
+        let ending = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(slots);
+        });
+        assert!(admission.admit().is_some());
+        ending.join().unwrap();
+    }
+
+    #[test]
+    fn turn_away_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        for (request, expected) in vec![("GET student\r\n\r\n", "108\r\n{\"status\":\"error\",\"code\":\"server_busy\""),
+                                        ("GET /collections/student HTTP/1.1\r\n\r\n", "HTTP/1.1 503 Service Unavailable\r\n")] {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            turn_away(listener.accept().unwrap().0);
+            let mut answer = String::new();
+            client.read_to_string(&mut answer).unwrap();
+            assert!(answer.starts_with(expected));
+        }
+    }
+}
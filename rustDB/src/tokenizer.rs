@@ -0,0 +1,282 @@
+#[doc="
+  Tokenizer for the parameter lines of the line protocol.
+
+  Words are separated by whitespace, ';' separates key-value pairs.
+  A value may be quoted with \" or ' to keep spaces, semicolons and
+  newlines, and may use the escapes \\n \\t \\r \\\\ \\\" \\' \\; and \\<space>.
+  Quoted and plain parts next to each other form one word: a\"b c\" is \"ab c\".
+  In filters '(' and ')' are tokens of their own too (see tokenize_filter).
+  An unquoted NULL as the whole value of a pair is the null value, a quoted NULL
+  is the text; a value may not hold a NUL character.
+"]
+use std::fmt;
+
+use value::NULL_TEXT;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Word(String),
+    Semicolon,
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub column: usize,
+    pub quoted: bool,       // part of the word was quoted, so it is never read as an operator
+}
+
+// position is the line in the request (the command line is line 1) and the column in that line
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(line: usize, column: usize, message: &str) -> Self {
+        ParseError {
+            line: line,
+            column: column,
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+// split input into words and semicolons, first_line is the line number of the input's first line
+pub fn tokenize(input: &str, first_line: usize) -> Result<Vec<Token>, ParseError> {
+    split_tokens(input, first_line, false)
+}
+
+// like tokenize, unquoted parentheses group the conditions of a filter
+pub fn tokenize_filter(input: &str, first_line: usize) -> Result<Vec<Token>, ParseError> {
+    split_tokens(input, first_line, true)
+}
+
+fn split_tokens(input: &str, first_line: usize, parentheses: bool) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars();
+    let mut line = first_line;
+    let mut column = 0;
+
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    let mut start = (line, column);
+    let mut quote: Option<(char, usize, usize)> = None;      // open quote and where it started
+
+    while let Some(c) = chars.next() {
+        column += 1;
+        let position = (line, column);
+
+        if c == '\\' {
+            let escaped = match chars.next() {
+                Some(e) => e,
+                None => return Err(ParseError::new(line, column, "dangling escape at end of input")),
+            };
+            column += 1;
+            let value = match escaped {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '\\' | '"' | '\'' | ';' | ' ' => escaped,
+                other => return Err(ParseError::new(line, column - 1, &format!("unknown escape sequence \\{}", other))),
+            };
+            if !in_word {
+                in_word = true;
+                start = position;
+            }
+            word.push(value);
+            continue;
+        }
+
+        if c == '\n' {
+            line += 1;
+            column = 0;
+        }
+
+        match quote {
+            Some((open, _, _)) => {
+                if c == open {
+                    quote = None;
+                } else {
+                    word.push(c);
+                }
+            },
+            None => {
+                if c == '"' || c == '\'' {
+                    if !in_word {
+                        in_word = true;
+                        start = position;
+                    }
+                    quote = Some((c, position.0, position.1));
+                    quoted = true;
+                } else if c.is_whitespace() || c == ';' || (parentheses && (c == '(' || c == ')')) {
+                    if in_word {
+                        tokens.push(Token { kind: TokenKind::Word(word.clone()), line: start.0, column: start.1, quoted: quoted });
+                        word.clear();
+                        in_word = false;
+                        quoted = false;
+                    }
+                    let kind = match c {
+                        ';' => Some(TokenKind::Semicolon),
+                        '(' => Some(TokenKind::Open),
+                        ')' => Some(TokenKind::Close),
+                        _ => None,
+                    };
+                    if let Some(kind) = kind {
+                        tokens.push(Token { kind: kind, line: position.0, column: position.1, quoted: false });
+                    }
+                } else {
+                    if !in_word {
+                        in_word = true;
+                        start = position;
+                    }
+                    word.push(c);
+                }
+            },
+        }
+    }
+
+    if let Some((_, quote_line, quote_column)) = quote {
+        return Err(ParseError::new(quote_line, quote_column, "unterminated quoted string"));
+    }
+    if in_word {
+        tokens.push(Token { kind: TokenKind::Word(word), line: start.0, column: start.1, quoted: quoted });
+    }
+    Ok(tokens)
+}
+
+// true while a quote is still open, the reader then keeps the next line in the same parameter
+pub fn needs_more(input: &str) -> bool {
+    let mut quote: Option<char> = None;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            },
+            '"' | '\'' => {
+                quote = match quote {
+                    None => Some(c),
+                    Some(open) if open == c => None,
+                    other => other,
+                };
+            },
+            _ => (),
+        }
+    }
+    quote.is_some()
+}
+
+// "key value; key other value" -> [(key, value), (key, other value)]
+// words after the key are joined with a single space, quote the value to keep it verbatim
+pub fn parse_pairs(input: &str, first_line: usize) -> Result<Vec<(String, String)>, ParseError> {
+    let tokens = try!(tokenize(input, first_line));
+    let mut pairs = Vec::new();
+    for group in tokens.split(|t| t.kind == TokenKind::Semicolon) {
+        if group.is_empty() {
+            continue;
+        }
+        let key = match group[0].kind {
+            TokenKind::Word(ref key) => key.clone(),
+            _ => unreachable!(),
+        };
+        if group.len() < 2 {
+            return Err(ParseError::new(group[0].line, group[0].column, &format!("missing value for key {}", key)));
+        }
+        let words: Vec<String> = group[1..].iter().map(|t| match t.kind {
+            TokenKind::Word(ref w) => w.clone(),
+            _ => String::new(),
+        }).collect();
+        if let Some(position) = words.iter().position(|word| word.contains('\u{0}')) {
+            let token = &group[position + 1];
+            return Err(ParseError::new(token.line, token.column, "a value may not hold a NUL character"));
+        }
+        let value = match group.len() == 2 && !group[1].quoted && words[0] == "NULL" {
+            true => NULL_TEXT.to_owned(),
+            false => words.join(" "),
+        };
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
+
+
+#[cfg(test)]
+mod tokenizer_tests {
+    use super::{tokenize, tokenize_filter, needs_more, parse_pairs, ParseError, Token, TokenKind};
+    use value::NULL_TEXT;
+
+    #[test]
+    fn plain_words_test() {
+        let tokens = tokenize("name  Ada;age 24", 2).unwrap();
+        let kinds: Vec<TokenKind> = tokens.iter().map(|t| t.kind.clone()).collect();
+        assert_eq!(kinds, vec![TokenKind::Word("name".to_owned()), TokenKind::Word("Ada".to_owned()),
+                               TokenKind::Semicolon,
+                               TokenKind::Word("age".to_owned()), TokenKind::Word("24".to_owned())]);
+        assert_eq!((tokens[4].line, tokens[4].column), (2, 15));
+    }
+
+    #[test]
+    fn parentheses_test() {
+        assert_eq!(kinds(tokenize("f(x)", 2).unwrap()), vec![TokenKind::Word("f(x)".to_owned())]);
+        assert_eq!(kinds(tokenize_filter("(a \"(b)\")", 2).unwrap()),
+                   vec![TokenKind::Open, TokenKind::Word("a".to_owned()), TokenKind::Word("(b)".to_owned()), TokenKind::Close]);
+    }
+
+    #[test]
+    fn quoted_value_test() {
+        let pairs = parse_pairs("name \"Ada  Lovelace\"; note 'a;b'; path C:\\\\dir; line \"one\\ntwo\"", 2).unwrap();
+        assert_eq!(pairs, vec![("name".to_owned(), "Ada  Lovelace".to_owned()),
+                               ("note".to_owned(), "a;b".to_owned()),
+                               ("path".to_owned(), "C:\\dir".to_owned()),
+                               ("line".to_owned(), "one\ntwo".to_owned())]);
+    }
+
+    #[test]
+    fn null_value_test() {
+        let pairs = parse_pairs("note NULL; name \"NULL\"; title NULL and void", 2).unwrap();
+        assert_eq!(pairs, vec![("note".to_owned(), NULL_TEXT.to_owned()),
+                               ("name".to_owned(), "NULL".to_owned()),
+                               ("title".to_owned(), "NULL and void".to_owned())]);
+        assert_eq!(parse_pairs("note \"a\u{0}b\"", 2), Err(ParseError::new(2, 6, "a value may not hold a NUL character")));
+    }
+
+    #[test]
+    fn unquoted_words_are_joined_test() {
+        let pairs = parse_pairs("name Ada Lovelace", 2).unwrap();
+        assert_eq!(pairs, vec![("name".to_owned(), "Ada Lovelace".to_owned())]);
+    }
+
+    #[test]
+    fn multi_line_value_test() {
+        let input = "bio \"first\n\nthird\"";
+        assert!(needs_more("bio \"first\n"));
+        assert!(!needs_more(input));
+        let pairs = parse_pairs(input, 3).unwrap();
+        assert_eq!(pairs, vec![("bio".to_owned(), "first\n\nthird".to_owned())]);
+    }
+
+    #[test]
+    fn malformed_input_test() {
+        assert_eq!(parse_pairs("name", 2), Err(ParseError::new(2, 1, "missing value for key name")));
+        assert_eq!(parse_pairs("id 1; name \"Ada", 4), Err(ParseError::new(4, 12, "unterminated quoted string")));
+        assert_eq!(parse_pairs("name A\\da", 2), Err(ParseError::new(2, 7, "unknown escape sequence \\d")));
+        assert!(parse_pairs("name Ada\\", 2).is_err());
+    }
+
+    fn kinds(tokens: Vec<Token>) -> Vec<TokenKind> {
+        tokens.into_iter().map(|t| t.kind).collect()
+    }
+}
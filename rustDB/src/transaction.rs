@@ -0,0 +1,256 @@
+#[doc="
+  Transactions of one connection.
+
+  BEGIN starts a transaction. APPEND, UPDATE and DELETE are then run on
+  private copies of their collections and queued, a statement that fails is
+  answered with its error and left out of the queue. GET and AGGREGATE of
+  the same connection read the private copies, so the transaction sees its
+  own changes. The answers to queued statements are tentative (status 202).
+
+  COMMIT runs the queue again on the collections as they are at that moment,
+  while the writes to the collections it touches wait; writes to other
+  collections go on. When a statement fails, or an id or a count differs
+  from its tentative answer because another connection changed the
+  collection meanwhile, nothing is applied and COMMIT fails, a changed
+  answer with a conflict. Otherwise the collections are replaced and the
+  queue is written to the log as one record, so it is replayed as a whole
+  or not at all. The answer is the list of the statement results, the same
+  as the tentative ones. ROLLBACK, or closing the connection, drops the
+  queue. Collections cannot be created, removed or indexed inside a
+  transaction.
+"]
+use std::collections::{HashMap, BTreeMap};
+use std::sync::RwLockWriteGuard;
+use rustc_serialize::json::Json;
+
+use db_module::RustDB;
+use backend::{Backend, CollectionBackend};
+use error::DbError;
+use wal::{WriteAheadLog, Operation};
+use value::ID_FIELD;
+
+pub struct Transaction {
+    operations: Vec<Operation>,
+    results: Vec<Json>,                         // the tentative answers, in statement order
+    workspace: HashMap<String, Backend>,        // private copies of the changed collections
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Transaction {
+            operations: Vec::new(),
+            results: Vec::new(),
+            workspace: HashMap::new(),
+        }
+    }
+
+    // run the statement on the private copy of its collection and queue it
+    pub fn execute(&mut self, database: &RustDB, operation: Operation) -> Result<Json, DbError> {
+        let name = try!(collection_of(&operation)).to_owned();
+        if !self.workspace.contains_key(&name) {
+            let shared = try!(database.find_cl(&name));
+            let copy = shared.read().unwrap().clone();
+            self.workspace.insert(name.clone(), copy);
+        }
+        let result = try!(apply(&operation, self.workspace.get_mut(&name).unwrap()));
+        self.operations.push(operation);
+        self.results.push(result.clone());
+        Ok(result)
+    }
+
+    // the private copy, when the transaction changed the collection
+    pub fn collection(&self, name: &str) -> Option<&dyn CollectionBackend> {
+        self.workspace.get(name).map(|copy| &**copy)
+    }
+
+    // apply every statement or none of them, the results in statement order
+    pub fn commit(self, database: &RustDB, write_ahead_log: &WriteAheadLog) -> Result<Vec<Json>, DbError> {
+        if self.operations.is_empty() {
+            return Ok(Vec::new());
+        }
+        let _checkpoint = write_ahead_log.lock_for_write();
+        let operations = &self.operations;
+        let tentative = &self.results;
+        apply_all(database, operations, |results| {
+            if results != &tentative[..] {
+                return Err(DbError::TransactionConflict);
+            }
+            write_ahead_log.append(&Operation::Transaction(operations.clone()))
+                .map_err(|e| DbError::Io(format!("Failed to write ahead log, the transaction is rolled back: {}", e)))
+        })
+    }
+}
+
+// run the statements on copies of the collections they touch and replace the collections when every
+// statement succeeds and accept agrees; the collections are locked in name order until then
+pub fn apply_all<F>(database: &RustDB, operations: &[Operation], accept: F) -> Result<Vec<Json>, DbError>
+    where F: FnOnce(&[Json]) -> Result<(), DbError>
+{
+    let mut shared = BTreeMap::new();
+    for operation in operations {
+        let name = try!(collection_of(operation));
+        if !shared.contains_key(name) {
+            shared.insert(name.to_owned(), try!(database.find_cl(name)));
+        }
+    }
+    let mut guards: BTreeMap<&str, RwLockWriteGuard<Backend>> = shared.iter().map(|(name, cl)| (name.as_str(), cl.write().unwrap())).collect();
+    let mut copies: HashMap<&str, Backend> = guards.iter().map(|(name, guard)| (*name, (**guard).clone())).collect();
+    let mut results = Vec::new();
+    for (number, operation) in operations.iter().enumerate() {
+        let name = try!(collection_of(operation));
+        match apply(operation, copies.get_mut(name).unwrap()) {
+            Ok(result) => results.push(result),
+            Err(err) => return Err(DbError::StatementFailed(number + 1, Box::new(err))),
+        }
+    }
+    try!(accept(&results));
+    for (name, guard) in guards.iter_mut() {
+        **guard = copies.remove(name).unwrap();
+    }
+    Ok(results)
+}
+
+const NOT_IN_TRANSACTION: &'static str = "Only APPEND, UPDATE and DELETE can be part of a transaction";
+
+fn collection_of(operation: &Operation) -> Result<&str, DbError> {
+    match *operation {
+        Operation::Append(ref name, _) | Operation::Update(ref name, _, _) | Operation::Delete(ref name, _) |
+        Operation::UpdateIf(ref name, _, _, _) | Operation::DeleteIf(ref name, _, _) => Ok(name),
+        _ => Err(DbError::Transaction(NOT_IN_TRANSACTION)),
+    }
+}
+
+fn apply(operation: &Operation, collection: &mut Backend) -> Result<Json, DbError> {
+    let number = match *operation {
+        Operation::Append(_, ref entry) => {
+            let mut created = BTreeMap::new();
+            created.insert(ID_FIELD.to_owned(), Json::U64(try!(collection.insert(entry))));
+            return Ok(Json::Object(created));
+        },
+        Operation::Update(_, ref target, ref desired) => try!(collection.update(target, desired)),
+        Operation::UpdateIf(_, ref target, ref desired, version) => try!(collection.update_if(target, desired, Some(version))),
+        Operation::Delete(_, ref target) => try!(collection.delete(target)),
+        Operation::DeleteIf(_, ref target, version) => try!(collection.delete_if(target, Some(version))),
+        _ => return Err(DbError::Transaction(NOT_IN_TRANSACTION)),
+    };
+    Ok(Json::U64(number as u64))
+}
+
+
+#[cfg(test)]
+mod transaction_tests {
+    use super::Transaction;
+    use std::fs::remove_file;
+    use std::path::Path;
+    use std::collections::BTreeMap;
+    use rustc_serialize::json::Json;
+    use wal::{WriteAheadLog, Operation};
+    use vec_dbcollection::TableEntry;
+    use value::{FieldType, Schema, Constraints};
+    use filter::Filter;
+    use error::DbError;
+
+    #[test]
+    fn commit_test() {
+        let snapshot_path = Path::new("transaction_test_commit_db.txt");
+        let log_path = Path::new("transaction_test_commit_log.txt");
+        {
+            let (db, wal) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            for op in vec![Operation::PutList("account".to_owned(), new_fields(), new_constraints()),
+                           Operation::Append("account".to_owned(), new_entry("Ada", "10"))] {
+                assert!(op.apply(&db));
+                wal.append(&op).unwrap();
+            }
+
+            let mut transaction = Transaction::new();
+            assert!(transaction.execute(&db, Operation::Append("account".to_owned(), new_entry("Bob", "5"))).is_ok());
+            assert!(transaction.execute(&db, Operation::Update("account".to_owned(), Filter::parse("name Ada", 1).unwrap(), new_balance("7"))).is_ok());
+            // a failing statement is not queued
+            assert_eq!(transaction.execute(&db, Operation::Append("account".to_owned(), new_entry("Ada", "1"))), Err(DbError::DuplicateKey("name".to_owned())));
+            assert_eq!(transaction.execute(&db, Operation::Append("missing".to_owned(), new_entry("Cy", "1"))), Err(DbError::UnknownCollection("missing".to_owned())));
+
+            // the transaction sees its changes, others do not
+            assert_eq!(transaction.collection("account").unwrap().get_number_of_data(), 2);
+            assert_eq!(db.find_cl("account").unwrap().read().unwrap().get_number_of_data(), 1);
+
+            let results = transaction.commit(&db, &wal).unwrap();
+            assert_eq!(results[1], Json::U64(1));
+            assert_eq!(db.find_cl("account").unwrap().read().unwrap().get_number_of_data(), 2);
+        }
+        {
+            // replayed from the log as one record
+            let (db, _) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            let account = db.find_cl("account").unwrap();
+            let account = account.read().unwrap();
+            assert_eq!(account.get_number_of_data(), 2);
+            assert_eq!(account.find(&Filter::parse("balance 7", 1).unwrap()).unwrap().len(), 1);
+        }
+        remove_file(snapshot_path).unwrap();
+        remove_file(log_path).unwrap();
+    }
+
+    #[test]
+    fn all_or_nothing_test() {
+        let snapshot_path = Path::new("transaction_test_abort_db.txt");
+        let log_path = Path::new("transaction_test_abort_log.txt");
+        {
+            let (db, wal) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            assert!(Operation::PutList("account".to_owned(), new_fields(), new_constraints()).apply(&db));
+
+            let mut transaction = Transaction::new();
+            assert!(transaction.execute(&db, Operation::Append("account".to_owned(), new_entry("Ada", "10"))).is_ok());
+            assert!(transaction.execute(&db, Operation::Append("account".to_owned(), new_entry("Bob", "5"))).is_ok());
+
+            // another connection takes the name before the commit
+            assert!(Operation::Append("account".to_owned(), new_entry("Bob", "1")).apply(&db));
+            assert_eq!(transaction.commit(&db, &wal), Err(DbError::StatementFailed(2, Box::new(DbError::DuplicateKey("name".to_owned())))));
+            assert_eq!(db.find_cl("account").unwrap().read().unwrap().get_number_of_data(), 1);
+
+            // the ids the client was answered with would change
+            let mut transaction = Transaction::new();
+            assert_eq!(transaction.execute(&db, Operation::Append("account".to_owned(), new_entry("Cy", "3"))), Ok(Json::Object(id(1))));
+            assert!(transaction.execute(&db, Operation::Update("account".to_owned(), Filter::parse("_id 1", 1).unwrap(), new_balance("4"))).is_ok());
+            assert!(Operation::Append("account".to_owned(), new_entry("Dan", "2")).apply(&db));
+            assert_eq!(transaction.commit(&db, &wal), Err(DbError::TransactionConflict));
+            assert_eq!(db.find_cl("account").unwrap().read().unwrap().find(&Filter::parse("_id 1", 1).unwrap()).unwrap().len(), 1);
+            assert_eq!(db.find_cl("account").unwrap().read().unwrap().get_number_of_data(), 2);
+        }
+        {
+            let (db, _) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            assert!(db.find_cl("account").is_err());
+        }
+        remove_file(snapshot_path).unwrap();
+        remove_file(log_path).unwrap();
+    }
+
+    fn id(id: u64) -> BTreeMap<String, Json> {
+        let mut created = BTreeMap::new();
+        created.insert("_id".to_owned(), Json::U64(id));
+        created
+    }
+
+    fn new_fields() -> Schema {
+        let mut fields = Schema::new();
+        fields.insert("name".to_owned(), FieldType::Str);
+        fields.insert("balance".to_owned(), FieldType::Int);
+        fields
+    }
+
+    fn new_constraints() -> Constraints {
+        let mut constraints = Constraints::new();
+        constraints.unique.insert("name".to_owned());
+        constraints
+    }
+
+    fn new_entry(name: &str, balance: &str) -> TableEntry {
+        let mut entry = new_balance(balance);
+        entry.insert("name".to_owned(), name.to_owned());
+        entry
+    }
+
+    fn new_balance(balance: &str) -> TableEntry {
+        let mut entry = TableEntry::new();
+        entry.insert("balance".to_owned(), balance.to_owned());
+        entry
+    }
+}
@@ -0,0 +1,459 @@
+#[doc="
+  Typed field values.
+
+  A collection declares a type per field (PUTLIST lines like `age int`),
+  values sent as text are coerced into that type on APPEND and UPDATE.
+  Values are written as plain JSON numbers, booleans and strings, a
+  timestamp is written as an RFC 3339 string and read back through the
+  collection schema.
+"]
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use rustc_serialize::{Encodable, Encoder, Decodable, Decoder};
+use rustc_serialize::json::Json;
+use time::{self, Timespec};
+
+const TIMESTAMP_FORMAT: &'static str = "%Y-%m-%dT%H:%M:%SZ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FieldType {
+    Str,
+    Int,
+    Float,
+    Bool,
+    Timestamp,
+}
+
+pub type Schema = BTreeMap<String, FieldType>;
+
+// declared with the fields on PUTLIST, the primary key is required and also listed as unique
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct Constraints {
+    pub primary_key: Option<String>,
+    pub unique: BTreeSet<String>,
+    pub required: BTreeSet<String>,             // every item has a value, APPEND gives it unless it has a default
+    pub defaults: BTreeMap<String, String>,     // stored when APPEND leaves the field out, as declared
+    pub nullable: BTreeSet<String>,             // NULL clears the value, other fields keep one once they have it
+}
+
+impl Constraints {
+    pub fn new() -> Self {
+        Constraints {
+            primary_key: None,
+            unique: BTreeSet::new(),
+            required: BTreeSet::new(),
+            defaults: BTreeMap::new(),
+            nullable: BTreeSet::new(),
+        }
+    }
+}
+
+// constraints logged or stored before required, default and nullable fields existed are read without them
+impl Decodable for Constraints {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        d.read_struct("Constraints", 5, |d| {
+            Ok(Constraints {
+                primary_key: try!(d.read_struct_field("primary_key", 0, Decodable::decode)),
+                unique: try!(d.read_struct_field("unique", 1, Decodable::decode)),
+                required: try!(d.read_struct_field("required", 2, |d| Option::decode(d))).unwrap_or_else(BTreeSet::new),
+                defaults: try!(d.read_struct_field("defaults", 3, |d| Option::decode(d))).unwrap_or_else(BTreeMap::new),
+                nullable: try!(d.read_struct_field("nullable", 4, |d| Option::decode(d))).unwrap_or_else(BTreeSet::new),
+            })
+        })
+    }
+}
+
+// every item carries the row number it got on insert under this field, rows are never reused
+pub const ID_FIELD: &'static str = "_id";
+pub const VERSION_FIELD: &'static str = "_version";    // 1 when appended, one more with every update
+
+// the text of an unquoted NULL value of APPEND and UPDATE and of a JSON null, no value a client sends
+// holds a NUL character (see tokenizer.rs and http.rs)
+pub const NULL_TEXT: &'static str = "\u{0}NULL";
+
+// type of a field in the schema, the id and the version are int fields of every collection
+pub fn field_type(fields: &Schema, name: &str) -> Option<FieldType> {
+    if name == ID_FIELD || name == VERSION_FIELD {
+        return Some(FieldType::Int);
+    }
+    fields.get(name).cloned()
+}
+
+// "name [type] [primary [key]] [unique] [required|nullable] [default value]", a field that is not required
+// may be left out, a nullable one may also be cleared; on error the index of the offending word and the reason,
+// and nothing is declared
+pub fn declare_field(words: &[String], fields: &mut Schema, constraints: &mut Constraints) -> Result<(), (usize, String)> {
+    let name = &words[0];
+    if name == ID_FIELD || name == VERSION_FIELD {
+        return Err((0, format!("field name {} is reserved", name)));
+    }
+    let mut field_type = FieldType::Str;
+    let mut position = 1;
+    let (mut primary, mut unique, mut required, mut default) = (false, false, false, None);
+    let mut nullable = None;
+    if let Some(declared) = words.get(1).and_then(|word| FieldType::from_name(word)) {
+        field_type = declared;
+        position = 2;
+    }
+    while position < words.len() {
+        match words[position].to_lowercase().as_ref() {
+            "primary" => {
+                if constraints.primary_key.is_some() {
+                    return Err((position, "only one primary key per collection".to_owned()));
+                }
+                primary = true;
+                if words.get(position + 1).map(|word| word.to_lowercase()) == Some("key".to_owned()) {
+                    position += 1;
+                }
+            },
+            "unique" => unique = true,
+            "required" => required = true,
+            "nullable" => nullable = Some(position),
+            "default" => {
+                let value = match words.get(position + 1) {
+                    Some(value) => value,
+                    None => return Err((position, "default expects a value".to_owned())),
+                };
+                if field_type.coerce(value).is_none() {
+                    return Err((position + 1, format!("default {} is not a {}", value, field_type.name())));
+                }
+                default = Some(value.to_owned());
+                position += 1;
+            },
+            _ if position == 1 => return Err((position, format!("unknown field type {}", words[position]))),
+            _ => return Err((position, format!("unknown field option {}", words[position]))),
+        }
+        position += 1;
+    }
+    // a nullable field may lose its value, which a required field or the primary key may not
+    if let Some(position) = nullable {
+        if required || primary {
+            return Err((position, format!("field {} cannot be both nullable and required", name)));
+        }
+        constraints.nullable.insert(name.to_owned());
+    }
+    if primary {
+        constraints.primary_key = Some(name.to_owned());
+    }
+    if primary || unique {
+        constraints.unique.insert(name.to_owned());
+    }
+    if required {
+        constraints.required.insert(name.to_owned());
+    }
+    if let Some(value) = default {
+        constraints.defaults.insert(name.to_owned(), value);
+    }
+    fields.insert(name.to_owned(), field_type);
+    Ok(())
+}
+
+impl FieldType {
+    pub fn from_name(name: &str) -> Option<FieldType> {
+        match name.to_lowercase().as_ref() {
+            "string" | "str" | "text" => Some(FieldType::Str),
+            "int" | "integer" => Some(FieldType::Int),
+            "float" | "double" | "number" => Some(FieldType::Float),
+            "bool" | "boolean" => Some(FieldType::Bool),
+            "timestamp" | "time" | "datetime" => Some(FieldType::Timestamp),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FieldType::Str => "string",
+            FieldType::Int => "int",
+            FieldType::Float => "float",
+            FieldType::Bool => "bool",
+            FieldType::Timestamp => "timestamp",
+        }
+    }
+
+    // turn the text sent by a client into a value of this type
+    pub fn coerce(&self, text: &str) -> Option<Value> {
+        match *self {
+            FieldType::Str => Some(Value::Str(text.to_owned())),
+            FieldType::Int => text.trim().parse::<i64>().ok().map(Value::Int),
+            // inf and NaN have no JSON form, a snapshot would store them as null
+            FieldType::Float => text.trim().parse::<f64>().ok().filter(|n| n.is_finite()).map(Value::Float),
+            FieldType::Bool => match text.trim().to_lowercase().as_ref() {
+                "true" | "1" => Some(Value::Bool(true)),
+                "false" | "0" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            FieldType::Timestamp => parse_timestamp(text.trim()).map(Value::Timestamp),
+        }
+    }
+
+    // read a value back from stored JSON
+    pub fn from_json(&self, json: &Json) -> Option<Value> {
+        match (*self, json) {
+            (FieldType::Int, &Json::I64(n)) => Some(Value::Int(n)),
+            (FieldType::Int, &Json::U64(n)) => Some(Value::Int(n as i64)),
+            (FieldType::Float, &Json::F64(n)) => Some(Value::Float(n)),
+            (FieldType::Float, &Json::I64(n)) => Some(Value::Float(n as f64)),
+            (FieldType::Float, &Json::U64(n)) => Some(Value::Float(n as f64)),
+            (FieldType::Bool, &Json::Boolean(b)) => Some(Value::Bool(b)),
+            (_, &Json::String(ref s)) => self.coerce(s),
+            _ => None,
+        }
+    }
+}
+
+impl Encodable for FieldType {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(self.name())
+    }
+}
+
+impl Decodable for FieldType {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        let name = try!(d.read_str());
+        match FieldType::from_name(&name) {
+            Some(field_type) => Ok(field_type),
+            None => Err(d.error(&format!("unknown field type {}", name))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Timestamp(i64),         // seconds since the epoch, UTC
+}
+
+impl Value {
+    // values of different types are ordered by type
+    fn rank(&self) -> u8 {
+        match *self {
+            Value::Bool(_) => 0,
+            Value::Int(_) => 1,
+            Value::Float(_) => 2,
+            Value::Timestamp(_) => 3,
+            Value::Str(_) => 4,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (&Value::Str(ref a), &Value::Str(ref b)) => a.cmp(b),
+            (&Value::Int(a), &Value::Int(b)) => a.cmp(&b),
+            (&Value::Float(a), &Value::Float(b)) => a.partial_cmp(&b).unwrap_or_else(|| a.is_nan().cmp(&b.is_nan())),
+            (&Value::Bool(a), &Value::Bool(b)) => a.cmp(&b),
+            (&Value::Timestamp(a), &Value::Timestamp(b)) => a.cmp(&b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match *self {
+            Value::Str(ref s) => s.hash(state),
+            Value::Int(n) => n.hash(state),
+            // equal floats hash alike: both zeros as 0.0, every NaN as one NaN
+            Value::Float(n) if n == 0.0 => 0.0f64.to_bits().hash(state),
+            Value::Float(n) if n.is_nan() => ::std::f64::NAN.to_bits().hash(state),
+            Value::Float(n) => n.to_bits().hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::Timestamp(t) => t.hash(state),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Str(ref s) => write!(f, "{:?}", s),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Timestamp(t) => write!(f, "{}", format_timestamp(t)),
+        }
+    }
+}
+
+impl Encodable for Value {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        match *self {
+            Value::Str(ref v) => s.emit_str(v),
+            Value::Int(v) => s.emit_i64(v),
+            Value::Float(v) => s.emit_f64(v),
+            Value::Bool(v) => s.emit_bool(v),
+            Value::Timestamp(v) => s.emit_str(&format_timestamp(v)),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Value {
+        Value::Str(s.to_owned())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Int(n)
+    }
+}
+
+// "2016-05-01T12:00:00Z", "2016-05-01 12:00:00", "2016-05-01" or seconds since the epoch
+pub fn parse_timestamp(text: &str) -> Option<i64> {
+    if let Ok(seconds) = text.parse::<i64>() {
+        return Some(seconds);
+    }
+    for format in [TIMESTAMP_FORMAT, "%Y-%m-%d %H:%M:%S", "%Y-%m-%d"].iter() {
+        if let Ok(tm) = time::strptime(text, format) {
+            return Some(tm.to_timespec().sec);
+        }
+    }
+    None
+}
+
+pub fn format_timestamp(seconds: i64) -> String {
+    let tm = time::at_utc(Timespec::new(seconds, 0));
+    match tm.strftime(TIMESTAMP_FORMAT) {
+        Ok(formatted) => formatted.to_string(),
+        Err(_) => seconds.to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod value_tests {
+    use super::{FieldType, Value, Schema, Constraints, declare_field, parse_timestamp, format_timestamp};
+    use rustc_serialize::json::{self, Json};
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    #[test]
+    fn coerce_test() {
+        assert_eq!(FieldType::Int.coerce("10"), Some(Value::Int(10)));
+        assert_eq!(FieldType::Int.coerce("ten"), None);
+        assert_eq!(FieldType::Float.coerce("2.5"), Some(Value::Float(2.5)));
+        assert_eq!(FieldType::Float.coerce("inf"), None);
+        assert_eq!(FieldType::Float.coerce("-inf"), None);
+        assert_eq!(FieldType::Float.coerce("NaN"), None);
+        assert_eq!(FieldType::Bool.coerce("TRUE"), Some(Value::Bool(true)));
+        assert_eq!(FieldType::Bool.coerce("maybe"), None);
+        assert_eq!(FieldType::Str.coerce("10"), Some(Value::Str("10".to_owned())));
+        assert_eq!(FieldType::Timestamp.coerce("1970-01-02"), Some(Value::Timestamp(86400)));
+    }
+
+    #[test]
+    fn declare_field_test() {
+        let mut fields = Schema::new();
+        let mut constraints = Constraints::new();
+        let words = |line: &str| -> Vec<String> { line.split_whitespace().map(|w| w.to_owned()).collect() };
+        assert!(declare_field(&words("id int primary key"), &mut fields, &mut constraints).is_ok());
+        assert!(declare_field(&words("email unique"), &mut fields, &mut constraints).is_ok());
+        assert!(declare_field(&words("name"), &mut fields, &mut constraints).is_ok());
+        assert_eq!(fields.get("id"), Some(&FieldType::Int));
+        assert_eq!(fields.get("email"), Some(&FieldType::Str));
+        assert_eq!(constraints.primary_key, Some("id".to_owned()));
+        assert!(constraints.unique.contains("id") && constraints.unique.contains("email"));
+
+        assert_eq!(declare_field(&words("code int primary"), &mut fields, &mut constraints),
+                   Err((2, "only one primary key per collection".to_owned())));
+        assert_eq!(declare_field(&words("age number"), &mut fields, &mut constraints), Ok(()));
+        assert_eq!(declare_field(&words("age years"), &mut fields, &mut constraints),
+                   Err((1, "unknown field type years".to_owned())));
+        assert_eq!(declare_field(&words("age int indexed"), &mut fields, &mut constraints),
+                   Err((2, "unknown field option indexed".to_owned())));
+        assert_eq!(declare_field(&words("_id int"), &mut fields, &mut constraints),
+                   Err((0, "field name _id is reserved".to_owned())));
+        assert_eq!(declare_field(&words("_version"), &mut fields, &mut constraints),
+                   Err((0, "field name _version is reserved".to_owned())));
+
+        assert!(declare_field(&words("score int required default 0"), &mut fields, &mut constraints).is_ok());
+        assert!(declare_field(&words("note nullable"), &mut fields, &mut constraints).is_ok());
+        assert!(constraints.required.contains("score") && !constraints.required.contains("note"));
+        assert!(constraints.nullable.contains("note") && !constraints.nullable.contains("score"));
+        assert_eq!(constraints.defaults.get("score"), Some(&"0".to_owned()));
+        assert_eq!(declare_field(&words("level int default high"), &mut fields, &mut constraints),
+                   Err((3, "default high is not a int".to_owned())));
+        assert_eq!(declare_field(&words("level int default"), &mut fields, &mut constraints),
+                   Err((2, "default expects a value".to_owned())));
+        assert_eq!(declare_field(&words("level nullable required"), &mut fields, &mut constraints),
+                   Err((1, "field level cannot be both nullable and required".to_owned())));
+        assert_eq!(declare_field(&words("level int unique required nullable"), &mut fields, &mut constraints),
+                   Err((4, "field level cannot be both nullable and required".to_owned())));
+
+        // a line with an error declares nothing
+        let (declared, constrained) = (fields.clone(), constraints.clone());
+        assert_eq!(declare_field(&words("level int unique required default high"), &mut fields, &mut constraints),
+                   Err((5, "default high is not a int".to_owned())));
+        assert_eq!((fields, constraints), (declared, constrained));
+
+        // constraints logged before required fields and defaults
+        let logged: Constraints = json::decode("{\"primary_key\":\"id\",\"unique\":[\"id\"]}").unwrap();
+        assert!(logged.required.is_empty() && logged.defaults.is_empty() && logged.nullable.is_empty());
+    }
+
+    #[test]
+    fn numeric_order_test() {
+        assert!(Value::Int(9) < Value::Int(10));
+        assert!(Value::from("9") > Value::from("10"));
+        assert!(Value::Float(-1.5) < Value::Float(0.0));
+    }
+
+    #[test]
+    fn float_hash_test() {
+        let hash_of = |value: Value| {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(Value::Float(0.0), Value::Float(-0.0));
+        assert_eq!(hash_of(Value::Float(0.0)), hash_of(Value::Float(-0.0)));
+        let other_nan = f64::from_bits(::std::f64::NAN.to_bits() ^ 1);
+        assert_eq!(Value::Float(::std::f64::NAN), Value::Float(other_nan));
+        assert_eq!(hash_of(Value::Float(::std::f64::NAN)), hash_of(Value::Float(other_nan)));
+        assert_eq!(hash_of(Value::Float(-::std::f64::NAN)), hash_of(Value::Float(::std::f64::NAN)));
+    }
+
+    #[test]
+    fn json_round_trip_test() {
+        let values = vec![Value::Int(9), Value::Bool(true), Value::from("Ada"), Value::Timestamp(86400)];
+        assert_eq!(json::encode(&values).unwrap(), "[9,true,\"Ada\",\"1970-01-02T00:00:00Z\"]");
+
+        let stored = Json::from_str("[9, true, \"Ada\", \"1970-01-02T00:00:00Z\"]").unwrap();
+        let stored = stored.as_array().unwrap();
+        assert_eq!(FieldType::Int.from_json(&stored[0]), Some(Value::Int(9)));
+        assert_eq!(FieldType::Bool.from_json(&stored[1]), Some(Value::Bool(true)));
+        assert_eq!(FieldType::Str.from_json(&stored[2]), Some(Value::from("Ada")));
+        assert_eq!(FieldType::Timestamp.from_json(&stored[3]), Some(Value::Timestamp(86400)));
+        assert_eq!(FieldType::Int.from_json(&stored[2]), None);
+    }
+
+    #[test]
+    fn timestamp_test() {
+        assert_eq!(parse_timestamp("2016-05-01T12:00:00Z"), Some(1462104000));
+        assert_eq!(parse_timestamp("2016-05-01 12:00:00"), Some(1462104000));
+        assert_eq!(format_timestamp(1462104000), "2016-05-01T12:00:00Z");
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+}
@@ -1,234 +1,189 @@
 
-use std::sync::{Arc, Mutex, RwLock, Barrier};
-use std::collections::{LinkedList,HashMap, BTreeSet};
-use std::thread;
-use std::fmt::{Display};
-use std::sync::atomic::{AtomicPtr, Ordering};
-//extern crate rustc_serialize;
-use rustc_serialize::json::{self,Json,ToJson};
-
-pub type TableEntry = HashMap<String, String>;
-pub type Set<K> = BTreeSet<K>;
-
+use std::sync::{Arc, Mutex, RwLock};
+use rustc_serialize::json::{Json, ToJson, EncoderError};
+
+use vec_dbcollection::{ItemNode, TableEntry, Record, to_changes, to_new_record};
+use value::{Value, Schema, Constraints, ID_FIELD, VERSION_FIELD};
+use filter::Filter;
+use aggregate::{Aggregate, Summary, aggregate_chunks};
+use pool::scans;
+use backend::{CollectionBackend, BackendKind, Backend, items_to_json};
+use error::DbError;
+use alter::{Alteration, alter_schema};
 
+pub type EntryList = Vec<Arc<Mutex<Box<ItemNode>>>>;
 
+// items a worker takes at a time on a scan
+const CHUNK_ITEMS: usize = 1024;
 
-#[derive(Debug, RustcDecodable, RustcEncodable)]
-pub struct ItemNode {
-    valid: bool,
-    content: TableEntry,
+#[derive(Debug)]
+pub struct Collection{
+    fields: Schema,
+    constraints: Constraints,   // only required fields and defaults, see new_backend
+    entries: Arc<RwLock<EntryList>>,
+    next_id: u64,               // scans run on the shared pool of scans, in chunks of CHUNK_ITEMS
 }
 
-impl ItemNode {
-    pub fn new(entry: &TableEntry) -> Self {
-        ItemNode {
-            valid: true,
-            content: entry.to_owned(),
-        }
-    }
-
-    pub fn is_valid(&self) -> bool {
-        self.valid
-    }
-
-    pub fn set_valid(&mut self, set_value: bool){
-        self.valid = set_value;
-    }
 
-    pub fn update_field(&mut self, field_name: String, field_value: String) -> Result<(), &str>{
-        if let Some(x) = self.content.get_mut(&field_name) {
-            *x = field_value;
-            Ok(())
-        }
-        else {
-            Err("Such field does not exist!")
-        }
-    }
-
-
-    pub fn matched(&self, template: &TableEntry) -> bool{
-        for key in template.keys() {
-            if self.content.get(key) != template.get(key){
-                return false;
-            }
+impl Collection{
+    pub fn with_constraints(fields: &Schema, constraints: &Constraints) -> Self {
+        Collection {
+            fields: fields.to_owned(),
+            constraints: constraints.to_owned(),
+            entries: Arc::new(RwLock::new(EntryList::new())),
+            next_id: 0,
         }
-        true
     }
 
-
-    pub fn modify(&mut self, template: &TableEntry) {
-        for key in template.keys() {
-            let val = self.content.entry(key.clone()).or_insert("".to_owned());
-            *val = template.get(key).unwrap().clone();
+    // items read back from a snapshot, in id order
+    pub fn from_items(fields: &Schema, constraints: &Constraints, items: Vec<Record>, next_id: u64) -> Self {
+        let collection = Collection::with_constraints(fields, constraints);
+        *collection.entries.write().unwrap() = items.iter().map(|item| Arc::new(Mutex::new(Box::new(ItemNode::new(item))))).collect();
+        Collection {
+            next_id: next_id,
+            ..collection
         }
     }
 }
 
-pub type EntryList = Vec<Arc<Mutex<Box<ItemNode>>>>;
-
-#[derive(Debug)]
-pub struct Collection{
-    fields: Set<String>,
-    entries: Arc<RwLock<EntryList>>,
-}
+impl CollectionBackend for Collection{
+    fn kind(&self) -> BackendKind {
+        BackendKind::Parallel
+    }
 
+    fn get_fields(&self) -> &Schema {
+        &self.fields
+    }
 
-impl Collection{
-    pub fn new(fields: &Set<String>) -> Self {
-        Collection {
-            fields: fields.to_owned(),
-            entries: Arc::new(RwLock::new(EntryList::new()))
-        }
+    fn get_constraints(&self) -> &Constraints {
+        &self.constraints
     }
 
-    pub fn get_number_of_data(&self) -> usize{
+    fn get_number_of_data(&self) -> usize{
         let share_entries = self.entries.clone();
         let guard = share_entries.read().unwrap();
         guard.len().clone()
     }
 
-    fn is_valid(&self,  target: &TableEntry) -> bool {
-        for key in target.keys() {
-            println!("key is :{:?}", key);
-        }
-        for key in target.keys() {
-            if !self.fields.contains(key){
-                return false;
-            }
-        }
-        return true;
+    fn get_items(&self) -> Vec<Record> {
+        self.entries.read().unwrap().iter().map(|item| item.lock().unwrap().get_content().clone()).collect()
     }
 
-
-    pub fn insert(&mut self, desired: &TableEntry){
-        if self.is_valid(desired) {
-
-            let mut share_entries = self.entries.clone();
-
-            share_entries.write().unwrap().push(Arc::new(Mutex::new(Box::new(ItemNode::new(desired)))));
-        }
-        else{
-            println!("Invalid Insert Entry");
+    fn visit_items(&self, skip: usize, take: usize, visit: &mut dyn FnMut(&Record)) {
+        for item in self.entries.read().unwrap().iter().skip(skip).take(take) {
+            visit(item.lock().unwrap().get_content());
         }
     }
 
+    // the id of the new item
+    fn insert(&mut self, desired: &TableEntry) -> Result<u64, DbError>{
+        let mut record = try!(to_new_record(&self.fields, &self.constraints, desired));
+        let id = self.next_id;
+        self.next_id += 1;
+        record.insert(ID_FIELD.to_owned(), Value::Int(id as i64));
+        record.insert(VERSION_FIELD.to_owned(), Value::Int(1));
+        let share_entries = self.entries.clone();
 
-    pub fn update(&mut self, target: &TableEntry, desired: &TableEntry) -> Option<usize>{
-        if !self.is_valid(target) || !self.is_valid(desired) {
-            None
-        } else {
-            let mut count = 0;
-            let mut count_guard = Arc::new(RwLock::new(count));
-            
-            let entries_ptr = self.entries.clone();
-            let mut share_entries = entries_ptr.write().unwrap();
-
-            let n_item = share_entries.len();
-
-            let finished = Arc::new(Barrier::new(n_item.clone() + 1));
-
-            for tid in 0..n_item{
-                let item = share_entries[tid].clone();
-                let target_clone = target.clone();
-                let desired_clone = desired.clone();
-                let mut share_count = count_guard.clone();
-
-                let tid_finished = finished.clone();
-                thread::spawn(move || {
-
-                    
-                    let mut item = item.lock().unwrap();
+        share_entries.write().unwrap().push(Arc::new(Mutex::new(Box::new(ItemNode::new(&record)))));
+        Ok(id)
+    }
 
-                    if item.matched(&target_clone) {
-                        item.modify(&desired_clone);
 
-                        *share_count.write().unwrap() += 1;
-                    }
+    fn update(&mut self, target: &Filter<String>, desired: &TableEntry) -> Result<usize, DbError>{
+        let target = try!(target.bind(&self.fields));
+        let desired = try!(to_changes(&self.fields, &self.constraints, desired));
 
-                    tid_finished.wait();
-                });
+        // no item is added or removed while the workers modify the chunks
+        let share_entries = self.entries.write().unwrap();
+        let counts = try!(scans().map(share_entries.clone(), CHUNK_ITEMS, move |chunk| {
+            let mut count = 0;
+            for item in chunk.iter() {
+                let mut item = item.lock().unwrap();
+                if item.matched(&target) {
+                    item.revise(&desired);
+                    count += 1;
+                }
             }
-
-            finished.clone().wait();
-            let count = count_guard.clone();
-            let count_res = (*count.read().unwrap()).clone();
-            Some(count_res)
-        }
+            count
+        }));
+        Ok(counts.into_iter().sum())
     }
 
-    pub fn find(&self, target: & TableEntry) ->  Option<Vec<TableEntry>> {
-        if !self.is_valid(target) {
-            None
-        } else {
-
-            let mut find_list: Vec<TableEntry> = Vec::new();
-
-            let mut res: Arc<RwLock<Vec<TableEntry>>> = Arc::new(RwLock::new(find_list));
-
-            let share_entries_ptr = self.entries.clone();
-            let share_entries = share_entries_ptr.read().unwrap();
-
-            let n_item = share_entries.len();
-
-            let finished = Arc::new(Barrier::new(n_item.clone() + 1));
-            
-            for tid in 0..n_item{
-                let item = share_entries[tid].clone();
-                let target_clone = target.clone();
-                let mut res = res.clone();
-
-                let tid_finished = finished.clone();
-
-                thread::spawn(move || {
-
-                    
-                    let guard = item.lock().unwrap();
-
-                    if guard.matched(&target_clone) {
-                        let mut res = res.write().unwrap();
-                        res.push(guard.content.clone());
- 
+    fn find(&self, target: &Filter<String>) -> Result<Vec<Record>, DbError> {
+        let target = try!(target.bind(&self.fields));
+        let items = self.entries.read().unwrap().clone();
+
+        // the chunks come back in order, so the result keeps the order of the items
+        let found = try!(scans().map(items, CHUNK_ITEMS, move |chunk| {
+            chunk.iter().filter_map(|item| {
+                let item = item.lock().unwrap();
+                match item.matched(&target) {
+                    true => Some(item.get_content().clone()),
+                    false => None,
+                }
+            }).collect::<Vec<Record>>()
+        }));
+        Ok(found.into_iter().flat_map(|chunk| chunk.into_iter()).collect())
+    }
 
-                    }
 
-                    tid_finished.wait();
-                });
+    // summaries of the matching items, every thread sums up a chunk of the items
+    fn aggregate(&self, target: &Filter<String>, aggregate: &Aggregate) -> Result<Vec<Summary>, DbError> {
+        let target = try!(target.bind(&self.fields));
+        try!(aggregate.check(&self.fields).map_err(DbError::InvalidQuery));
+        let items = self.entries.read().unwrap().clone();
+        let groups = try!(aggregate_chunks(scans(), items, aggregate.groups(), move |groups, item| {
+            let item = item.lock().unwrap();
+            if item.matched(&target) {
+                groups.add(item.get_content());
             }
+        }));
+        groups.finish().map_err(DbError::InvalidQuery)
+    }
 
 
-            finished.clone().wait();
+    fn delete(&mut self, target: &Filter<String>) -> Result<usize, DbError>{
+        let target = try!(target.bind(&self.fields));
+        let mut count = 0;
+        let mut index = 0;
 
-            let res = res.clone();
-            let return_res = (*res.read().unwrap()).clone();
+        let shared_ptr = self.entries.clone();
+        let mut shared_entries = shared_ptr.write().unwrap();
 
-            Some(return_res)
+        while index < shared_entries.len() {
+            let item_ptr = shared_entries[index].clone();
+            let item = item_ptr.lock().unwrap();
+            if item.matched(&target) {
+                shared_entries.remove(index);
+                count += 1;
+            } else {
+                index += 1;
+            }
         }
+        Ok(count)
     }
 
+    fn alter(&mut self, alterations: &[Alteration]) -> Result<(), DbError> {
+        let (fields, constraints) = try!(alter_schema(&self.fields, &self.constraints, alterations));
+        self.fields = fields;
+        self.constraints = constraints;
+        let alterations = alterations.to_vec();
+        let share_entries = self.entries.write().unwrap();
+        try!(scans().map(share_entries.clone(), CHUNK_ITEMS, move |chunk| {
+            for item in chunk.iter() {
+                item.lock().unwrap().alter(&alterations);
+            }
+        }));
+        Ok(())
+    }
 
-    pub fn delete(&mut self, target: &TableEntry) -> Option<usize>{
-        if !self.is_valid(target)  {
-            None
-        } else {
-
-            let mut count = 0;
-            let mut index = 0;
-
-            let shared_ptr = self.entries.clone();
-            let mut shared_entries = shared_ptr.write().unwrap();
+    // the items are copied
+    fn box_clone(&self) -> Backend {
+        Box::new(Collection::from_items(&self.fields, &self.constraints, self.get_items(), self.next_id))
+    }
 
-            while index < shared_entries.len() {
-                let item_ptr = shared_entries[index].clone();
-                let item = item_ptr.lock().unwrap();
-                if item.matched(target) {
-                    shared_entries.remove(index);
-                    count += 1;
-                } else {
-                    index += 1;
-                }
-            }
-            Some(count)
-        }
+    fn to_json(&self) -> Result<String, EncoderError> {
+        items_to_json(self.kind(), &self.fields, &self.constraints, self.get_items(), self.next_id)
     }
 }
 
@@ -241,13 +196,13 @@ impl ToJson for Collection {
 
 impl PartialEq for Collection {
     fn eq(&self, other: &Self) -> bool {
-        for key in &other.fields {
-            if !self.fields.contains(key){
+        for (key, field_type) in &other.fields {
+            if self.fields.get(key) != Some(field_type){
                 return false;
             }
         }
-        for key in &self.fields {
-            if !other.fields.contains(key){
+        for key in self.fields.keys() {
+            if !other.fields.contains_key(key){
                 return false;
             }
         }
@@ -256,65 +211,17 @@ impl PartialEq for Collection {
 }
 
 
-#[cfg(test)]
-mod itemnode_tests {
-    use super::{ItemNode, TableEntry};
-
-    #[test]
-    fn node_validate_test() {
-        let mut node = ItemNode::new(&new_table_entry(0, "Ada", 24));
-        assert!(node.is_valid());
-        node.set_valid(false);
-        assert!(!node.is_valid());
-    }
-        
-    #[test]
-    fn node_matches_test() {
-
-        let mut node = ItemNode::new(&new_table_entry(0, "Ada", 24));
-        assert!(node.is_valid());
-
-        let mut matched = new_table_entry(0, "Ada", 24);
-        assert!(node.matched(&matched));
-        matched.remove(&String::from("age"));
-        assert!(node.matched(&matched));
-
-        
-        let mut non_matched = new_table_entry(0, "Joey", 24);
-        assert!(!node.matched(&non_matched));
-        non_matched.insert("name".to_owned(), "Ada".to_owned());
-        non_matched.insert("sex".to_owned(), "female".to_owned());
-        assert!(!node.matched(&non_matched));
-
-    }
-
-
-    #[test]
-    fn node_modify_test() {
-
-        let mut node = ItemNode::new(&new_table_entry(0, "Ada", 24));
-
-        let mut matched = new_table_entry(0, "Ada", 24);
-        assert_eq!(node.content, matched);
-        
-        let mut non_matched = new_table_entry(0, "Joey", 24);
-        node.modify(&non_matched);
-        assert_eq!(node.content, non_matched);
-    }
-
-    fn new_table_entry(id: usize, name: &str, age: usize) -> TableEntry{
-        let mut entry = TableEntry::new();
-        entry.insert("id".to_owned(), id.to_string());
-        entry.insert("name".to_owned(), name.to_string());
-        entry.insert("age".to_owned(), age.to_string());
-        entry
-    }
-}
-
 #[cfg(test)]
 mod collection_tests {
-    use super::{Collection, ItemNode, TableEntry, Set};
-    use std::collections::{BTreeSet};
+    use super::{Collection, TableEntry, Record, CHUNK_ITEMS};
+    use vec_dbcollection::Set;
+    use value::{FieldType, Schema, Constraints};
+    use value::Value;
+    use vec_dbcollection;
+    use filter::Filter;
+    use aggregate::Aggregate;
+    use backend::CollectionBackend;
+    use error::DbError;
 
     #[test]
     fn insert_test() {
@@ -338,13 +245,13 @@ mod collection_tests {
 
         let mut target = TableEntry::new();
         target.insert("age".to_owned(), 25.to_string());
-        let expected: Vec<TableEntry> = vec![new_sort_entry(1, "Joey", 25), new_sort_entry(1, "Ross", 25)];
+        let expected: Vec<Record> = vec![new_sort_record(1, 1, "Joey", 25), new_sort_record(2, 1, "Ross", 25)];
 
-        assert_eq!(clct.find(&target), Some(expected));
-        // assert!(equal_vec_entry(clct.find(&target), Some(expected)));
+        assert_eq!(clct.find(&Filter::from(&target)), Ok(expected));
+        // assert!(equal_vec_entry(clct.find(&Filter::from(&target)), Some(expected)));
 
         let mut non_valid = new_long_entry(0, "Ada", 24, "female");
-        assert_eq!(clct.find(&non_valid), None);
+        assert_eq!(clct.find(&Filter::from(&non_valid)), Err(DbError::UnknownField("gender".to_owned())));
     }
 
     #[test]
@@ -356,27 +263,31 @@ mod collection_tests {
 
         let mut target = TableEntry::new();
         target.insert("age".to_owned(), 25.to_string());
-        let expected: Vec<TableEntry> = vec![new_sort_entry(1, "Ross", 25), new_sort_entry(1, "Joey", 25)];
+        let expected: Vec<Record> = vec![new_sort_record(2, 1, "Ross", 25), new_sort_record(1, 1, "Joey", 25)];
 
         /*
         for item in expected{
             assert!(item in finded);
         }
         */
-        //assert_eq!(clct.find(&target), Some(expected));
+        //assert_eq!(clct.find(&Filter::from(&target)), Some(expected));
         //assert_eq!(BTreeSet::from_iter(finded.into_iter()), BTreeSet::from_iter(expected.into_iter()));
 
         let mut update_desired = TableEntry::new();
         update_desired.insert("age".to_owned(),24.to_string());
-        assert_eq!(clct.update(&target,&update_desired), Some(2));
+        assert_eq!(clct.update(&Filter::from(&target),&update_desired), Ok(2));
 
         let empty_vector = Vec::new();
-        assert_eq!(clct.find(&target), Some(empty_vector));
+        assert_eq!(clct.find(&Filter::from(&target)), Ok(empty_vector));
 
         let mut new_target = TableEntry::new();
         new_target.insert("age".to_owned(),24.to_string());
-        let new_expected: Vec<TableEntry> = vec![new_sort_entry(0, "Ada", 24), new_sort_entry(1, "Joey", 24), new_sort_entry(1, "Ross", 24)];
-        assert_eq!(clct.find(&new_target), Some(new_expected));
+        let mut new_expected: Vec<Record> = vec![new_sort_record(0, 0, "Ada", 24), new_sort_record(1, 1, "Joey", 24), new_sort_record(2, 1, "Ross", 24)];
+        // the updated items are at their second version
+        for item in new_expected[1..].iter_mut() {
+            item.insert("_version".to_owned(), Value::Int(2));
+        }
+        assert_eq!(clct.find(&Filter::from(&new_target)), Ok(new_expected));
     }
 
     #[test]
@@ -388,12 +299,73 @@ mod collection_tests {
 
         let mut target = TableEntry::new();
         target.insert("age".to_owned(), 25.to_string());
-        let expected: Vec<TableEntry> = vec![new_sort_entry(1, "Joey", 25), new_sort_entry(1, "Ross", 25)];
-        assert_eq!(clct.find(&target), Some(expected));
-        assert_eq!(clct.delete(&target), Some(2));
+        let expected: Vec<Record> = vec![new_sort_record(1, 1, "Joey", 25), new_sort_record(2, 1, "Ross", 25)];
+        assert_eq!(clct.find(&Filter::from(&target)), Ok(expected));
+        assert_eq!(clct.delete(&Filter::from(&target)), Ok(2));
 
         let empty_vector = Vec::new();
-        assert_eq!(clct.find(&target), Some(empty_vector));
+        assert_eq!(clct.find(&Filter::from(&target)), Ok(empty_vector));
+    }
+
+    #[test]
+    fn boolean_filter_test(){
+        let mut clct = new_collection();
+        clct.insert(&new_sort_entry(0, "Ada", 24));
+        clct.insert(&new_sort_entry(1, "Joey", 25));
+        clct.insert(&new_sort_entry(2, "Ross", 26));
+
+        let target = Filter::parse("(name Ada OR name Ross) AND NOT id 2", 1).unwrap();
+        assert_eq!(clct.find(&target), Ok(vec![new_sort_record(0, 0, "Ada", 24)]));
+
+        let mut update_desired = TableEntry::new();
+        update_desired.insert("age".to_owned(), 30.to_string());
+        assert_eq!(clct.update(&Filter::parse("name Joey OR id 2", 1).unwrap(), &update_desired), Ok(2));
+        assert_eq!(clct.delete(&Filter::parse("NOT age 30", 1).unwrap()), Ok(1));
+        let mut updated = new_sort_record(2, 2, "Ross", 30);
+        updated.insert("_version".to_owned(), Value::Int(2));
+        assert_eq!(clct.find(&Filter::parse("name Ross", 1).unwrap()), Ok(vec![updated]));
+    }
+
+    #[test]
+    fn aggregate_test(){
+        let mut clct = new_collection();
+        clct.insert(&new_sort_entry(0, "Ada", 24));
+        clct.insert(&new_sort_entry(1, "Joey", 25));
+        clct.insert(&new_sort_entry(2, "Ross", 25));
+        clct.insert(&new_sort_entry(3, "Amy", 26));
+        clct.insert(&new_sort_entry(4, "Ben", 25));
+
+        let mut aggregate = Aggregate::new();
+        assert!(aggregate.set("GROUP", &["BY".to_owned(), "age".to_owned()]).is_ok());
+        assert!(aggregate.set("MAX", &["name".to_owned()]).is_ok());
+        assert!(aggregate.set("COUNT", &[]).is_ok());
+        let grouped = clct.aggregate(&Filter::parse("NOT name Ada", 1).unwrap(), &aggregate).unwrap();
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[0]["age"], Some(Value::from("25")));
+        assert_eq!(grouped[0]["count"], Some(Value::Int(3)));
+        assert_eq!(grouped[0]["max(name)"], Some(Value::from("Ross")));
+        assert!(clct.aggregate(&Filter::parse("gender f", 1).unwrap(), &aggregate).is_err());
+    }
+
+    #[test]
+    fn large_scan_test(){
+        // many more items than threads, in many chunks
+        let mut clct = new_collection();
+        let mut sequential = vec_dbcollection::Collection::new(&new_collection_fields());
+        for n in 0..CHUNK_ITEMS * 20 + 7 {
+            clct.insert(&new_sort_entry(n, "Ada", n % 50)).unwrap();
+            sequential.insert(&new_sort_entry(n, "Ada", n % 50)).unwrap();
+        }
+        let target = Filter::parse("age 7 OR age 42", 1).unwrap();
+        let ids = |found: Vec<Record>| found.iter().map(|item| item["_id"].clone()).collect::<Vec<Value>>();
+        let found = ids(clct.find(&target).unwrap());
+        assert_eq!(found.len(), (CHUNK_ITEMS * 20 + 7) / 25);
+        assert_eq!(found, ids(sequential.find(&target).unwrap()));
+
+        let mut update_desired = TableEntry::new();
+        update_desired.insert("name".to_owned(), "Bob".to_owned());
+        assert_eq!(clct.update(&target, &update_desired), Ok(found.len()));
+        assert_eq!(ids(clct.find(&Filter::parse("name Bob", 1).unwrap()).unwrap()), found);
     }
 
     fn new_sort_entry(id: usize, name: &str, age: usize) -> TableEntry{
@@ -405,6 +377,17 @@ mod collection_tests {
     }
 
 
+    fn new_sort_record(row: i64, id: usize, name: &str, age: usize) -> Record{
+        let mut entry = Record::new();
+        entry.insert("_id".to_owned(), Value::Int(row));
+        entry.insert("_version".to_owned(), Value::Int(1));
+        entry.insert("id".to_owned(), Value::Str(id.to_string()));
+        entry.insert("name".to_owned(), Value::from(name));
+        entry.insert("age".to_owned(), Value::Str(age.to_string()));
+        entry
+    }
+
+
     fn new_long_entry(id: usize, name: &str, age: usize, sex: &str) -> TableEntry{
         let mut entry = TableEntry::new();
         entry.insert("id".to_owned(), id.to_string());
@@ -414,12 +397,18 @@ mod collection_tests {
         entry
     }
 
+    // every field holds text
     fn new_collection() -> Collection {
+        let schema: Schema = new_collection_fields().into_iter().map(|field| (field, FieldType::Str)).collect();
+        Collection::with_constraints(&schema, &Constraints::new())
+    }
+
+    fn new_collection_fields() -> Set<String> {
         let mut set: Set<String> = Set::new();
         set.insert("id".to_owned());
         set.insert("name".to_owned());
         set.insert("age".to_owned());
-        Collection::new(&set)
+        set
     }
 
     // fn equal_vec_entry(obj1: Option<Vec<TableEntry>>, obj2: Option<Vec<TableEntry>>) -> bool{
@@ -1,30 +1,51 @@
-use std::collections::{HashMap, BTreeSet};
+use std::collections::{HashMap, BTreeMap, BTreeSet};
+use rustc_serialize::{Decodable, Encodable, Encoder};
+use rustc_serialize::json::{self, Json, EncoderError};
 // use std::thread;
 // use std::fmt::{Display};
+use value::{Value, FieldType, Schema, Constraints, ID_FIELD, VERSION_FIELD, NULL_TEXT, field_type};
+use filter::Filter;
+use query::Query;
+use aggregate::{Aggregate, Summary};
+use index::{Index, IndexKind, Indexes, Rows, add_record, remove_record, candidates};
+use mvcc::{Readers, Snapshot};
+use backend::{CollectionBackend, BackendKind, Backend};
+use error::DbError;
+use alter::{Alteration, alter_schema};
 
-pub type TableEntry = HashMap<String, String>;
+pub type TableEntry = HashMap<String, String>;      // values as sent by the client
+pub type Record = HashMap<String, Value>;           // values coerced to the field types
 pub type Set<K> = BTreeSet<K>;
 
-#[derive(Debug, RustcDecodable, RustcEncodable)]
+// one version of an item, seen by the snapshots from created on until it expired
+#[derive(Debug, Clone)]
 pub struct ItemNode {
     valid: bool,
-    content: TableEntry,
+    content: Record,
+    created: u64,               // version of the write that created it
+    expired: Option<u64>,       // version of the write that replaced or deleted it
 }
 
 impl ItemNode {
-    pub fn new(entry: &TableEntry) -> Self {
+    pub fn new(entry: &Record) -> Self {
         ItemNode {
             valid: true,
             content: entry.to_owned(),
+            created: 0,
+            expired: None,
         }
     }
 
+    pub fn visible_at(&self, version: u64) -> bool {
+        self.valid && self.created <= version && self.expired.map_or(true, |expired| expired > version)
+    }
+
     #[allow(dead_code)]
     pub fn is_valid(&self) -> bool {
         self.valid
     }
 
-    pub fn get_content(&self) -> &TableEntry{
+    pub fn get_content(&self) -> &Record{
         return &self.content;
     }
 
@@ -34,7 +55,7 @@ impl ItemNode {
     }
 
     #[allow(dead_code)]
-    pub fn update_field(&mut self, field_name: String, field_value: String) -> Result<(), &str>{
+    pub fn update_field(&mut self, field_name: String, field_value: Value) -> Result<(), &str>{
         if let Some(x) = self.content.get_mut(&field_name) {
             *x = field_value;
             Ok(())
@@ -45,141 +66,613 @@ impl ItemNode {
     }
 
 
-    pub fn matched(&self, template: &TableEntry) -> bool{
-        for key in template.keys() {
-            if self.content.get(key) != template.get(key){
-                return false;
-            }
+    pub fn matched(&self, filter: &Filter<Value>) -> bool{
+        filter.matches(&self.content)
+    }
+
+
+    // the template comes from to_record, so only fields of the schema are written
+    pub fn modify(&mut self, template: &Record) {
+        for (key, value) in template.iter() {
+            self.content.insert(key.clone(), value.clone());
         }
-        true
     }
 
+    // the item after ALTER, the version is kept since the values are not written
+    pub fn alter(&mut self, alterations: &[Alteration]) {
+        for alteration in alterations {
+            alteration.apply(&mut self.content);
+        }
+    }
+
+    // modify, clear the nullable fields set to NULL, and count the write in the version of the item
+    pub fn revise(&mut self, changes: &Changes) {
+        let written = match self.content.get(VERSION_FIELD) {
+            Some(&Value::Int(written)) => written,
+            _ => 1,
+        };
+        self.modify(&changes.values);
+        for field in changes.cleared.iter() {
+            self.content.remove(field);
+        }
+        self.content.insert(VERSION_FIELD.to_owned(), Value::Int(written + 1));
+    }
+
+    // read back a stored item, values are typed through the collection schema
+    fn from_json(json: &Json, fields: &Schema) -> Result<ItemNode, String> {
+        let valid = json.find("valid").and_then(|v| v.as_boolean()).unwrap_or(true);
+        let content = match json.find("content") {
+            Some(&Json::Object(ref object)) => object,
+            _ => return Err("item without content".to_owned()),
+        };
+        let mut record = Record::new();
+        for (key, stored) in content.iter() {
+            let field_type = match field_type(fields, key) {
+                Some(field_type) => field_type,
+                None => return Err(format!("field {} is not in the schema", key)),
+            };
+            match field_type.from_json(stored) {
+                Some(value) => record.insert(key.clone(), value),
+                None => return Err(format!("value {} of field {} is not a {}", stored, key, field_type.name())),
+            };
+        }
+        Ok(ItemNode {
+            valid: valid,
+            content: record,
+            created: 0,
+            expired: None,
+        })
+    }
+}
+
+// stored without its versions, a loaded item is the first version of its row
+impl Encodable for ItemNode {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("ItemNode", 2, |s| {
+            try!(s.emit_struct_field("valid", 0, |s| self.valid.encode(s)));
+            s.emit_struct_field("content", 1, |s| self.content.encode(s))
+        })
+    }
+}
+
+// the values an APPEND or UPDATE writes and the nullable fields it sets to NULL
+#[derive(Debug, Clone, PartialEq)]
+pub struct Changes {
+    pub values: Record,
+    pub cleared: BTreeSet<String>,
+}
 
-    pub fn modify(&mut self, template: &TableEntry) {
-        for key in template.keys() {
-            let val = self.content.entry(key.clone()).or_insert("".to_owned());
-            *val = template.get(key).unwrap().clone();
+// coerce the values into the field types, an unknown field is told before a value that does not fit
+pub fn to_changes(fields: &Schema, constraints: &Constraints, target: &TableEntry) -> Result<Changes, DbError> {
+    if let Some(unknown) = target.keys().find(|key| !fields.contains_key(*key)) {
+        return Err(DbError::UnknownField(unknown.clone()));
+    }
+    let mut changes = Changes {
+        values: Record::new(),
+        cleared: BTreeSet::new(),
+    };
+    for (key, text) in target.iter() {
+        if text == NULL_TEXT {
+            if !constraints.nullable.contains(key) {
+                return Err(DbError::NotNullable(key.clone()));
+            }
+            changes.cleared.insert(key.clone());
+            continue;
         }
+        match fields[key].coerce(text) {
+            Some(value) => changes.values.insert(key.clone(), value),
+            None => return Err(DbError::TypeMismatch(key.clone(), text.clone())),
+        };
     }
+    Ok(changes)
 }
 
-pub type EntryList = Vec<Box<ItemNode>>;
+// the record of a new item: fields left out get their default, a nullable field given NULL stays without a value,
+// then every required field must have a value
+pub fn to_new_record(fields: &Schema, constraints: &Constraints, target: &TableEntry) -> Result<Record, DbError> {
+    let changes = try!(to_changes(fields, constraints, target));
+    let mut record = changes.values;
+    for (field, text) in constraints.defaults.iter() {
+        if !record.contains_key(field) && !changes.cleared.contains(field) {
+            match fields.get(field).and_then(|field_type| field_type.coerce(text)) {
+                Some(value) => record.insert(field.clone(), value),
+                None => return Err(DbError::TypeMismatch(field.clone(), text.clone())),
+            };
+        }
+    }
+    match missing_field(constraints, &record) {
+        Some(field) => Err(DbError::MissingField(field.clone())),
+        None => Ok(record),
+    }
+}
 
-#[derive(Debug, RustcDecodable, RustcEncodable)]
+// a required field the item has no value for
+pub fn missing_field<'a>(constraints: &'a Constraints, record: &Record) -> Option<&'a String> {
+    constraints.required.iter().find(|field| !record.contains_key(*field))
+}
+
+// versions of an item, oldest first
+pub type Versions = Vec<Box<ItemNode>>;
+// items by row number, rows are handed out in insert order and never reused
+pub type EntryList = BTreeMap<u64, Versions>;
+
+// every write gets the next version, readers see the items as of a version (see mvcc.rs)
+#[derive(Debug, Clone)]
 pub struct Collection{
-    fields: Set<String>,
+    fields: Schema,
+    constraints: Constraints,
     entries: EntryList,
+    next_row: u64,
+    indexes: Indexes,
+    version: u64,               // version of the last write
+    epoch: u64,                 // one more with every ALTER, see mvcc.rs
+    expired: Set<u64>,          // rows with versions the collector may drop
+    readers: Readers,           // shared with the copies of a transaction
 }
 
 impl Collection{
+    // every field holds text
     pub fn new(fields: &Set<String>) -> Self {
+        let schema: Schema = fields.iter().map(|f| (f.clone(), FieldType::Str)).collect();
+        Collection::with_schema(&schema)
+    }
+
+    pub fn with_schema(fields: &Schema) -> Self {
         Collection {
             fields: fields.to_owned(),
-            entries: EntryList::new()
+            constraints: Constraints::new(),
+            entries: EntryList::new(),
+            next_row: 0,
+            indexes: Indexes::new(),
+            version: 0,
+            epoch: 0,
+            expired: Set::new(),
+            readers: Readers::new(),
         }
     }
 
-    #[allow(dead_code)]
-    pub fn get_number_of_data(&self) -> usize{
-        self.entries.len()
+    // unique fields get a hash index, so a duplicate or a lookup by primary key takes one probe
+    pub fn with_constraints(fields: &Schema, constraints: &Constraints) -> Result<Self, DbError> {
+        let mut collection = Collection::with_schema(fields);
+        for field in constraints.primary_key.iter().chain(constraints.unique.iter()) {
+            if !fields.contains_key(field) {
+                return Err(DbError::UnknownField(field.clone()));
+            }
+            collection.indexes.entry(field.clone()).or_insert_with(|| Index::new(IndexKind::Hash));
+        }
+        collection.constraints = constraints.clone();
+        if let Some(ref key) = constraints.primary_key {
+            collection.constraints.unique.insert(key.clone());
+        }
+        Ok(collection)
+    }
+
+    pub fn get_next_row(&self) -> u64 {
+        self.next_row
     }
 
-    fn is_valid(&self,  target: &TableEntry) -> bool {
-        for key in target.keys() {
-            println!("key is :{:?}", key);
+    pub fn get_indexes(&self) -> &Indexes{
+        return &self.indexes;
+    }
+
+    // a reader sees the collection as of now until the snapshot is dropped
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(&self.readers, self.version, self.epoch)
+    }
+
+    pub fn get_epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    fn next_version(&mut self) -> u64 {
+        self.version += 1;
+        self.version
+    }
+
+    // the version of a row a snapshot sees, None when the row did not exist or was deleted then
+    fn version_at(&self, row: u64, version: u64) -> Option<&ItemNode> {
+        self.entries.get(&row)
+            .and_then(|versions| versions.iter().rev().find(|item| item.visible_at(version)))
+            .map(|item| &**item)
+    }
+
+    // one of the rows is not at the expected version anymore
+    fn is_stale(&self, rows: &[u64], expected: Option<u64>) -> bool {
+        match expected {
+            None => false,
+            Some(expected) => rows.iter().any(|row| {
+                self.version_at(*row, self.version).and_then(|item| item.content.get(VERSION_FIELD)) != Some(&Value::Int(expected as i64))
+            }),
         }
-        for key in target.keys() {
-            if !self.fields.contains(key){
-                return false;
+    }
+
+    // the unique field of record holding a value some row other than except already has,
+    // an index also holds the values of older versions so the latest one is checked
+    fn duplicate_of(&self, record: &Record, except: Option<u64>) -> Option<String> {
+        for field in self.constraints.unique.iter() {
+            if let (Some(value), Some(index)) = (record.get(field), self.indexes.get(field)) {
+                let taken = index.rows_of(value).into_iter().any(|row| {
+                    Some(row) != except && self.version_at(row, self.version).map_or(false, |item| item.content.get(field) == Some(value))
+                });
+                if taken {
+                    return Some(field.clone());
+                }
             }
         }
-        return true;
+        None
     }
 
-    pub fn get_fields(&self) -> &Set<String>{
-        return &self.fields;
+    // rows of the items matching the filter as of version, narrowed down by the indexes when one applies
+    fn matching_rows(&self, target: &Filter<Value>, version: u64) -> Vec<u64> {
+        let matched = |row: &u64| self.version_at(*row, version).map_or(false, |item| item.matched(target));
+        match candidates(&self.indexes, target) {
+            Some(rows) => rows.into_iter().filter(matched).collect(),
+            None => self.entries.keys().cloned().filter(matched).collect(),
+        }
     }
 
-    pub fn get_entries(&self) -> &EntryList{
-        return &self.entries;
+    // the rows an index narrows the filter down to, None when every row has to be looked at
+    pub fn candidate_rows(&self, target: &Filter<Value>) -> Option<Rows> {
+        candidates(&self.indexes, target)
     }
 
+    // at most limit rows from the row from on
+    pub fn rows_from(&self, from: u64, limit: usize) -> Vec<u64> {
+        self.entries.range(from..).take(limit).map(|(row, _)| *row).collect()
+    }
+
+    // the items of the rows a snapshot sees that match the filter
+    pub fn read_at(&self, target: &Filter<Value>, version: u64, rows: &[u64]) -> Vec<Record> {
+        rows.iter()
+            .filter_map(|row| self.version_at(*row, version))
+            .filter(|item| item.matched(target))
+            .map(|item| item.content.clone())
+            .collect()
+    }
 
-    pub fn insert(&mut self, desired: &TableEntry) -> Result<&'static str, &'static str>{
-        if self.is_valid(desired) {
-            self.entries.push(Box::new(ItemNode::new(desired)));
-            return Ok("Insert Success");
+    // read back a stored collection, a list of field names is a collection written before fields had types
+    // and a list of items one written before items had row numbers
+    pub fn from_json(json: &Json) -> Result<Collection, String> {
+        let mut fields = Schema::new();
+        match json.find("fields") {
+            Some(&Json::Object(ref object)) => {
+                for (name, type_name) in object.iter() {
+                    match type_name.as_string().and_then(FieldType::from_name) {
+                        Some(field_type) => fields.insert(name.clone(), field_type),
+                        None => return Err(format!("unknown type {} of field {}", type_name, name)),
+                    };
+                }
+            },
+            Some(&Json::Array(ref names)) => {
+                for name in names.iter() {
+                    match name.as_string() {
+                        Some(name) => fields.insert(name.to_owned(), FieldType::Str),
+                        None => return Err(format!("invalid field name {}", name)),
+                    };
+                }
+            },
+            _ => return Err("collection without fields".to_owned()),
+        }
+        let constraints = match json.find("constraints") {
+            Some(stored) => match Constraints::decode(&mut json::Decoder::new(stored.clone())) {
+                Ok(constraints) => constraints,
+                Err(e) => return Err(format!("invalid constraints: {}", e)),
+            },
+            None => Constraints::new(),
+        };
+        let mut collection = try!(Collection::with_constraints(&fields, &constraints).map_err(|e| e.to_string()));
+        // indexes are declared before the items are read, their entries are rebuilt below
+        if let Some(&Json::Object(ref indexes)) = json.find("indexes") {
+            for (field, kind) in indexes.iter() {
+                let kind = match kind.as_string().and_then(IndexKind::from_name) {
+                    Some(kind) => kind,
+                    None => return Err(format!("unknown index kind {} of field {}", kind, field)),
+                };
+                if !collection.indexes.contains_key(field) {
+                    try!(collection.create_index(field, kind).map_err(|e| format!("index on {}: {}", field, e)));
+                }
+            }
         }
-        else{
-            return Err("Format Invalid");
+        match json.find("entries") {
+            Some(&Json::Object(ref items)) => {
+                for (row, item) in items.iter() {
+                    let row = match row.parse::<u64>() {
+                        Ok(row) => row,
+                        Err(_) => return Err(format!("invalid row number {}", row)),
+                    };
+                    collection.entries.insert(row, vec![Box::new(try!(ItemNode::from_json(item, &fields)))]);
+                }
+            },
+            Some(&Json::Array(ref items)) => {
+                for (row, item) in items.iter().enumerate() {
+                    collection.entries.insert(row as u64, vec![Box::new(try!(ItemNode::from_json(item, &fields)))]);
+                }
+            },
+            _ => return Err("collection without entries".to_owned()),
         }
+        let after_last = collection.entries.keys().next_back().map(|row| row + 1).unwrap_or(0);
+        collection.next_row = match json.find("next_row").and_then(|n| n.as_u64()) {
+            Some(next_row) if next_row >= after_last => next_row,
+            _ => after_last,
+        };
+        // items written before they had ids get their row number, before they had versions the first version;
+        // every item is checked against the ones indexed before it
+        let rows: Vec<u64> = collection.entries.keys().cloned().collect();
+        for row in rows {
+            let mut content = collection.entries[&row][0].content.clone();
+            if let Some(field) = missing_field(&constraints, &content) {
+                return Err(format!("item {} has no value for required field {}", row, field));
+            }
+            if let Some(field) = collection.duplicate_of(&content, Some(row)) {
+                return Err(format!("item {} repeats a value of unique field {}", row, field));
+            }
+            content.entry(ID_FIELD.to_owned()).or_insert(Value::Int(row as i64));
+            content.entry(VERSION_FIELD.to_owned()).or_insert(Value::Int(1));
+            add_record(&mut collection.indexes, row, &content);
+            collection.entries.get_mut(&row).unwrap()[0].content = content;
+        }
+        Ok(collection)
     }
+}
 
+impl CollectionBackend for Collection{
+    fn kind(&self) -> BackendKind {
+        BackendKind::Indexed
+    }
 
-    pub fn update(&mut self, target: &TableEntry, desired: &TableEntry) -> Option<usize>{
-        if !self.is_valid(target) || !self.is_valid(desired) {
-            None
-        } else {
-            let mut count = 0;
-            
-            for item in self.entries.iter_mut(){
-                if (*item).matched(target) {
-                    (*item).modify(desired);
-                    count += 1;
-                }
+    fn get_fields(&self) -> &Schema{
+        return &self.fields;
+    }
+
+    fn get_constraints(&self) -> &Constraints{
+        return &self.constraints;
+    }
+
+    fn get_number_of_data(&self) -> usize{
+        self.entries.keys().filter(|row| self.version_at(**row, self.version).is_some()).count()
+    }
+
+    // the latest version of every item
+    fn get_items(&self) -> Vec<Record>{
+        self.entries.keys().filter_map(|row| self.version_at(*row, self.version)).map(|item| item.content.clone()).collect()
+    }
+
+    fn visit_items(&self, skip: usize, take: usize, visit: &mut dyn FnMut(&Record)) {
+        for item in self.entries.keys().filter_map(|row| self.version_at(*row, self.version)).skip(skip).take(take) {
+            visit(&item.content);
+        }
+    }
+
+    // the id of the new item
+    fn insert(&mut self, desired: &TableEntry) -> Result<u64, DbError>{
+        let mut record = try!(to_new_record(&self.fields, &self.constraints, desired));
+        if let Some(ref key) = self.constraints.primary_key {
+            if !record.contains_key(key) {
+                return Err(DbError::MissingKey(key.clone()));
             }
-            Some(count)
         }
+        if let Some(field) = self.duplicate_of(&record, None) {
+            return Err(DbError::DuplicateKey(field));
+        }
+        let row = self.next_row;
+        self.next_row += 1;
+        record.insert(ID_FIELD.to_owned(), Value::Int(row as i64));
+        record.insert(VERSION_FIELD.to_owned(), Value::Int(1));
+        add_record(&mut self.indexes, row, &record);
+        let mut item = ItemNode::new(&record);
+        item.created = self.next_version();
+        self.entries.insert(row, vec![Box::new(item)]);
+        Ok(row)
     }
 
-    pub fn find(&self, target: &TableEntry) -> Option<Vec<TableEntry>> {
-        if !self.is_valid(target) {
-            None
-        } else {
+    fn update(&mut self, target: &Filter<String>, desired: &TableEntry) -> Result<usize, DbError>{
+        self.update_if(target, desired, None)
+    }
 
-            let mut res: Vec<TableEntry> = Vec::new();
-            
-            for item in &self.entries{
-                if item.matched(target) {
-                    res.push(item.content.clone())
-                }
+    // with an expected version nothing is changed unless every matching item is still at it
+    fn update_if(&mut self, target: &Filter<String>, desired: &TableEntry, expected: Option<u64>) -> Result<usize, DbError>{
+        let target = try!(target.bind(&self.fields));
+        let desired = try!(to_changes(&self.fields, &self.constraints, desired));
+        let rows = self.matching_rows(&target, self.version);
+        if self.is_stale(&rows, expected) {
+            return Err(DbError::StaleVersion);
+        }
+
+        // nothing is changed when one of the rows would break a unique field
+        if let Some(field) = desired.values.keys().find(|field| self.constraints.unique.contains(*field)) {
+            if rows.len() > 1 {
+                return Err(DbError::DuplicateKey(field.clone()));
+            }
+        }
+        if rows.len() == 1 {
+            if let Some(field) = self.duplicate_of(&desired.values, Some(rows[0])) {
+                return Err(DbError::DuplicateKey(field));
             }
-            Some(res)
         }
+
+        let mut count = 0;
+        let version = self.next_version();
+
+        // the old version stays, and stays indexed, for the snapshots taken before
+        for row in rows {
+            let versions = self.entries.get_mut(&row).unwrap();
+            let mut newer = versions.last().unwrap().clone();
+            newer.revise(&desired);
+            newer.created = version;
+            versions.last_mut().unwrap().expired = Some(version);
+            add_record(&mut self.indexes, row, &newer.content);
+            versions.push(newer);
+            self.expired.insert(row);
+            count += 1;
+        }
+        Ok(count)
     }
 
+    fn find(&self, target: &Filter<String>) -> Result<Vec<Record>, DbError> {
+        self.select(target, &Query::new())
+    }
 
-    pub fn delete(&mut self, target: &TableEntry) -> Option<usize>{
-        if !self.is_valid(target)  {
-            None
-        } else {
+    // the matching items shaped by the options of GET
+    fn select(&self, target: &Filter<String>, query: &Query) -> Result<Vec<Record>, DbError> {
+        let target = try!(target.bind(&self.fields));
+        try!(query.check(&self.fields).map_err(DbError::InvalidQuery));
+        let items = self.matching_rows(&target, self.version).into_iter().filter_map(|row| self.version_at(row, self.version)).map(|item| &item.content).collect();
+        Ok(query.apply(items))
+    }
 
-            let mut count = 0;
-            let mut index = 0;
+    // summaries of the matching items, summed up where they are stored without copying them
+    fn aggregate(&self, target: &Filter<String>, aggregate: &Aggregate) -> Result<Vec<Summary>, DbError> {
+        let target = try!(target.bind(&self.fields));
+        try!(aggregate.check(&self.fields).map_err(DbError::InvalidQuery));
+        let items = self.matching_rows(&target, self.version).into_iter().filter_map(|row| self.version_at(row, self.version)).map(|item| &item.content);
+        aggregate.run(items).map_err(DbError::InvalidQuery)
+    }
 
-            while index < self.entries.len() {
-                if self.entries[index].matched(target) {
-                    self.entries.remove(index);
-                    count += 1;
-                } else {
-                    index += 1;
+    fn delete(&mut self, target: &Filter<String>) -> Result<usize, DbError>{
+        self.delete_if(target, None)
+    }
+
+    // with an expected version nothing is deleted unless every matching item is still at it
+    fn delete_if(&mut self, target: &Filter<String>, expected: Option<u64>) -> Result<usize, DbError>{
+        let target = try!(target.bind(&self.fields));
+        let mut count = 0;
+        let rows = self.matching_rows(&target, self.version);
+        if self.is_stale(&rows, expected) {
+            return Err(DbError::StaleVersion);
+        }
+        let version = self.next_version();
+
+        // the item is only marked, the collector drops it once no snapshot sees it
+        for row in rows {
+            self.entries.get_mut(&row).unwrap().last_mut().unwrap().expired = Some(version);
+            self.expired.insert(row);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn create_index(&mut self, field: &str, kind: IndexKind) -> Result<&'static str, DbError>{
+        if !self.fields.contains_key(field) {
+            return Err(DbError::UnknownField(field.to_owned()));
+        }
+        if self.indexes.contains_key(field) {
+            return Err(DbError::IndexExists(field.to_owned()));
+        }
+        let mut index = Index::new(kind);
+        for (row, versions) in self.entries.iter() {
+            for item in versions.iter() {
+                if let Some(value) = item.content.get(field) {
+                    index.insert(value, *row);
                 }
             }
-            Some(count)
         }
+        self.indexes.insert(field.to_owned(), index);
+        Ok("Index created")
+    }
+
+    fn drop_index(&mut self, field: &str) -> Result<&'static str, DbError>{
+        if self.constraints.unique.contains(field) {
+            return Err(DbError::ProtectedIndex(field.to_owned()));
+        }
+        match self.indexes.remove(field) {
+            Some(_) => Ok("Index dropped"),
+            None => Err(DbError::UnknownIndex(field.to_owned())),
+        }
+    }
+
+    // every version is altered in place, a reader bound to the old fields starts over (see mvcc.rs)
+    fn alter(&mut self, alterations: &[Alteration]) -> Result<(), DbError> {
+        let (fields, constraints) = try!(alter_schema(&self.fields, &self.constraints, alterations));
+        for versions in self.entries.values_mut() {
+            for item in versions.iter_mut() {
+                item.alter(alterations);
+            }
+        }
+        // an index follows its field
+        for alteration in alterations {
+            match *alteration {
+                Alteration::DropField(ref field) => {
+                    self.indexes.remove(field);
+                },
+                Alteration::RenameField(ref field, ref name) => {
+                    if let Some(index) = self.indexes.remove(field) {
+                        self.indexes.insert(name.clone(), index);
+                    }
+                },
+                Alteration::AddField(..) => (),
+            }
+        }
+        self.fields = fields;
+        self.constraints = constraints;
+        self.epoch += 1;
+        Ok(())
+    }
+
+    // drop the versions no snapshot can see anymore, the number of versions dropped
+    fn collect_garbage(&mut self) -> usize {
+        let oldest = self.readers.oldest().unwrap_or(self.version);
+        let mut dropped = 0;
+        let rows: Vec<u64> = self.expired.iter().cloned().collect();
+        for row in rows {
+            let versions = self.entries.remove(&row).unwrap_or_else(Versions::new);
+            let (gone, kept): (Versions, Versions) = versions.into_iter().partition(|item| item.expired.map_or(false, |expired| expired <= oldest));
+            for item in gone.iter() {
+                remove_record(&mut self.indexes, row, &item.content);
+            }
+            // a value the dropped versions share with a kept one is indexed again
+            for item in kept.iter() {
+                add_record(&mut self.indexes, row, &item.content);
+            }
+            dropped += gone.len();
+            if kept.iter().all(|item| item.expired.is_none()) {
+                self.expired.remove(&row);
+            }
+            if !kept.is_empty() {
+                self.entries.insert(row, kept);
+            }
+        }
+        dropped
+    }
+
+    fn multi_version(&self) -> Option<&Collection> {
+        Some(self)
+    }
+
+    fn box_clone(&self) -> Backend {
+        Box::new(self.clone())
+    }
+
+    fn to_json(&self) -> Result<String, EncoderError> {
+        json::encode(self)
+    }
+}
+
+// only the latest version of an item is stored, like a derived encoding otherwise
+impl Encodable for Collection {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let items: BTreeMap<u64, &ItemNode> = self.entries.keys()
+            .filter_map(|row| self.version_at(*row, self.version).map(|item| (*row, item)))
+            .collect();
+        s.emit_struct("Collection", 5, |s| {
+            try!(s.emit_struct_field("fields", 0, |s| self.fields.encode(s)));
+            try!(s.emit_struct_field("constraints", 1, |s| self.constraints.encode(s)));
+            try!(s.emit_struct_field("entries", 2, |s| items.encode(s)));
+            try!(s.emit_struct_field("next_row", 3, |s| self.next_row.encode(s)));
+            s.emit_struct_field("indexes", 4, |s| self.indexes.encode(s))
+        })
     }
 }
 
 
 impl PartialEq for Collection {
     fn eq(&self, other: &Self) -> bool {
-        for key in &other.fields {
-            if !self.fields.contains(key){
+        for (key, field_type) in &other.fields {
+            if self.fields.get(key) != Some(field_type){
                 return false;
             }
         }
-        for key in &self.fields {
-            if !other.fields.contains(key){
+        for key in self.fields.keys() {
+            if !other.fields.contains_key(key){
                 return false;
             }
         }
@@ -191,7 +684,11 @@ impl PartialEq for Collection {
 
 mod itemnode_tests {
     #[allow(unused_imports)]
-    use super::{ItemNode, TableEntry};
+    use super::{ItemNode, Record};
+    #[allow(unused_imports)]
+    use value::{Value, FieldType, Schema};
+    #[allow(unused_imports)]
+    use filter::Filter;
 
     #[test]
     fn node_validate_test() {
@@ -204,20 +701,14 @@ mod itemnode_tests {
     #[test]
     fn node_matches_test() {
 
-        let mut node = ItemNode::new(&new_table_entry(0, "Ada", 24));
+        let node = ItemNode::new(&new_table_entry(0, "Ada", 24));
         assert!(node.is_valid());
 
-        let mut matched = new_table_entry(0, "Ada", 24);
-        assert!(node.matched(&matched));
-        matched.remove(&String::from("age"));
-        assert!(node.matched(&matched));
+        assert!(node.matched(&filter_of("id 0; name Ada; age 24")));
+        assert!(node.matched(&filter_of("id 0; name Ada")));
 
-        
-        let mut non_matched = new_table_entry(0, "Joey", 24);
-        assert!(!node.matched(&non_matched));
-        non_matched.insert("name".to_owned(), "Ada".to_owned());
-        non_matched.insert("sex".to_owned(), "female".to_owned());
-        assert!(!node.matched(&non_matched));
+        assert!(!node.matched(&filter_of("id 0; name Joey; age 24")));
+        assert!(!node.matched(&filter_of("name Ada; sex female")));
 
     }
 
@@ -236,19 +727,44 @@ mod itemnode_tests {
     }
 
     #[allow(dead_code)]
-    fn new_table_entry(id: usize, name: &str, age: usize) -> TableEntry{
-        let mut entry = TableEntry::new();
-        entry.insert("id".to_owned(), id.to_string());
-        entry.insert("name".to_owned(), name.to_string());
-        entry.insert("age".to_owned(), age.to_string());
+    fn new_table_entry(id: usize, name: &str, age: usize) -> Record{
+        let mut entry = Record::new();
+        entry.insert("id".to_owned(), Value::Str(id.to_string()));
+        entry.insert("name".to_owned(), Value::Str(name.to_string()));
+        entry.insert("age".to_owned(), Value::Str(age.to_string()));
         entry
     }
+
+    #[allow(dead_code)]
+    fn filter_of(line: &str) -> Filter<Value> {
+        let mut fields = Schema::new();
+        for field in vec!["id", "name", "age", "sex"] {
+            fields.insert(field.to_owned(), FieldType::Str);
+        }
+        Filter::parse(line, 1).unwrap().bind(&fields).unwrap()
+    }
 }
 
 
 mod collection_tests {
     #[allow(unused_imports)]
-    use super::{Collection, ItemNode, TableEntry, Set};
+    use super::{Collection, ItemNode, TableEntry, Record, Set};
+    #[allow(unused_imports)]
+    use value::{Value, FieldType, Schema, Constraints};
+    #[allow(unused_imports)]
+    use filter::Filter;
+    #[allow(unused_imports)]
+    use query::Query;
+    #[allow(unused_imports)]
+    use aggregate::Aggregate;
+    #[allow(unused_imports)]
+    use index::IndexKind;
+    #[allow(unused_imports)]
+    use rustc_serialize::json::{self, Json};
+    #[allow(unused_imports)]
+    use backend::CollectionBackend;
+    #[allow(unused_imports)]
+    use error::DbError;
 
     #[test]
     fn insert_test() {
@@ -272,12 +788,12 @@ mod collection_tests {
 
         let mut target = TableEntry::new();
         target.insert("age".to_owned(), 25.to_string());
-        let expected: Vec<TableEntry> = vec![new_sort_entry(1, "Joey", 25), new_sort_entry(1, "Ross", 25)];
+        let expected: Vec<Record> = vec![new_sort_record(1, 1, "Joey", 25), new_sort_record(2, 1, "Ross", 25)];
 
-        assert_eq!(clct.find(&target), Some(expected));
+        assert_eq!(clct.find(&Filter::from(&target)), Ok(expected));
 
         let mut non_valid = new_long_entry(0, "Ada", 24, "female");
-        assert_eq!(clct.find(&non_valid), None);
+        assert_eq!(clct.find(&Filter::from(&non_valid)), Err(DbError::UnknownField("gender".to_owned())));
     }
 
     #[test]
@@ -289,20 +805,24 @@ mod collection_tests {
 
         let mut target = TableEntry::new();
         target.insert("age".to_owned(), 25.to_string());
-        let expected: Vec<TableEntry> = vec![new_sort_entry(1, "Joey", 25), new_sort_entry(1, "Ross", 25)];
-        assert_eq!(clct.find(&target), Some(expected));
+        let expected: Vec<Record> = vec![new_sort_record(1, 1, "Joey", 25), new_sort_record(2, 1, "Ross", 25)];
+        assert_eq!(clct.find(&Filter::from(&target)), Ok(expected));
 
         let mut update_desired = TableEntry::new();
         update_desired.insert("age".to_owned(),24.to_string());
-        assert_eq!(clct.update(&target,&update_desired), Some(2));
+        assert_eq!(clct.update(&Filter::from(&target),&update_desired), Ok(2));
 
         let empty_vector = Vec::new();
-        assert_eq!(clct.find(&target), Some(empty_vector));
+        assert_eq!(clct.find(&Filter::from(&target)), Ok(empty_vector));
 
         let mut new_target = TableEntry::new();
         new_target.insert("age".to_owned(),24.to_string());
-        let new_expected: Vec<TableEntry> = vec![new_sort_entry(0, "Ada", 24), new_sort_entry(1, "Joey", 24), new_sort_entry(1, "Ross", 24)];
-        assert_eq!(clct.find(&new_target), Some(new_expected));
+        let mut new_expected: Vec<Record> = vec![new_sort_record(0, 0, "Ada", 24), new_sort_record(1, 1, "Joey", 24), new_sort_record(2, 1, "Ross", 24)];
+        // the updated items are at their second version
+        for item in new_expected[1..].iter_mut() {
+            item.insert("_version".to_owned(), Value::Int(2));
+        }
+        assert_eq!(clct.find(&Filter::from(&new_target)), Ok(new_expected));
     }
 
     #[test]
@@ -314,12 +834,12 @@ mod collection_tests {
 
         let mut target = TableEntry::new();
         target.insert("age".to_owned(), 25.to_string());
-        let expected: Vec<TableEntry> = vec![new_sort_entry(1, "Joey", 25), new_sort_entry(1, "Ross", 25)];
-        assert_eq!(clct.find(&target), Some(expected));
-        assert_eq!(clct.delete(&target), Some(2));
+        let expected: Vec<Record> = vec![new_sort_record(1, 1, "Joey", 25), new_sort_record(2, 1, "Ross", 25)];
+        assert_eq!(clct.find(&Filter::from(&target)), Ok(expected));
+        assert_eq!(clct.delete(&Filter::from(&target)), Ok(2));
 
         let empty_vector = Vec::new();
-        assert_eq!(clct.find(&target), Some(empty_vector));
+        assert_eq!(clct.find(&Filter::from(&target)), Ok(empty_vector));
     }
 
     #[allow(dead_code)]
@@ -331,6 +851,242 @@ mod collection_tests {
         entry
     }
 
+    #[test]
+    fn typed_fields_test(){
+        let mut schema = Schema::new();
+        schema.insert("name".to_owned(), FieldType::Str);
+        schema.insert("age".to_owned(), FieldType::Int);
+        schema.insert("active".to_owned(), FieldType::Bool);
+        let mut clct = Collection::with_schema(&schema);
+
+        let mut entry = TableEntry::new();
+        entry.insert("name".to_owned(), "Ada".to_owned());
+        entry.insert("age".to_owned(), "9".to_owned());
+        entry.insert("active".to_owned(), "true".to_owned());
+        assert!(clct.insert(&entry).is_ok());
+        entry.insert("age".to_owned(), "ten".to_owned());
+        assert!(clct.insert(&entry).is_err());
+
+        let mut target = TableEntry::new();
+        target.insert("age".to_owned(), "09".to_owned());
+        let found = clct.find(&Filter::from(&target)).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].get("age"), Some(&Value::Int(9)));
+        assert_eq!(found[0].get("active"), Some(&Value::Bool(true)));
+
+        target.insert("active".to_owned(), "yes".to_owned());
+        assert_eq!(clct.find(&Filter::from(&target)), Err(DbError::TypeMismatch("active".to_owned(), "yes".to_owned())));
+
+        let older = Filter::parse("age > 8; name prefix A", 1).unwrap();
+        assert_eq!(clct.find(&older).unwrap().len(), 1);
+        assert!(clct.update(&older, &entry).is_err());
+        assert_eq!(clct.delete(&Filter::parse("age between 10 and 20", 1).unwrap()), Ok(0));
+
+        // a float without a JSON form is refused, so the snapshot can be read back
+        schema.insert("score".to_owned(), FieldType::Float);
+        let mut clct = Collection::with_schema(&schema);
+        let mut scored = TableEntry::new();
+        for score in vec!["inf", "-inf", "NaN"] {
+            scored.insert("score".to_owned(), score.to_owned());
+            assert_eq!(clct.insert(&scored), Err(DbError::TypeMismatch("score".to_owned(), score.to_owned())));
+        }
+        scored.insert("score".to_owned(), "2.5".to_owned());
+        assert!(clct.insert(&scored).is_ok());
+        let loaded = Collection::from_json(&Json::from_str(&json::encode(&clct).unwrap()).unwrap()).unwrap();
+        assert_eq!(loaded.get_items(), clct.get_items());
+    }
+
+    #[test]
+    fn index_test(){
+        let mut clct = new_collection();
+        clct.insert(&new_sort_entry(0, "Ada", 24));
+        clct.insert(&new_sort_entry(1, "Joey", 25));
+        assert!(clct.create_index("age", IndexKind::BTree).is_ok());
+        assert!(clct.create_index("age", IndexKind::Hash).is_err());
+        assert!(clct.create_index("gender", IndexKind::Hash).is_err());
+        clct.insert(&new_sort_entry(1, "Ross", 25));
+
+        let older = Filter::parse("age >= 25", 1).unwrap();
+        assert_eq!(clct.find(&older), Ok(vec![new_sort_record(1, 1, "Joey", 25), new_sort_record(2, 1, "Ross", 25)]));
+
+        let mut update_desired = TableEntry::new();
+        update_desired.insert("age".to_owned(), 20.to_string());
+        assert_eq!(clct.update(&Filter::parse("age 25 AND name Joey", 1).unwrap(), &update_desired), Ok(1));
+        assert_eq!(clct.delete(&Filter::parse("age < 21", 1).unwrap()), Ok(1));
+        assert_eq!(clct.find(&older), Ok(vec![new_sort_record(2, 1, "Ross", 25)]));
+
+        // the index definition is stored, its entries are rebuilt
+        let stored = Json::from_str(&json::encode(&clct).unwrap()).unwrap();
+        let loaded = Collection::from_json(&stored).unwrap();
+        assert_eq!(loaded.get_indexes().get("age").map(|index| index.kind()), Some(IndexKind::BTree));
+        assert_eq!(loaded.find(&older), Ok(vec![new_sort_record(2, 1, "Ross", 25)]));
+        assert!(clct.drop_index("age").is_ok());
+        assert!(clct.drop_index("age").is_err());
+    }
+
+    #[test]
+    fn id_test(){
+        let mut clct = new_collection();
+        assert_eq!(clct.insert(&new_sort_entry(0, "Ada", 24)), Ok(0));
+        assert_eq!(clct.insert(&new_sort_entry(1, "Joey", 25)), Ok(1));
+        assert_eq!(clct.insert(&new_sort_entry(1, "Ross", 25)), Ok(2));
+        assert_eq!(clct.delete(&Filter::parse("_id 1", 1).unwrap()), Ok(1));
+        // ids are not reused after a delete
+        assert_eq!(clct.insert(&new_sort_entry(1, "Joey", 25)), Ok(3));
+
+        assert_eq!(clct.find(&Filter::parse("_id 2", 1).unwrap()), Ok(vec![new_sort_record(2, 1, "Ross", 25)]));
+        assert_eq!(clct.find(&Filter::parse("_id in 1 3", 1).unwrap()), Ok(vec![new_sort_record(3, 1, "Joey", 25)]));
+        assert_eq!(clct.find(&Filter::parse("_id seven", 1).unwrap()), Err(DbError::TypeMismatch("_id".to_owned(), "seven".to_owned())));
+
+        let mut update_desired = TableEntry::new();
+        update_desired.insert("age".to_owned(), 30.to_string());
+        assert_eq!(clct.update(&Filter::parse("_id 0", 1).unwrap(), &update_desired), Ok(1));
+        let mut updated = new_sort_record(0, 0, "Ada", 30);
+        updated.insert("_version".to_owned(), Value::Int(2));
+        assert_eq!(clct.find(&Filter::parse("age 30", 1).unwrap()), Ok(vec![updated]));
+        // the id itself is not written by a client
+        update_desired.insert("_id".to_owned(), 9.to_string());
+        assert!(clct.update(&Filter::parse("_id 0", 1).unwrap(), &update_desired).is_err());
+        assert!(clct.insert(&update_desired).is_err());
+
+        let stored = Json::from_str(&json::encode(&clct).unwrap()).unwrap();
+        let mut loaded = Collection::from_json(&stored).unwrap();
+        assert_eq!(loaded.find(&Filter::parse("_id 2", 1).unwrap()), Ok(vec![new_sort_record(2, 1, "Ross", 25)]));
+        assert_eq!(loaded.insert(&new_sort_entry(4, "Amy", 20)), Ok(4));
+    }
+
+    #[test]
+    fn version_test(){
+        let mut clct = new_collection();
+        clct.insert(&new_sort_entry(0, "Ada", 24)).unwrap();
+        clct.insert(&new_sort_entry(1, "Joey", 25)).unwrap();
+        let ada = Filter::parse("_id 0", 1).unwrap();
+        let version_of = |clct: &Collection| clct.find(&Filter::parse("_id 0", 1).unwrap()).unwrap()[0].get("_version").cloned();
+        assert_eq!(version_of(&clct), Some(Value::Int(1)));
+
+        let mut update_desired = TableEntry::new();
+        update_desired.insert("age".to_owned(), 30.to_string());
+        assert_eq!(clct.update_if(&ada, &update_desired, Some(1)), Ok(1));
+        assert_eq!(version_of(&clct), Some(Value::Int(2)));
+        // a second writer that read version 1 is refused and changes nothing
+        update_desired.insert("age".to_owned(), 40.to_string());
+        assert_eq!(clct.update_if(&ada, &update_desired, Some(1)), Err(DbError::StaleVersion));
+        assert_eq!(clct.find(&Filter::parse("age 40", 1).unwrap()), Ok(vec![]));
+        assert_eq!(clct.delete_if(&Filter::new(), Some(1)), Err(DbError::StaleVersion));
+        assert_eq!(clct.get_number_of_data(), 2);
+
+        // the version is stored and can be filtered on
+        let stored = Json::from_str(&json::encode(&clct).unwrap()).unwrap();
+        let mut loaded = Collection::from_json(&stored).unwrap();
+        assert_eq!(version_of(&loaded), Some(Value::Int(2)));
+        assert_eq!(loaded.find(&Filter::parse("_version > 1", 1).unwrap()).unwrap().len(), 1);
+        assert_eq!(loaded.delete_if(&ada, Some(2)), Ok(1));
+    }
+
+    #[test]
+    fn select_test(){
+        let mut clct = new_collection();
+        clct.insert(&new_sort_entry(0, "Ada", 24));
+        clct.insert(&new_sort_entry(1, "Joey", 25));
+        clct.insert(&new_sort_entry(2, "Ross", 25));
+        clct.insert(&new_sort_entry(3, "Amy", 26));
+
+        let mut query = Query::new();
+        assert!(query.set("SORT", &["age".to_owned(), "desc".to_owned(), "name".to_owned()]).is_ok());
+        assert!(query.set("LIMIT", &["2".to_owned()]).is_ok());
+        assert!(query.set("OFFSET", &["1".to_owned()]).is_ok());
+        assert_eq!(clct.select(&Filter::new(), &query), Ok(vec![new_sort_record(1, 1, "Joey", 25), new_sort_record(2, 2, "Ross", 25)]));
+
+        assert!(query.set("FIELDS", &["name".to_owned()]).is_ok());
+        let names = clct.select(&Filter::parse("age < 26", 1).unwrap(), &query).unwrap();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[0].get("name"), Some(&Value::from("Ross")));
+        assert_eq!(names[0].get("age"), None);
+
+        assert!(query.set("SORT", &["gender".to_owned()]).is_ok());
+        assert!(clct.select(&Filter::new(), &query).is_err());
+    }
+
+    #[test]
+    fn aggregate_test(){
+        let mut schema = Schema::new();
+        schema.insert("name".to_owned(), FieldType::Str);
+        schema.insert("age".to_owned(), FieldType::Int);
+        let mut clct = Collection::with_schema(&schema);
+        for &(name, age) in [("Ada", 24), ("Joey", 25), ("Ross", 25), ("Amy", 40)].iter() {
+            let mut entry = TableEntry::new();
+            entry.insert("name".to_owned(), name.to_owned());
+            entry.insert("age".to_owned(), age.to_string());
+            clct.insert(&entry).unwrap();
+        }
+
+        let mut aggregate = Aggregate::new();
+        assert!(aggregate.set("SUM", &["age".to_owned()]).is_ok());
+        assert!(aggregate.set("DISTINCT", &["age".to_owned()]).is_ok());
+        let summary = clct.aggregate(&Filter::parse("age < 30", 1).unwrap(), &aggregate).unwrap();
+        assert_eq!(summary[0]["sum(age)"], Some(Value::Int(74)));
+        assert_eq!(summary[0]["distinct(age)"], Some(Value::Int(2)));
+
+        assert!(aggregate.set("AVG", &["name".to_owned()]).is_ok());
+        assert!(clct.aggregate(&Filter::new(), &aggregate).is_err());
+    }
+
+    #[test]
+    fn constraints_test(){
+        let mut fields = Schema::new();
+        fields.insert("id".to_owned(), FieldType::Str);
+        fields.insert("name".to_owned(), FieldType::Str);
+        fields.insert("age".to_owned(), FieldType::Str);
+        let mut constraints = Constraints::new();
+        constraints.primary_key = Some("id".to_owned());
+        constraints.unique.insert("name".to_owned());
+        assert!(Collection::with_constraints(&fields, &{ let mut c = Constraints::new(); c.unique.insert("gender".to_owned()); c }).is_err());
+
+        let mut clct = Collection::with_constraints(&fields, &constraints).unwrap();
+        assert!(clct.insert(&new_sort_entry(0, "Ada", 24)).is_ok());
+        assert!(clct.insert(&new_sort_entry(1, "Joey", 25)).is_ok());
+        assert_eq!(clct.insert(&new_sort_entry(0, "Ross", 25)), Err(DbError::DuplicateKey("id".to_owned())));
+        assert_eq!(clct.insert(&new_sort_entry(2, "Ada", 25)), Err(DbError::DuplicateKey("name".to_owned())));
+        let mut keyless = TableEntry::new();
+        keyless.insert("name".to_owned(), "Ross".to_owned());
+        assert_eq!(clct.insert(&keyless), Err(DbError::MissingKey("id".to_owned())));
+
+        // the primary key is answered by its hash index
+        assert_eq!(clct.get_indexes().get("id").map(|index| index.kind()), Some(IndexKind::Hash));
+        assert!(clct.drop_index("id").is_err());
+        assert_eq!(clct.find(&Filter::parse("id 1", 1).unwrap()), Ok(vec![new_sort_record(1, 1, "Joey", 25)]));
+
+        let mut rename = TableEntry::new();
+        rename.insert("name".to_owned(), "Ada".to_owned());
+        assert_eq!(clct.update(&Filter::parse("id 1", 1).unwrap(), &rename), Err(DbError::DuplicateKey("name".to_owned())));
+        assert_eq!(clct.update(&Filter::parse("id 0", 1).unwrap(), &rename), Ok(1));
+        assert_eq!(clct.update(&Filter::new(), &rename), Err(DbError::DuplicateKey("name".to_owned())));
+
+        let stored = Json::from_str(&json::encode(&clct).unwrap()).unwrap();
+        let mut loaded = Collection::from_json(&stored).unwrap();
+        assert_eq!(loaded.get_constraints(), clct.get_constraints());
+        assert!(loaded.get_constraints().unique.contains("id"));
+        assert_eq!(loaded.insert(&new_sort_entry(1, "Ross", 25)), Err(DbError::DuplicateKey("id".to_owned())));
+
+        // a snapshot that repeats a unique value or a primary key is not loaded
+        let encoded = json::encode(&clct).unwrap();
+        let repeated = Json::from_str(&encoded.replace("\"Joey\"", "\"Ada\"")).unwrap();
+        assert_eq!(Collection::from_json(&repeated).err(), Some("item 1 repeats a value of unique field name".to_owned()));
+        let repeated = Json::from_str(&encoded.replace("\"id\":\"1\"", "\"id\":\"0\"")).unwrap();
+        assert_eq!(Collection::from_json(&repeated).err(), Some("item 1 repeats a value of unique field id".to_owned()));
+    }
+
+    #[allow(dead_code)]
+    fn new_sort_record(row: i64, id: usize, name: &str, age: usize) -> Record{
+        let mut entry = Record::new();
+        entry.insert("_id".to_owned(), Value::Int(row));
+        entry.insert("_version".to_owned(), Value::Int(1));
+        entry.insert("id".to_owned(), Value::Str(id.to_string()));
+        entry.insert("name".to_owned(), Value::from(name));
+        entry.insert("age".to_owned(), Value::Str(age.to_string()));
+        entry
+    }
+
     #[allow(dead_code)]
     fn new_long_entry(id: usize, name: &str, age: usize, sex: &str) -> TableEntry{
         let mut entry = TableEntry::new();
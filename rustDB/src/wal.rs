@@ -0,0 +1,399 @@
+#[doc="
+  Write-ahead log: every mutating command is appended to the log file
+  instead of rewriting the whole database after each request. Every
+  SNAPSHOT_INTERVAL records the database is dumped into the snapshot file
+  and the log is truncated. On startup the latest snapshot is loaded and
+  the records written after it are replayed; a record that cannot be
+  replayed stops the startup, like a damaged snapshot does.
+  Writers hold the checkpoint lock in shared mode while they apply and log
+  a change, catalog changes and snapshots hold it exclusively.
+"]
+use std::fs::{File, OpenOptions};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use rustc_serialize::json::{self, Json};
+
+use db_module::RustDB;
+use vec_dbcollection::TableEntry;
+use value::{Schema, Constraints};
+use filter::Filter;
+use index::IndexKind;
+use backend::BackendKind;
+use alter::Alteration;
+use lib::{read_snapshot, store_snapshot};
+use transaction::apply_all;
+
+pub const SNAPSHOT_INTERVAL: usize = 1000;
+
+// mutating commands, replayed in order on startup
+#[derive(Debug, Clone, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum Operation {
+    PutList(String, Schema, Constraints),
+    DeleteList(String),
+    Append(String, TableEntry),
+    Update(String, Filter<String>, TableEntry),
+    Delete(String, Filter<String>),
+    CreateIndex(String, String, IndexKind),
+    DropIndex(String, String),
+    Transaction(Vec<Operation>),            // committed together, replayed together
+    UpdateIf(String, Filter<String>, TableEntry, u64),     // with an IF VERSION precondition
+    DeleteIf(String, Filter<String>, u64),
+    PutListOn(String, Schema, Constraints, BackendKind),   // a collection with another backend than the indexed one
+    Alter(String, Vec<Alteration>),
+}
+
+impl Operation {
+    // a PUTLIST, with its backend unless it is the default one
+    pub fn put_list(name: String, fields: Schema, constraints: Constraints, kind: BackendKind) -> Operation {
+        match kind {
+            BackendKind::Indexed => Operation::PutList(name, fields, constraints),
+            _ => Operation::PutListOn(name, fields, constraints, kind),
+        }
+    }
+
+    // an UPDATE, with its precondition when it has one
+    pub fn update(name: String, target: Filter<String>, desired: TableEntry, expected: Option<u64>) -> Operation {
+        match expected {
+            Some(version) => Operation::UpdateIf(name, target, desired, version),
+            None => Operation::Update(name, target, desired),
+        }
+    }
+
+    // a DELETE, with its precondition when it has one
+    pub fn delete(name: String, target: Filter<String>, expected: Option<u64>) -> Operation {
+        match expected {
+            Some(version) => Operation::DeleteIf(name, target, version),
+            None => Operation::Delete(name, target),
+        }
+    }
+
+    // re-execute the command against the database
+    pub fn apply(&self, database: &RustDB) -> bool {
+        match *self {
+            Operation::PutList(ref name, ref fields, ref constraints) => database.create_table(name, fields, constraints, BackendKind::Indexed).is_ok(),
+            Operation::PutListOn(ref name, ref fields, ref constraints, kind) => database.create_table(name, fields, constraints, kind).is_ok(),
+            Operation::DeleteList(ref name) => database.delete_cl(name).is_ok(),
+            Operation::Append(ref name, ref entry) => {
+                match database.find_cl(name) {
+                    Ok(cl) => cl.write().unwrap().insert(entry).is_ok(),
+                    Err(_) => false,
+                }
+            },
+            Operation::Update(ref name, ref target, ref desired) => {
+                match database.find_cl(name) {
+                    Ok(cl) => cl.write().unwrap().update(target, desired).is_ok(),
+                    Err(_) => false,
+                }
+            },
+            Operation::Delete(ref name, ref target) => {
+                match database.find_cl(name) {
+                    Ok(cl) => cl.write().unwrap().delete(target).is_ok(),
+                    Err(_) => false,
+                }
+            },
+            Operation::UpdateIf(ref name, ref target, ref desired, version) => {
+                match database.find_cl(name) {
+                    Ok(cl) => cl.write().unwrap().update_if(target, desired, Some(version)).is_ok(),
+                    Err(_) => false,
+                }
+            },
+            Operation::DeleteIf(ref name, ref target, version) => {
+                match database.find_cl(name) {
+                    Ok(cl) => cl.write().unwrap().delete_if(target, Some(version)).is_ok(),
+                    Err(_) => false,
+                }
+            },
+            Operation::CreateIndex(ref name, ref field, kind) => {
+                match database.find_cl(name) {
+                    Ok(cl) => cl.write().unwrap().create_index(field, kind).is_ok(),
+                    Err(_) => false,
+                }
+            },
+            Operation::DropIndex(ref name, ref field) => {
+                match database.find_cl(name) {
+                    Ok(cl) => cl.write().unwrap().drop_index(field).is_ok(),
+                    Err(_) => false,
+                }
+            },
+            Operation::Alter(ref name, ref alterations) => {
+                match database.find_cl(name) {
+                    Ok(cl) => cl.write().unwrap().alter(alterations).is_ok(),
+                    Err(_) => false,
+                }
+            },
+            Operation::Transaction(ref operations) => {
+                apply_all(database, operations, |_| Ok(())).is_ok()
+            },
+        }
+    }
+}
+
+// one line of the log file, lsn is the log sequence number
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct LogRecord {
+    lsn: u64,
+    operation: Operation,
+}
+
+struct LogFile {
+    file: File,
+    next_lsn: u64,
+    since_snapshot: usize,
+}
+
+pub struct WriteAheadLog {
+    snapshot_path: PathBuf,
+    checkpoint: RwLock<()>,
+    log: Mutex<LogFile>,
+}
+
+impl WriteAheadLog {
+    // load the latest snapshot and replay the log written after it
+    pub fn recover(snapshot_path: &Path, log_path: &Path) -> Result<(RustDB, WriteAheadLog)> {
+        let (snapshot_lsn, database) = try!(load_snapshot(snapshot_path));
+
+        let mut last_lsn = snapshot_lsn;
+        let mut replayed = 0;
+        let log_file = try!(OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(log_path));
+        let mut reader = BufReader::new(try!(log_file.try_clone()));
+        let mut good_end = 0;           // offset after the last complete record
+        loop {
+            let mut line = String::new();
+            let read = match reader.read_line(&mut line) {
+                Ok(read) => read,
+                Err(e) => if e.kind() == ErrorKind::InvalidData { 0 } else { return Err(e) },
+            };
+            if read == 0 {
+                break;
+            }
+            // a line without its newline was never acknowledged, its append was cut short
+            if !line.ends_with('\n') {
+                println!("Stop replay at incomplete log record after {}", last_lsn);
+                break;
+            }
+            if line.trim().is_empty() {
+                good_end += read as u64;
+                continue;
+            }
+            match json::decode::<LogRecord>(&line) {
+                Ok(record) => {
+                    good_end += read as u64;
+                    if record.lsn <= snapshot_lsn {
+                        continue;       // already contained in the snapshot
+                    }
+                    // only changes that were applied are logged, so a record that fails now means the log
+                    // does not belong to the snapshot; going on would load a state no client was answered with
+                    if !record.operation.apply(&database) {
+                        return Err(Error::new(ErrorKind::InvalidData, format!("Replay of log record {} failed", record.lsn)));
+                    }
+                    last_lsn = record.lsn;
+                    replayed += 1;
+                },
+                Err(_) => {
+                    // a crash in the middle of an append leaves a torn last line
+                    println!("Stop replay at unreadable log record after {}", last_lsn);
+                    break;
+                },
+            }
+        }
+        println!("Replayed {} log records", replayed);
+        // the torn rest is cut off, so new records are not appended behind it and lost on the next replay
+        try!(log_file.set_len(good_end));
+
+        let wal = WriteAheadLog {
+            snapshot_path: snapshot_path.to_path_buf(),
+            checkpoint: RwLock::new(()),
+            log: Mutex::new(LogFile {
+                file: log_file,
+                next_lsn: last_lsn + 1,
+                since_snapshot: replayed,
+            }),
+        };
+        Ok((database, wal))
+    }
+
+    // hold while a record is applied and appended, so a snapshot never sees half of it
+    pub fn lock_for_write<'a>(&'a self) -> RwLockReadGuard<'a, ()> {
+        self.checkpoint.read().unwrap()
+    }
+
+    // PUTLIST and DELETELIST run alone, so no write on the collection is logged out of order
+    pub fn lock_for_catalog<'a>(&'a self) -> RwLockWriteGuard<'a, ()> {
+        self.checkpoint.write().unwrap()
+    }
+
+    // append the operation and flush it to disk before the client is answered
+    pub fn append(&self, operation: &Operation) -> Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let record = LogRecord {
+            lsn: log.next_lsn,
+            operation: operation.clone(),
+        };
+        let line = match json::encode(&record) {
+            Ok(line) => line + "\n",
+            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Log record encode error")),
+        };
+        try!(log.file.write_all(line.as_bytes()));
+        try!(log.file.sync_data());
+        log.next_lsn += 1;
+        log.since_snapshot += 1;
+        Ok(())
+    }
+
+    pub fn should_snapshot(&self) -> bool {
+        self.log.lock().unwrap().since_snapshot >= SNAPSHOT_INTERVAL
+    }
+
+    // dump the database with the last logged lsn, then start an empty log
+    // must not be called while holding lock_for_write or lock_for_catalog
+    pub fn snapshot(&self, database: &RustDB) -> Result<()> {
+        let _checkpoint = self.checkpoint.write().unwrap();
+        let content = match database.to_json() {
+            Ok(content) => content,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Database encode error")),
+        };
+        let mut log = self.log.lock().unwrap();
+        try!(store_snapshot(&self.snapshot_path, log.next_lsn - 1, &content));
+        try!(log.file.set_len(0));
+        log.since_snapshot = 0;
+        Ok(())
+    }
+}
+
+// snapshot header holds the lsn of the last record it contains
+fn load_snapshot(path: &Path) -> Result<(u64, RustDB)> {
+    match try!(read_snapshot(path)) {
+        None => Ok((0, RustDB::new())),
+        Some((lsn, content)) => match Json::from_str(&content).map_err(|e| e.to_string()).and_then(|j| RustDB::from_json(&j)) {
+            Ok(database) => Ok((lsn, database)),
+            Err(e) => Err(Error::new(ErrorKind::InvalidData,
+                format!("Corrupt snapshot {}: {}", path.display(), e))),
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod wal_tests {
+    use super::{WriteAheadLog, Operation};
+    use std::fs::{remove_file, OpenOptions};
+    use std::io::prelude::*;
+    use std::path::Path;
+    use vec_dbcollection::TableEntry;
+    use value::{FieldType, Schema, Constraints};
+    use filter::Filter;
+    use backend::BackendKind;
+    use alter::Alteration;
+
+    #[test]
+    fn replay_after_restart_test() {
+        let snapshot_path = Path::new("wal_test_replay_db.txt");
+        let log_path = Path::new("wal_test_replay_log.txt");
+        {
+            let (db, wal) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            for op in vec![Operation::put_list("student".to_owned(), new_student_fields(), Constraints::new(), BackendKind::Parallel),
+                           Operation::Append("student".to_owned(), new_entry(0, "Ada")),
+                           Operation::Append("student".to_owned(), new_entry(1, "Joey")),
+                           Operation::Alter("student".to_owned(), vec![Alteration::RenameField("name".to_owned(), "login".to_owned())])] {
+                assert!(op.apply(&db));
+                wal.append(&op).unwrap();
+            }
+        }
+        {
+            let (db, _) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            let student = db.find_cl("student").unwrap();
+            assert_eq!(student.read().unwrap().get_number_of_data(), 2);
+            assert_eq!(student.read().unwrap().kind(), BackendKind::Parallel);
+            assert_eq!(student.read().unwrap().find(&Filter::parse("login Joey", 1).unwrap()).unwrap().len(), 1);
+        }
+        remove_file(snapshot_path).unwrap();
+        remove_file(log_path).unwrap();
+    }
+
+    #[test]
+    fn torn_record_is_cut_off_test() {
+        let snapshot_path = Path::new("wal_test_torn_db.txt");
+        let log_path = Path::new("wal_test_torn_log.txt");
+        {
+            let (db, wal) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            let create = Operation::PutList("student".to_owned(), new_student_fields(), Constraints::new());
+            assert!(create.apply(&db));
+            wal.append(&create).unwrap();
+        }
+        OpenOptions::new().append(true).open(log_path).unwrap().write_all(b"{\"lsn\":2,\"oper").unwrap();
+        for name in vec!["Ada", "Joey"] {
+            let (db, wal) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            let append = Operation::Append("student".to_owned(), new_entry(0, name));
+            assert!(append.apply(&db));
+            wal.append(&append).unwrap();
+        }
+        {
+            let (db, _) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            assert_eq!(db.find_cl("student").unwrap().read().unwrap().get_number_of_data(), 2);
+        }
+        let _ = remove_file(snapshot_path);
+        remove_file(log_path).unwrap();
+    }
+
+    #[test]
+    fn failed_replay_stops_recovery_test() {
+        let snapshot_path = Path::new("wal_test_failed_db.txt");
+        let log_path = Path::new("wal_test_failed_log.txt");
+        {
+            let (_, wal) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            // logged without its collection, as if the log came from another database
+            wal.append(&Operation::Append("student".to_owned(), new_entry(0, "Ada"))).unwrap();
+        }
+        let err = WriteAheadLog::recover(snapshot_path, log_path).err().unwrap();
+        assert_eq!(err.to_string(), "Replay of log record 1 failed");
+        let _ = remove_file(snapshot_path);
+        remove_file(log_path).unwrap();
+    }
+
+    #[test]
+    fn snapshot_skips_logged_records_test() {
+        let snapshot_path = Path::new("wal_test_snapshot_db.txt");
+        let log_path = Path::new("wal_test_snapshot_log.txt");
+        {
+            let (db, wal) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            let create = Operation::PutList("student".to_owned(), new_student_fields(), Constraints::new());
+            let append = Operation::Append("student".to_owned(), new_entry(0, "Ada"));
+            assert!(create.apply(&db));
+            wal.append(&create).unwrap();
+            assert!(append.apply(&db));
+            wal.append(&append).unwrap();
+            wal.snapshot(&db).unwrap();
+
+            let delete = Operation::Delete("student".to_owned(), Filter::parse("id 0; name prefix A", 1).unwrap());
+            assert!(delete.apply(&db));
+            wal.append(&delete).unwrap();
+        }
+        {
+            let (db, wal) = WriteAheadLog::recover(snapshot_path, log_path).unwrap();
+            assert_eq!(db.find_cl("student").unwrap().read().unwrap().get_number_of_data(), 0);
+            assert_eq!(wal.log.lock().unwrap().next_lsn, 4);
+        }
+        remove_file(snapshot_path).unwrap();
+        remove_file(log_path).unwrap();
+    }
+
+    fn new_student_fields() -> Schema {
+        let mut fields = Schema::new();
+        fields.insert("id".to_owned(), FieldType::Int);
+        fields.insert("name".to_owned(), FieldType::Str);
+        fields
+    }
+
+    fn new_entry(id: usize, name: &str) -> TableEntry {
+        let mut entry = TableEntry::new();
+        entry.insert("id".to_owned(), id.to_string());
+        entry.insert("name".to_owned(), name.to_owned());
+        entry
+    }
+}